@@ -0,0 +1,445 @@
+// the control flow graph of an assembled program and the checks on it: code that nothing reaches,
+// routines that run into the code after them instead of returning, and routines whose pushes, pops and
+// sp adjustments do not balance on every path to their return.
+//
+// `push pc` followed by `jmp` is a call, and the routine it calls is assumed to return with sp where it
+// was. `pop pc` (`ret`) is a return, and any other write to pc (`mov pc, t0`, `lod pc, [t0]`) is an
+// indirect jump, whose target is unknown
+
+use crate::diagnostic::{Code, Diagnostic, Error};
+use crate::instructions::Instruction;
+use crate::source::SourceLine;
+use lunacore_isa::{AluOp, Condition, Instruction as Isa, MemOp, Offset, Register, Src2};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    // falls through to the next instruction
+    Next,
+    // always branches, None for a target that is not an instruction
+    Jump(Option<usize>),
+    // branches or falls through
+    Branch(Option<usize>),
+    // `push pc` and the `jmp` after it, execution goes on after the jmp
+    Call(Option<usize>),
+    Return,
+    Indirect,
+    // a .word in the text section
+    Data,
+}
+
+// what an instruction does to sp and to bp, in bytes pushed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stack {
+    // negative for pops and for `add sp, sp, !n`
+    Adjust(i32),
+    // mov bp, sp
+    SaveFrame,
+    // mov sp, bp
+    RestoreFrame,
+    // bp gets a value that has nothing to do with sp, like the caller's from `pop bp`
+    LoseFrame(i32),
+    // sp gets a value that has nothing to do with the one before, like `mov sp, !0x1000`
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub address: u16,
+    pub flow: Flow,
+    pub stack: Stack,
+}
+
+// one node per instruction of the program, in the same order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub nodes: Vec<Node>,
+}
+
+// where execution can start
+pub struct Roots {
+    // the entry point of the program, its code may end anywhere
+    pub entry: Option<usize>,
+    // .proc and .global labels, the targets of calls are added to them
+    pub routines: Vec<usize>,
+    // code whose address is used as a value, e.g. by a jump table
+    pub addressed: Vec<usize>,
+    // what to call the routines in warnings, by the instruction they start at
+    pub names: HashMap<usize, String>,
+}
+
+impl Cfg {
+    // `externs` are the addresses of branches to an .extern label, which the linker fills in
+    pub fn build(program: &[(u16, Instruction)], externs: &HashSet<u16>) -> Cfg {
+        let index = program.iter().enumerate().map(|(i, (address, _))| (*address, i)).collect::<HashMap<_, _>>();
+        let isa = program.iter().map(|(_, instruction)| instruction.to_isa()).collect::<Vec<_>>();
+
+        // the same pc the hardware reads: after the branch, one more when it or the next instruction is wide
+        let target = |i: usize, offset: Offset| {
+            let (address, instruction) = &program[i];
+            let next_wide = program
+                .get(i + 1)
+                .is_some_and(|(next, following)| *next == address + 1 + instruction.is_wide() as u16 && following.is_wide());
+            let pc = address.wrapping_add(2 + (instruction.is_wide() || next_wide) as u16);
+            index.get(&pc.wrapping_add(offset.value())).copied()
+        };
+
+        let nodes = isa
+            .iter()
+            .enumerate()
+            .map(|(i, instruction)| {
+                let address = program[i].0;
+                let push_pc = matches!(instruction, Some(Isa::Mem { op: MemOp::Push, td: Register::Pc, src2: Src2::Reg(_), .. }));
+                let call = match isa.get(i + 1) {
+                    Some(Some(Isa::Branch { cond: Condition::Mp, offset })) if push_pc => {
+                        Some(target(i + 1, *offset).filter(|_| !externs.contains(&program[i + 1].0)))
+                    }
+                    _ => None,
+                };
+                let flow = match instruction {
+                    _ if call.is_some() => Flow::Call(call.flatten()),
+                    None => Flow::Data,
+                    Some(Isa::Branch { .. }) if externs.contains(&address) => Flow::Indirect,
+                    Some(Isa::Branch { cond: Condition::Mp, offset }) => Flow::Jump(target(i, *offset)),
+                    Some(Isa::Branch { cond: Condition::Nv, .. }) => Flow::Next,
+                    Some(Isa::Branch { offset, .. }) => Flow::Branch(target(i, *offset)),
+                    Some(Isa::Mem { op: MemOp::Pop, td: Register::Pc, .. }) => Flow::Return,
+                    Some(Isa::Mem { op: MemOp::Lod, td: Register::Pc, .. } | Isa::Dp { td: Register::Pc, .. }) => Flow::Indirect,
+                    Some(_) => Flow::Next,
+                };
+                let stack = match instruction {
+                    // the return address is not counted, calls and returns take care of it
+                    _ if call.is_some() || flow == Flow::Return => Stack::Adjust(0),
+                    Some(instruction) => stack_effect(instruction),
+                    None => Stack::Adjust(0),
+                };
+                Node { address, flow, stack }
+            })
+            .collect();
+        Cfg { nodes }
+    }
+
+    // the warnings for every path from the roots, in the order of the instructions they are about
+    pub fn check(&self, sources: &[SourceLine], roots: &Roots) -> Vec<Diagnostic> {
+        let calls = self.nodes.iter().filter_map(|node| match node.flow {
+            Flow::Call(target) => target,
+            _ => None,
+        });
+        let routines = roots
+            .routines
+            .iter()
+            .copied()
+            .chain(calls)
+            .map(|i| {
+                let name = roots.names.get(&i).cloned();
+                (i, name.unwrap_or_else(|| format!("the routine at 0x{:04x}", self.nodes[i].address)))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut walk = Walk {
+            cfg: self,
+            routines: &routines,
+            reached: vec![false; self.nodes.len()],
+            warnings: Vec::new(),
+        };
+        let mut starts = routines.iter().map(|(i, name)| (*i, Some(name.as_str()))).collect::<Vec<_>>();
+        starts.sort();
+        // an object file may start with one of its routines
+        if let Some(entry) = roots.entry.filter(|entry| !routines.contains_key(entry)) {
+            starts.insert(0, (entry, None));
+        }
+        for (start, routine) in starts {
+            walk.walk(start, routine, true);
+        }
+        for &start in &roots.addressed {
+            if !walk.reached[start] {
+                walk.walk(start, None, false);
+            }
+        }
+
+        // one warning for every run of instructions nothing reaches
+        for i in 0..self.nodes.len() {
+            let code = |i: usize| self.nodes[i].flow != Flow::Data && !walk.reached[i];
+            if code(i) && (i == 0 || !code(i - 1)) {
+                walk.warnings.push((i, Code::ControlFlow, "Unreachable code, nothing jumps or falls through to it".to_string()));
+            }
+        }
+
+        walk.warnings.sort_by_key(|(i, _, _)| *i);
+        let mut reported = HashSet::new();
+        walk.warnings
+            .into_iter()
+            .filter(|(i, _, message)| reported.insert((*i, message.clone())))
+            .map(|(i, code, message)| Diagnostic::warning(Error::new(code, message), &sources[i]))
+            .collect()
+    }
+}
+
+fn stack_effect(instruction: &Isa) -> Stack {
+    let size = |byte: bool| if byte { 1 } else { 2 };
+    let imm = |src2: &Src2, sign: i32| src2.imm().map_or(Stack::Unknown, |imm| Stack::Adjust(sign * imm as i16 as i32));
+    match *instruction {
+        Isa::Mem { op: MemOp::Push, byte, .. } => Stack::Adjust(size(byte)),
+        Isa::Mem { op: MemOp::Pop | MemOp::Lod, td: Register::Sp, .. } => Stack::Unknown,
+        Isa::Mem { op: MemOp::Pop, byte, td: Register::Bp, .. } => Stack::LoseFrame(-size(byte)),
+        Isa::Mem { op: MemOp::Pop, byte, .. } => Stack::Adjust(-size(byte)),
+        Isa::Mem { op: MemOp::Lod, td: Register::Bp, .. } => Stack::LoseFrame(0),
+        Isa::Dp { op: AluOp::Mov, td: Register::Bp, src2: Src2::Reg(Register::Sp), .. } => Stack::SaveFrame,
+        Isa::Dp { op: AluOp::Mov, td: Register::Sp, src2: Src2::Reg(Register::Bp), .. } => Stack::RestoreFrame,
+        Isa::Dp { op: AluOp::Add, td: Register::Sp, tn: Register::Sp, ref src2 } => imm(src2, -1),
+        Isa::Dp { op: AluOp::Sub, td: Register::Sp, tn: Register::Sp, ref src2 } => imm(src2, 1),
+        Isa::Dp { td: Register::Sp, .. } => Stack::Unknown,
+        Isa::Dp { td: Register::Bp, .. } => Stack::LoseFrame(0),
+        _ => Stack::Adjust(0),
+    }
+}
+
+// bytes pushed since the start of the routine, and when bp was set from sp. None when they are not known
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct State {
+    depth: Option<i32>,
+    frame: Option<i32>,
+}
+
+impl State {
+    fn after(self, stack: Stack) -> State {
+        match stack {
+            Stack::Adjust(bytes) => State { depth: self.depth.map(|depth| depth + bytes), ..self },
+            Stack::SaveFrame => State { frame: self.depth, ..self },
+            Stack::RestoreFrame => State { depth: self.frame, ..self },
+            Stack::LoseFrame(bytes) => State {
+                depth: self.depth.map(|depth| depth + bytes),
+                frame: None,
+            },
+            Stack::Unknown => State { depth: None, ..self },
+        }
+    }
+}
+
+struct Walk<'a> {
+    cfg: &'a Cfg,
+    routines: &'a HashMap<usize, String>,
+    reached: Vec<bool>,
+    warnings: Vec<(usize, Code, String)>,
+}
+
+impl Walk<'_> {
+    // follows every path from `start`, the code of `routine` or of the entry point when it is None.
+    // every instruction is followed once, with the state of the first path that gets to it
+    fn walk(&mut self, start: usize, routine: Option<&str>, checked: bool) {
+        let nodes = &self.cfg.nodes;
+        let mut depths = HashMap::new();
+        let mut pending = vec![(start, State { depth: Some(0), frame: None })];
+        while let Some((i, state)) = pending.pop() {
+            // every path to a return is checked, the stack has to be right there
+            if let (Flow::Return, Some(routine), Some(depth), true) = (nodes[i].flow, routine, state.depth, checked) {
+                if depth != 0 {
+                    let message = match depth > 0 {
+                        true => format!("{} returns with {} still pushed", routine, pushed(depth)),
+                        false => format!("{} returns after popping {} more than it pushed", routine, pushed(-depth)),
+                    };
+                    let message = format!("{}, so ret does not pop the return address", message);
+                    self.warnings.push((i, Code::Stack, message));
+                }
+            }
+            if let Some(&depth) = depths.get(&i) {
+                if let (Some(first), Some(other), true) = (depth, state.depth, checked && nodes[i].flow != Flow::Return) {
+                    if first != other {
+                        let (first, other) = (pushed(first), pushed(other));
+                        let message = format!("The paths that get here disagree on the stack: one has pushed {}, another {}", first, other);
+                        self.warnings.push((i, Code::Stack, message));
+                    }
+                }
+                continue;
+            }
+            depths.insert(i, state.depth);
+            self.reached[i] = true;
+
+            let after = state.after(nodes[i].stack);
+            let mut next = Vec::new();
+            match nodes[i].flow {
+                Flow::Next => next.push(Some(i + 1)),
+                Flow::Jump(target) => next.push(target),
+                Flow::Branch(target) => next.extend([target, Some(i + 1)]),
+                Flow::Call(_) => {
+                    self.reached[i + 1] = true;
+                    next.push(Some(i + 2));
+                }
+                Flow::Return | Flow::Indirect | Flow::Data => {}
+            }
+
+            for target in next {
+                // the end of the program, or a jump to where there is no code
+                let Some(target) = target.filter(|target| *target < nodes.len()) else {
+                    if let (Some(routine), true) = (routine, checked) {
+                        let message = format!("{} runs past the end of the program without returning", routine);
+                        self.warnings.push((i, Code::ControlFlow, message));
+                    }
+                    continue;
+                };
+                match self.routines.get(&target) {
+                    Some(other) if target != start && target == i + 1 + matches!(nodes[i].flow, Flow::Call(_)) as usize && checked => {
+                        let message = match routine {
+                            Some(routine) => format!("{} falls through into {} without returning", routine, other),
+                            None => format!("The code of the entry point falls through into {}", other),
+                        };
+                        self.warnings.push((i, Code::ControlFlow, message));
+                    }
+                    // a jump to another routine is a tail call, which returns for this one
+                    Some(_) if target != start => {}
+                    _ => pending.push((target, after)),
+                }
+            }
+        }
+    }
+}
+
+fn pushed(bytes: i32) -> String {
+    match bytes {
+        1 => "1 byte".to_string(),
+        bytes => format!("{} bytes", bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    // the warnings of a program, as `line: message`
+    fn warnings(program: &str) -> Vec<String> {
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse_program(program, "test"), Ok(()));
+        parser.diagnostics.iter().map(|diagnostic| format!("{}: {}", diagnostic.location.line, diagnostic.message)).collect()
+    }
+
+    #[test]
+    fn test_build() {
+        let mut parser = Parser::new();
+        let program = "call f\njmp end\nf: push bp\nmov bp, sp\nsub sp, !4\njz.w f\nmov sp, bp\npop bp\nmov pc, t0\nret\nend:";
+        assert_eq!(parser.parse_program(program, "test"), Ok(()));
+        let cfg = Cfg::build(&parser.program, &HashSet::new());
+        let nodes = cfg.nodes.iter().map(|node| (node.address, node.flow, node.stack)).collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            vec![
+                (0, Flow::Call(Some(3)), Stack::Adjust(0)),
+                (1, Flow::Jump(Some(3)), Stack::Adjust(0)),
+                (2, Flow::Jump(None), Stack::Adjust(0)),
+                (3, Flow::Next, Stack::Adjust(2)),
+                (4, Flow::Next, Stack::SaveFrame),
+                (5, Flow::Next, Stack::Adjust(4)),
+                (6, Flow::Branch(Some(3)), Stack::Adjust(0)),
+                (8, Flow::Next, Stack::RestoreFrame),
+                (9, Flow::Next, Stack::LoseFrame(-2)),
+                (10, Flow::Indirect, Stack::Adjust(0)),
+                (11, Flow::Return, Stack::Adjust(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let program = "
+            _start: call f
+                    mov t1, !handler
+                    jmp end
+                    nop
+                    nop
+            f:      jmp .done
+                    mov t0, !1
+            .done:  ret
+            handler:
+                    ret
+            end:
+            ";
+        assert_eq!(
+            warnings(program),
+            vec!["5: Unreachable code, nothing jumps or falls through to it", "8: Unreachable code, nothing jumps or falls through to it"]
+        );
+
+        // the handlers of a jump table in data memory are reached through it
+        let table = "
+            _start: lod t0, [t1 + !table]
+                    mov pc, t0
+            zero:   jmp end
+            one:    jmp end
+            two:    jmp end
+            end:
+                    .data
+            table:  .word zero, one
+            ";
+        assert_eq!(warnings(table), vec!["6: Unreachable code, nothing jumps or falls through to it"]);
+    }
+
+    #[test]
+    fn test_fall_through() {
+        let program = "_start: call f\ncall g\nf: nop\ng: push t0\npop t0";
+        assert_eq!(
+            warnings(program),
+            vec![
+                "2: The code of the entry point falls through into f",
+                "3: f falls through into g without returning",
+                "5: g runs past the end of the program without returning",
+            ]
+        );
+
+        // the entry point may run off the end, which halts the emulator, and routines may jump to each other
+        assert_eq!(warnings("jmp main\nf: jmp g\ng: ret\nmain: call f\nmov t3, t0"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_stack_balance() {
+        let program = "
+            _start: call f
+                    call g
+                    call h
+                    jmp end
+            f:      push t2
+                    jz .skip
+                    pop t2
+            .skip:  ret
+            g:      pushb t0
+                    add sp, sp, !3
+                    ret
+            h:      push t0
+                    jnz h
+                    pop t0
+                    ret
+            end:
+            ";
+        assert_eq!(
+            warnings(program),
+            vec![
+                "9: f returns with 2 bytes still pushed, so ret does not pop the return address",
+                "12: g returns after popping 2 bytes more than it pushed, so ret does not pop the return address",
+                "13: The paths that get here disagree on the stack: one has pushed 0 bytes, another 2 bytes",
+            ]
+        );
+
+        // frames, .proc and unknown values of sp
+        let program = "
+            _start: mov sp, !0x1000
+                    push !1
+                    call f
+                    call g
+                    add sp, sp, !2
+                    jmp end
+            f:      push bp
+                    mov bp, sp
+                    push t0
+                    push t1
+                    mov sp, bp
+                    pop bp
+                    ret
+            g:      mov sp, t0
+                    ret
+            .proc k
+                    push t2
+                    return
+            .endproc
+            end:
+            ";
+        assert_eq!(warnings(program), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,214 @@
+// the assembler as a library, for tests and tools that assemble source without going through files:
+//
+//   let program = assemble("mov t0, !1", &Options::default())?;
+//   assert_eq!(program.words, [0x1a01]);
+
+use crate::debuginfo::debug_info;
+use crate::diagnostic::Diagnostic;
+use crate::expr::Expr;
+use crate::layout::Layout;
+use crate::listing::listing;
+use crate::object::Object;
+use crate::parser::{Parser, Section};
+use crate::source::SourceLine;
+use lunacore_isa::container::{Container, ContainerSection, SectionKind};
+use std::fmt::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // the name diagnostics show, relative .include paths start next to it
+    pub filename: String,
+    // searched for .include and .incbin files not found next to the including file
+    pub include_paths: Vec<PathBuf>,
+    // keep addresses relative and allow .extern, for Program::object
+    pub relocatable: bool,
+    // constants defined before the first line, see parser::parse_define
+    pub defines: Vec<(String, Expr)>,
+    // where the sections go, ignored for relocatable objects
+    pub layout: Layout,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            filename: "<input>".to_string(),
+            include_paths: Vec::new(),
+            relocatable: false,
+            defines: Vec::new(),
+            layout: Layout::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: SectionKind,
+    pub address: u16,
+    // the words of a .proc, 0 for other symbols
+    pub size: u16,
+}
+
+// where an instruction came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub address: u16,
+    // 2 for a wide instruction
+    pub words: u16,
+    pub source: SourceLine,
+}
+
+pub struct Program {
+    // program and data memory from address 0, as far as the program fills them
+    pub words: Vec<u16>,
+    pub data: Vec<u8>,
+    // _start when the program defines it, otherwise the start of .text
+    pub entry: u16,
+    // the initial sp, from the `stack` line of the layout
+    pub sp: u16,
+    // sorted by section, then address
+    pub symbols: Vec<Symbol>,
+    // how words and data are loaded, as written to the executable
+    pub sections: Vec<ContainerSection>,
+    // one per instruction, in address order
+    pub lines: Vec<Line>,
+    pub warnings: Vec<Diagnostic>,
+    parser: Parser,
+}
+
+// on failure returns every diagnostic, warnings included, in the order they were found
+pub fn assemble(source: &str, options: &Options) -> Result<Program, Vec<Diagnostic>> {
+    let mut parser = Parser::new();
+    parser.include_paths = options.include_paths.clone();
+    parser.relocatable = options.relocatable;
+    parser.defines = options.defines.clone();
+    parser.layout = options.layout.clone();
+    if parser.parse_program(source, &options.filename).is_err() {
+        return Err(parser.diagnostics);
+    }
+    Ok(Program::from_parser(parser))
+}
+
+impl Program {
+    fn from_parser(parser: Parser) -> Program {
+        let words = parser.words();
+        let entry = parser.label_map.get("_start").copied().unwrap_or(parser.placements[0].address);
+
+        let mut symbols = parser
+            .text_symbols()
+            .map(|(name, address)| (SectionKind::Text, name, *address))
+            .chain(parser.data_label_map.iter().map(|(name, address)| (SectionKind::Data, name, *address)))
+            .map(|(section, name, address)| {
+                let procedure = parser.procedures.iter().find(|procedure| procedure.name == *name && section == SectionKind::Text);
+                let size = procedure.map_or(0, |procedure| procedure.end - procedure.start);
+                Symbol { name: name.clone(), section, address, size }
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by(|a, b| (a.section as u16, a.address, &a.name).cmp(&(b.section as u16, b.address, &b.name)));
+
+        let lines = parser
+            .program
+            .iter()
+            .zip(&parser.sources)
+            .map(|((address, instruction), source)| Line {
+                address: *address,
+                words: 1 + instruction.is_wide() as u16,
+                source: source.clone(),
+            })
+            .collect();
+
+        // .text is always there, .rodata goes to data memory like .data and .bss is only zeroed memory
+        let sections = parser
+            .placements
+            .iter()
+            .filter(|placement| placement.section == Section::Text || (placement.section != Section::Bss && placement.size > 0))
+            .map(|placement| {
+                let (start, end) = (placement.address as usize, placement.address as usize + placement.size);
+                match placement.section {
+                    Section::Text => ContainerSection {
+                        kind: SectionKind::Text,
+                        address: placement.address,
+                        bytes: words[start..end].iter().flat_map(|word| word.to_le_bytes()).collect(),
+                    },
+                    _ => ContainerSection {
+                        kind: SectionKind::Data,
+                        address: placement.address,
+                        bytes: parser.data[start..end].to_vec(),
+                    },
+                }
+            })
+            .collect();
+
+        Program {
+            sections,
+            data: parser.data.clone(),
+            warnings: parser.diagnostics.clone(),
+            words,
+            entry,
+            sp: parser.layout.stack(),
+            symbols,
+            lines,
+            parser,
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // the line an instruction word belongs to
+    pub fn line_at(&self, address: u16) -> Option<&Line> {
+        self.lines.iter().find(|line| (line.address..line.address + line.words).contains(&address))
+    }
+
+    pub fn container(&self) -> Container {
+        Container {
+            entry: self.entry,
+            sp: self.sp,
+            sections: self.sections.clone(),
+        }
+    }
+
+    // only meaningful when assembled with Options::relocatable
+    pub fn object(&self) -> Object {
+        Object::from_parser(&self.parser)
+    }
+
+    pub fn listing(&self) -> String {
+        listing(&self.parser)
+    }
+
+    pub fn debug_info(&self) -> String {
+        debug_info(&self.parser)
+    }
+
+    // one `<address> <text|data> <name>` line per symbol, addresses in hex;
+    // procedures end with their size in words
+    pub fn symbol_table(&self) -> String {
+        let mut out = String::new();
+        for symbol in &self.symbols {
+            let section = match symbol.section {
+                SectionKind::Text => "text",
+                SectionKind::Data => "data",
+            };
+            let _ = match symbol.size {
+                0 => writeln!(out, "{:04x} {} {}", symbol.address, section, symbol.name),
+                size => writeln!(out, "{:04x} {} {} {:04x}", symbol.address, section, symbol.name, size),
+            };
+        }
+        out
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Program")
+            .field("words", &self.words)
+            .field("data", &self.data)
+            .field("entry", &self.entry)
+            .field("sp", &self.sp)
+            .field("symbols", &self.symbols)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,73 @@
+use compiler::disassembler::disassemble;
+use lunacore_isa::container::{Container, MAGIC};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let usage = format!("Usage: {} [-o <output_filename>] [--raw] <executable> [data_file]", args[0]);
+
+    let mut output_filename = None;
+    let mut filenames = Vec::new();
+    let mut raw = false;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if arg == "-o" {
+            match rest.next() {
+                Some(filename) => output_filename = Some(filename.clone()),
+                None => {
+                    eprintln!("Missing filename after -o\n{}", usage);
+                    std::process::exit(1);
+                }
+            }
+        } else if arg == "--raw" {
+            raw = true;
+        } else {
+            filenames.push(arg.clone());
+        }
+    }
+
+    let Some(input_filename) = filenames.first() else {
+        eprintln!("Missing Filename\n{}", usage);
+        std::process::exit(1);
+    };
+    let bytes = fs::read(input_filename)?;
+
+    let container = if raw {
+        if bytes.len() % 2 != 0 {
+            eprintln!("{}: File size is not a multiple of 2 bytes", input_filename);
+            std::process::exit(1);
+        }
+        let text = bytes.chunks(2).map(|word| u16::from_le_bytes([word[0], word[1]])).collect::<Vec<_>>();
+        // the data image is either given explicitly or sits next to the binary (prog.lunaexe -> prog.lunadata)
+        let data_filename = filenames.get(1).cloned().or_else(|| {
+            input_filename
+                .strip_suffix("exe")
+                .map(|stem| stem.to_owned() + "data")
+                .filter(|data_filename| Path::new(data_filename).exists())
+        });
+        let data = match data_filename {
+            Some(data_filename) => fs::read(data_filename)?,
+            None => Vec::new(),
+        };
+        Container::new(0, &text, &data)
+    } else {
+        Container::parse(&bytes).unwrap_or_else(|err| {
+            let hint = if bytes.starts_with(MAGIC) { "" } else { " (use --raw for raw images)" };
+            eprintln!("{}: {}{}", input_filename, err, hint);
+            std::process::exit(1);
+        })
+    };
+
+    let source = disassemble(&container);
+    match output_filename {
+        Some(output_filename) => {
+            fs::write(Path::new(&output_filename), source)?;
+            println!("Disassembly generated succesfully at: {}", output_filename);
+        }
+        None => print!("{}", source),
+    }
+    Ok(())
+}
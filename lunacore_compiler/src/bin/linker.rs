@@ -0,0 +1,99 @@
+use compiler::linker::{link, Module};
+use compiler::object::Object;
+use lunacore_isa::container::Container;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let usage = format!("Usage: {} [-o <output_filename>] [--raw] <object_filename>...", args[0]);
+
+    let mut output_filename = None;
+    let mut object_filenames = Vec::new();
+    let mut raw = false;
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        if arg == "-o" {
+            match rest.next() {
+                Some(filename) => output_filename = Some(filename.clone()),
+                None => {
+                    eprintln!("Missing filename after -o\n{}", usage);
+                    std::process::exit(1);
+                }
+            }
+        } else if arg == "--raw" {
+            raw = true;
+        } else {
+            object_filenames.push(arg.clone());
+        }
+    }
+
+    let Some(first) = object_filenames.first() else {
+        eprintln!("Missing Filename\n{}", usage);
+        std::process::exit(1);
+    };
+    // prog.lunaobj -> prog.lunaexe, next to it the data image prog.lunadata
+    let output_filename = output_filename.unwrap_or_else(|| match first.strip_suffix("obj") {
+        Some(stem) => stem.to_owned() + "exe",
+        None => first.to_owned() + ".lunaexe",
+    });
+    let data_filename = match output_filename.strip_suffix("exe") {
+        Some(stem) => stem.to_owned() + "data",
+        None => output_filename.clone() + ".lunadata",
+    };
+
+    let mut modules = Vec::new();
+    for filename in &object_filenames {
+        let input = fs::read_to_string(filename)?;
+        match Object::parse(&input) {
+            Ok(object) => modules.push(Module {
+                name: filename.clone(),
+                object,
+            }),
+            Err(err) => {
+                eprintln!("Error in {}: {}\nNo file was generated", filename, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let executable = match link(&modules) {
+        Ok(executable) => executable,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("error: {}", error);
+            }
+            eprintln!("No file was generated");
+            std::process::exit(1);
+        }
+    };
+
+    if !raw {
+        let container = Container::new(executable.entry, &executable.text, &executable.data);
+        fs::write(Path::new(&output_filename), container.to_bytes())?;
+        println!("Executable generated succesfully at: {}", output_filename);
+        return Ok(());
+    }
+
+    // bare words and a separate data image, the program has to start at address 0
+    if executable.entry != 0 {
+        eprintln!(
+            "error: --raw executables start at address 0, but _start is at {:#06x}; leave out --raw to keep it in the executable\nNo file was generated",
+            executable.entry
+        );
+        std::process::exit(1);
+    }
+    let mut file = File::create(Path::new(&output_filename))?;
+    for word in &executable.text {
+        file.write_all(&word.to_le_bytes())?;
+    }
+    println!("Executable generated succesfully at: {}", output_filename);
+
+    if !executable.data.is_empty() {
+        fs::write(Path::new(&data_filename), &executable.data)?;
+        println!("Data image generated succesfully at: {}", data_filename);
+    }
+    Ok(())
+}
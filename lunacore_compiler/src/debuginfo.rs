@@ -0,0 +1,90 @@
+// debug information (.lunadbg), written next to the executable with `-g` and read by the emulator
+//
+// the format is line based text:
+//   lunadbg 1
+//   file <index> <name>
+//   symbol <text|data> <address> <name>
+//   proc <start> <end> <name>                        the words of a .proc, the end is exclusive
+//   line <pc> <words> <file index> <line> <column>     every instruction, 2 words when wide
+//   pseudo <pc> <source>                             instructions a pseudo-instruction expanded to
+
+use crate::lexer::{source_text, tokenize, TokenKind};
+use crate::parser::{split_label, Parser};
+use std::fmt::Write;
+
+pub fn debug_info(parser: &Parser) -> String {
+    let mut out = "lunadbg 1\n".to_string();
+
+    let mut files = Vec::new();
+    for source in &parser.sources {
+        if !files.contains(&source.location.file) {
+            files.push(source.location.file.clone());
+        }
+    }
+    for (i, file) in files.iter().enumerate() {
+        let _ = writeln!(out, "file {} {}", i, file);
+    }
+
+    let mut symbols = parser
+        .text_symbols()
+        .map(|(name, pc)| ("text", *pc, name))
+        .chain(parser.data_label_map.iter().map(|(name, addr)| ("data", *addr, name)))
+        .collect::<Vec<_>>();
+    symbols.sort();
+    for (section, address, name) in symbols {
+        let _ = writeln!(out, "symbol {} {:04x} {}", section, address, name);
+    }
+    for procedure in &parser.procedures {
+        let _ = writeln!(out, "proc {:04x} {:04x} {}", procedure.start, procedure.end, procedure.name);
+    }
+
+    for (i, line) in parser.listing.iter().enumerate() {
+        let end = parser.listing.get(i + 1).map_or(parser.program.len(), |next| next.instruction);
+        let (_, rest) = split_label(&line.source.text);
+        let tokens = tokenize(rest)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|token| !matches!(token.kind, TokenKind::Comment(_)))
+            .collect::<Vec<_>>();
+        let offset = line.source.text.len() - rest.len();
+        let column = offset + tokens.first().map_or(0, |token| token.span.start) + 1;
+
+        for (pc, instruction) in &parser.program[line.instruction..end] {
+            let location = &line.source.location;
+            let file = files.iter().position(|file| *file == location.file).unwrap_or(0);
+            let words = 1 + instruction.is_wide() as u8;
+            let _ = writeln!(out, "line {:04x} {} {} {} {}", pc, words, file, location.line, column);
+            if line.pseudo {
+                let _ = writeln!(out, "pseudo {:04x} {}", pc, source_text(rest, &tokens));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_info() {
+        let program = ".data\ncount: .byte 1\n.text\nmain:   mov t0, !100\n        call f ; comment\nf:      ret\n";
+        let mut parser = Parser::new();
+        parser.parse_program(program, "test.luna").unwrap();
+
+        assert_eq!(
+            debug_info(&parser),
+            "lunadbg 1\n\
+             file 0 test.luna\n\
+             symbol data 0000 count\n\
+             symbol text 0000 main\n\
+             symbol text 0004 f\n\
+             line 0000 2 0 4 9\n\
+             line 0002 1 0 5 9\n\
+             pseudo 0002 call f\n\
+             line 0003 1 0 5 9\n\
+             pseudo 0003 call f\n\
+             line 0004 1 0 6 9\n"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::source::{Location, SourceLine};
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    Syntax = 1,
+    UnknownInstruction,
+    UnknownDirective,
+    InvalidOperands,
+    InvalidRegister,
+    OutOfRange,
+    UndefinedSymbol,
+    DuplicateSymbol,
+    InvalidExpression,
+    Section,
+    Macro,
+    File,
+    Conditional,
+    Frame,
+    Procedure,
+    ControlFlow,
+    Stack,
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "E{:04}", *self as u8)
+    }
+}
+
+// an error found in a single line, it becomes a Diagnostic once its location is known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub code: Code,
+    pub message: String,
+    // byte range in the line
+    pub span: Option<Range<usize>>,
+}
+
+impl Error {
+    pub fn new(code: Code, message: impl Into<String>) -> Error {
+        Error {
+            code,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    // points the error at the tokens, unless a more precise span was already set
+    pub fn at(mut self, tokens: &[Token]) -> Error {
+        let real = tokens.iter().filter(|token| !token.span.is_empty()).collect::<Vec<_>>();
+        if let (None, Some(first), Some(last)) = (&self.span, real.first(), real.last()) {
+            self.span = Some(first.span.start..last.span.end);
+        }
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Code,
+    pub message: String,
+    pub location: Location,
+    pub text: String,
+    pub span: Option<Range<usize>>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(error: Error, source: &SourceLine) -> Diagnostic {
+        Diagnostic::new(Severity::Error, error, source)
+    }
+
+    pub fn warning(error: Error, source: &SourceLine) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, error, source)
+    }
+
+    fn new(severity: Severity, error: Error, source: &SourceLine) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code: error.code,
+            message: error.message,
+            location: source.location.clone(),
+            text: source.text.clone(),
+            span: error.span,
+            notes: source.location.notes(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+// error[E0005]: Invalid register t9
+//  --> prog.luna:3:9
+//   |
+// 3 |     mov t9, !1
+//   |         ^^
+//   = note: in expansion of macro load (defined at prog.luna:2), called at prog.luna:5
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let line = self.location.line.to_string();
+        let gutter = " ".repeat(line.len());
+        let text = self.text.trim_end();
+
+        // the whole line, without its indentation and trailing comment, when the error has no span
+        let span = match &self.span {
+            Some(span) => span.start.min(text.len())..span.end.min(text.len()),
+            None => {
+                let start = text.len() - text.trim_start().len();
+                let end = match tokenize(text).as_deref() {
+                    Ok([.., Token { kind: TokenKind::Comment(_), span, .. }]) => text[..span.start].trim_end().len(),
+                    _ => text.len(),
+                };
+                start..end.max(start)
+            }
+        };
+        let column = text[..span.start].chars().count() + 1;
+        let carets = text[span.clone()].chars().count().max(1);
+
+        writeln!(f, "{}[{}]: {}", severity, self.code, self.message)?;
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.location.file, line, column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line, text)?;
+        write!(f, "{} | {}{}", gutter, " ".repeat(column - 1), "^".repeat(carets))?;
+        for note in &self.notes {
+            write!(f, "\n{} = note: {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::source_lines;
+
+    #[test]
+    fn test_render() {
+        let source = source_lines("\n\n    mov t9, !1", "prog.luna", None).pop_back().unwrap();
+        let error = Error {
+            code: Code::InvalidRegister,
+            message: "Invalid register t9".into(),
+            span: Some(8..10),
+        };
+        assert_eq!(
+            Diagnostic::error(error, &source).to_string(),
+            "error[E0005]: Invalid register t9\n --> prog.luna:3:9\n  |\n3 |     mov t9, !1\n  |         ^^"
+        );
+
+        let error = Error::new(Code::OutOfRange, "Shift by 16");
+        assert_eq!(
+            Diagnostic::warning(error, &source).to_string(),
+            "warning[E0006]: Shift by 16\n --> prog.luna:3:5\n  |\n3 |     mov t9, !1\n  |     ^^^^^^^^^^"
+        );
+
+        // a trailing comment is not part of the line the warning is about
+        let source = source_lines("    mov t9, !1 ; load", "prog.luna", None).pop_back().unwrap();
+        assert_eq!(
+            Diagnostic::warning(Error::new(Code::OutOfRange, "Shift by 16"), &source).to_string(),
+            "warning[E0006]: Shift by 16\n --> prog.luna:1:5\n  |\n1 |     mov t9, !1 ; load\n  |     ^^^^^^^^^^"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+// turns a program back into assembly that the compiler encodes to the very same words:
+// the words are decoded one after the other, branch targets get a label (L<address>), and wide
+// encodings that the compiler would otherwise shorten keep a `.w` suffix. every line is assembled
+// again to check it, words that would not come out the same are written as `.word`
+
+use crate::assembler::{assemble, Options};
+use crate::instructions::Instruction;
+use lunacore_isa::container::{Container, SectionKind};
+use lunacore_isa::{Condition, Instruction as Isa, Offset, Src2};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+const DATA_BYTES_PER_ROW: usize = 16;
+
+// sections that do not start where the one before them ends get an `.org`, so the memory images
+// come out the same even when the sections were placed by a layout
+pub fn disassemble(container: &Container) -> String {
+    let entry = container.entry;
+    let sections = |kind| {
+        let mut sections = container.sections.iter().filter(|section| section.kind == kind).collect::<Vec<_>>();
+        sections.sort_by_key(|section| section.address);
+        sections
+    };
+
+    // the decoded instruction at every pc with its words, None for words that are kept raw
+    let mut instructions = Vec::new();
+    let mut ends = Vec::new();
+    for section in sections(SectionKind::Text) {
+        let text = section.words();
+        let mut pc = 0;
+        while pc < text.len() {
+            let instruction = Instruction::decode(&text[pc..]).filter(|instruction| text[pc..].starts_with(&instruction.to_binary()));
+            let len = instruction.as_ref().map_or(1, |instruction| 1 + instruction.is_wide() as usize);
+            let words = text[pc..pc + len].to_vec();
+            instructions.push((section.address.wrapping_add(pc as u16), instruction, words));
+            pc += len;
+        }
+        ends.push(section.address.wrapping_add(text.len() as u16));
+    }
+    // the pc register skips the second word of the instruction right after a branch when it is wide
+    let next_wide = |i: usize| {
+        let (pc, _, words) = &instructions[i];
+        instructions.get(i + 1).is_some_and(|(next, _, next_words)| *next == pc.wrapping_add(words.len() as u16) && next_words.len() == 2)
+    };
+
+    // only addresses where an instruction starts, or the end of a section, can hold a label
+    let starts = instructions.iter().map(|(pc, _, _)| *pc).chain(ends.iter().copied()).collect::<BTreeSet<_>>();
+    let mut labels = BTreeSet::new();
+    for (i, (pc, instruction, _)) in instructions.iter().enumerate() {
+        if let Some(Instruction::Isa(Isa::Branch { offset, .. })) = instruction {
+            let target = offset.target(*pc, next_wide(i));
+            if starts.contains(&target) {
+                labels.insert(target);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    if entry != 0 && !starts.contains(&entry) {
+        let _ = writeln!(out, "; the entry point 0x{:04x} is not the start of an instruction", entry);
+    }
+    // the source cannot hold the initial sp, the layout gives it
+    if container.sp != 0 {
+        let _ = writeln!(out, "; the initial sp is 0x{:04x}, set it with a `stack 0x{:04x}` layout line", container.sp, container.sp);
+    }
+    let _ = writeln!(out, ".text");
+    let mut end = 0;
+    for (i, (pc, instruction, words)) in instructions.iter().enumerate() {
+        if *pc != end {
+            write_labels(&mut out, end, entry, &labels);
+            let _ = writeln!(out, ".org 0x{:04x}", pc);
+        }
+        write_labels(&mut out, *pc, entry, &labels);
+        let line = instruction
+            .as_ref()
+            .and_then(|instruction| instruction_line(instruction, *pc, next_wide(i), words, &labels))
+            .unwrap_or_else(|| format!(".word {}", words.iter().map(|w| format!("0x{:04x}", w)).collect::<Vec<_>>().join(", ")));
+        let hex = words.iter().map(|w| format!("{:04x}", w)).collect::<Vec<_>>().join(" ");
+        let _ = writeln!(out, "    {:<28}; {:04x}  {}", line, pc, hex);
+        end = pc.wrapping_add(words.len() as u16);
+    }
+    write_labels(&mut out, end, entry, &labels);
+
+    let data = sections(SectionKind::Data);
+    if !data.is_empty() {
+        let _ = writeln!(out, "\n.data");
+    }
+    let mut end = 0;
+    for section in data {
+        if section.address as usize != end {
+            let _ = writeln!(out, ".org 0x{:04x}", section.address);
+        }
+        for (i, bytes) in section.bytes.chunks(DATA_BYTES_PER_ROW).enumerate() {
+            let address = section.address as usize + i * DATA_BYTES_PER_ROW;
+            let bytes = bytes.iter().map(|b| format!("0x{:02x}", b)).collect::<Vec<_>>().join(", ");
+            let _ = writeln!(out, "    .byte {}  ; {:04x}", bytes, address);
+        }
+        end = section.address as usize + section.bytes.len();
+    }
+    out
+}
+
+fn write_labels(out: &mut String, pc: u16, entry: u16, labels: &BTreeSet<u16>) {
+    if pc == entry && pc != 0 {
+        let _ = writeln!(out, "_start:");
+    }
+    if labels.contains(&pc) {
+        let _ = writeln!(out, "{}:", label(pc));
+    }
+}
+
+fn label(pc: u16) -> String {
+    format!("L{:04x}", pc)
+}
+
+// None when the instruction has no assembly that encodes to the same words
+fn instruction_line(instruction: &Instruction, pc: u16, next_wide: bool, words: &[u16], labels: &BTreeSet<u16>) -> Option<String> {
+    let line = match instruction {
+        Instruction::Isa(Isa::Branch { cond: Condition::Nv, offset: Offset::SignImm9(0) }) => "nop".to_string(),
+        // relaxation keeps every branch that was short short, as long as the wide ones stay wide
+        Instruction::Isa(Isa::Branch { offset, .. }) => {
+            let target = offset.target(pc, next_wide);
+            if !labels.contains(&target) {
+                return None;
+            }
+            let line = instruction.text(&|_| label(target));
+            return Some(match offset {
+                Offset::WideImm16(_) => line.replacen(' ', ".w ", 1),
+                Offset::SignImm9(_) => line,
+            });
+        }
+        Instruction::Isa(Isa::Dp { src2: Src2::WideImm16(imm), .. } | Isa::Mem { src2: Src2::WideImm16(imm), .. })
+            if (-8..=7).contains(&(*imm as i16)) =>
+        {
+            instruction.text(&|_| unreachable!()).replacen(' ', ".w ", 1)
+        }
+        instruction => instruction.text(&|_| unreachable!()),
+    };
+
+    let assembled = assemble(&line, &Options::default()).is_ok_and(|program| program.words == words);
+    assembled.then_some(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layout;
+
+    fn words_and_data(program: &str) -> (Vec<u16>, Vec<u8>) {
+        let program = assemble(program, &Options::default()).unwrap();
+        (program.words, program.data)
+    }
+
+    // disassembles the program and checks that the source assembles to the same memory images
+    fn round_trip(program: &str, options: &Options) -> String {
+        let program = assemble(program, options).unwrap();
+        let source = disassemble(&program.container());
+        let assembled = assemble(&source, &Options::default()).unwrap();
+        assert_eq!((&assembled.words, &assembled.data, assembled.entry), (&program.words, &program.data, program.entry), "{}", source);
+        source
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let program = "
+            .data
+            value: .word 0x1234
+            .text
+            start:  la t0, start
+                    lod t1, [t0 + !value]
+                    lodb t2, [bp - !3]
+                    sav t1, [sp + !300]
+            loop:   sub t1, t1, !1
+                    jnz loop
+                    push !-2
+                    pop t3
+                    push pc
+                    jmp.w later
+                    nop
+                    ret
+            later:  shl t0, t0, !4
+                    jmp end
+            end:
+            ";
+        let source = round_trip(program, &Options::default());
+        assert!(source.contains("mov.w t0, !"));
+        assert!(source.contains("jnz L0006"));
+    }
+
+    #[test]
+    fn test_raw_words() {
+        // op 11, mov with a first register, a wide immediate without its second word
+        let text = [0xc000, 0x1a4b, 0x3a00];
+        let source = disassemble(&Container::new(0, &text, &[]));
+        assert_eq!(words_and_data(&source).0, text, "{}", source);
+        assert_eq!(source.matches(".word").count(), 3);
+
+        let entry = disassemble(&Container::new(1, &[0x1a01, 0x1a02], &[]));
+        assert!(entry.contains("_start:\n    mov t0, !2"));
+    }
+
+    #[test]
+    fn test_sections() {
+        let options = Options {
+            layout: Layout::parse("text 0x40\ndata 0x100\nrodata 0x800").unwrap(),
+            ..Options::default()
+        };
+        let program = "
+            .data
+            count:  .word 3
+            .org 0x110
+            flag:   .byte 1
+            .rodata
+            table:  .byte 1, 2
+            .text
+            start:  la t0, table
+                    lod t1, [t0]
+                    jmp start
+            ";
+        let source = round_trip(program, &options);
+        assert!(source.contains(".text\n.org 0x0040\n_start:\nL0040:\n"));
+        assert!(source.contains(".data\n.org 0x0100\n"));
+        assert!(source.contains("  ; 0110\n.org 0x0800\n    .byte 0x01, 0x02  ; 0800\n"));
+    }
+}
@@ -0,0 +1,179 @@
+// memory images for loading programs into hardware models (FPGA block RAMs, Logisim ROMs)
+//
+// an image is a list of memory entries starting at address 0: 16-bit words for the
+// instruction ROM and bytes for the data RAM
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // Intel HEX, addressed by memory entry with every entry stored high byte first
+    IntelHex,
+    // one hexadecimal entry per line, for Verilog's $readmemh
+    ReadMemH,
+    // one binary entry per line, for Verilog's $readmemb
+    ReadMemB,
+    // Logisim-evolution "v2.0 raw" memory image
+    Logisim,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "ihex" => Some(Format::IntelHex),
+            "readmemh" => Some(Format::ReadMemH),
+            "readmemb" => Some(Format::ReadMemB),
+            "logisim" => Some(Format::Logisim),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::IntelHex => "hex",
+            Format::ReadMemH => "memh",
+            Format::ReadMemB => "memb",
+            Format::Logisim => "img",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    // bits per entry, 16 or 8
+    pub width: usize,
+    pub entries: Vec<u16>,
+}
+
+impl Image {
+    pub fn words(words: &[u16]) -> Image {
+        Image {
+            width: 16,
+            entries: words.to_vec(),
+        }
+    }
+
+    pub fn bytes(bytes: &[u8]) -> Image {
+        Image {
+            width: 8,
+            entries: bytes.iter().map(|&byte| byte as u16).collect(),
+        }
+    }
+
+    // fills the image up to `depth` entries, which must hold the whole program
+    pub fn pad(&mut self, depth: usize, fill: u16) -> Result<(), String> {
+        if depth > 1 << 16 {
+            return Err(format!("Memory depth {} is larger than the address space", depth));
+        }
+        if self.entries.len() > depth {
+            return Err(format!("Image has {} entries, more than the memory depth of {}", self.entries.len(), depth));
+        }
+        if self.width == 8 && fill > 0xff {
+            return Err(format!("Fill value 0x{:x} does not fit in a byte", fill));
+        }
+        self.entries.resize(depth, fill);
+        Ok(())
+    }
+
+    pub fn export(&self, format: Format) -> String {
+        match format {
+            Format::IntelHex => self.intel_hex(),
+            Format::ReadMemH => self.lines(|entry, width| format!("{:01$x}", entry, width / 4)),
+            Format::ReadMemB => self.lines(|entry, width| format!("{:01$b}", entry, width)),
+            Format::Logisim => self.logisim(),
+        }
+    }
+
+    fn lines(&self, text: impl Fn(u16, usize) -> String) -> String {
+        self.entries.iter().map(|&entry| text(entry, self.width) + "\n").collect()
+    }
+
+    fn intel_hex(&self) -> String {
+        let mut output = String::new();
+        // 16 bytes per record
+        let per_record = 128 / self.width;
+        for (i, chunk) in self.entries.chunks(per_record).enumerate() {
+            let bytes = chunk
+                .iter()
+                .flat_map(|&entry| match self.width {
+                    16 => entry.to_be_bytes().to_vec(),
+                    _ => vec![entry as u8],
+                })
+                .collect::<Vec<_>>();
+            let address = (i * per_record) as u16;
+            output += &record(0x00, address, &bytes);
+        }
+        output + &record(0x01, 0, &[])
+    }
+
+    fn logisim(&self) -> String {
+        // runs of the same value are written as count*value, 8 items per line
+        let mut items = Vec::new();
+        let mut rest = &self.entries[..];
+        while let Some(&value) = rest.first() {
+            let count = rest.iter().take_while(|&&entry| entry == value).count();
+            match count {
+                1..=3 => items.extend(std::iter::repeat_n(format!("{:x}", value), count)),
+                _ => items.push(format!("{}*{:x}", count, value)),
+            }
+            rest = &rest[count..];
+        }
+
+        let mut output = "v2.0 raw\n".to_string();
+        for line in items.chunks(8) {
+            output += &line.join(" ");
+            output.push('\n');
+        }
+        output
+    }
+}
+
+// one Intel HEX record, the checksum makes all of its bytes add up to 0
+fn record(kind: u8, address: u16, bytes: &[u8]) -> String {
+    let mut fields = vec![bytes.len() as u8];
+    fields.extend_from_slice(&address.to_be_bytes());
+    fields.push(kind);
+    fields.extend_from_slice(bytes);
+    let checksum = fields.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)).wrapping_neg();
+    fields.push(checksum);
+
+    let mut line = ":".to_string();
+    for byte in fields {
+        write!(line, "{:02X}", byte).unwrap();
+    }
+    line + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let image = Image::words(&[0x1a03, 0x83fc]);
+        assert_eq!(image.export(Format::IntelHex), ":040000001A0383FC60\n:00000001FF\n");
+        assert_eq!(image.export(Format::ReadMemH), "1a03\n83fc\n");
+        assert_eq!(image.export(Format::ReadMemB), "0001101000000011\n1000001111111100\n");
+        assert_eq!(image.export(Format::Logisim), "v2.0 raw\n1a03 83fc\n");
+
+        let data = Image::bytes(&[1, 0xff]);
+        assert_eq!(data.export(Format::IntelHex), ":0200000001FFFE\n:00000001FF\n");
+        assert_eq!(data.export(Format::ReadMemB), "00000001\n11111111\n");
+    }
+
+    #[test]
+    fn test_padding() {
+        let mut image = Image::words(&[7, 0, 0]);
+        image.pad(20, 0).unwrap();
+        assert_eq!(image.export(Format::Logisim), "v2.0 raw\n7 19*0\n");
+        let records = image.export(Format::IntelHex);
+        assert_eq!(records.lines().nth(2), Some(":080010000000000000000000E8"));
+
+        let mut data = Image::bytes(&[1, 2, 3]);
+        assert!(data.pad(2, 0).is_err());
+        assert!(data.pad(4, 0x100).is_err());
+        assert!(data.pad(1 << 17, 0).is_err());
+        data.pad(4, 0xff).unwrap();
+        assert_eq!(data.export(Format::ReadMemH), "01\n02\n03\nff\n");
+    }
+}
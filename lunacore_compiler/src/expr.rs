@@ -0,0 +1,284 @@
+// constant expressions used by immediates, memory offsets and directives
+//
+// precedence, lowest to highest:
+//   ||   &&   |   ^   &   == !=   < <= > >=   << >>   + -   * / %   unary - ~ +
+// comparisons and the logical operators give 1 or 0, like in C
+// plus the byte selectors lo(x) and hi(x)
+
+use crate::diagnostic::{Code, Error};
+use crate::lexer::{Token, TokenKind};
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(i32),
+    Symbol(String, Range<usize>), // the span is where the symbol appears in its line
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Lo,
+    Hi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    LogicalOr,
+    LogicalAnd,
+    Or,
+    Xor,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl Expr {
+    pub fn parse_tokens(tokens: &[Token]) -> Result<Expr, Error> {
+        if tokens.is_empty() {
+            return Err(expr_error("Empty expression"));
+        }
+        let mut parser = ExprParser { tokens, pos: 0 };
+        let expr = parser.parse_binary(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(expr_error(format!("Unexpected token '{}' in expression", token.text)).at(&tokens[parser.pos..])),
+        }
+    }
+
+    // errors from `lookup` point at the symbol that was looked up
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Result<i32, Error>) -> Result<i32, Error> {
+        match self {
+            Expr::Num(n) => Ok(*n),
+            Expr::Symbol(name, span) => lookup(name).map_err(|err| match span.is_empty() {
+                true => err,
+                false => Error { span: Some(span.clone()), ..err },
+            }),
+            Expr::Unary(op, e) => {
+                let v = e.eval(lookup)?;
+                Ok(match op {
+                    UnaryOp::Neg => v.checked_neg().ok_or_else(overflow)?,
+                    UnaryOp::Not => !v,
+                    UnaryOp::Lo => v & 0xff,
+                    UnaryOp::Hi => (v >> 8) & 0xff,
+                })
+            }
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(lookup)?, b.eval(lookup)?);
+                let result = match op {
+                    BinaryOp::LogicalOr => Some((a != 0 || b != 0) as i32),
+                    BinaryOp::LogicalAnd => Some((a != 0 && b != 0) as i32),
+                    BinaryOp::Or => Some(a | b),
+                    BinaryOp::Xor => Some(a ^ b),
+                    BinaryOp::And => Some(a & b),
+                    BinaryOp::Eq => Some((a == b) as i32),
+                    BinaryOp::Ne => Some((a != b) as i32),
+                    BinaryOp::Lt => Some((a < b) as i32),
+                    BinaryOp::Le => Some((a <= b) as i32),
+                    BinaryOp::Gt => Some((a > b) as i32),
+                    BinaryOp::Ge => Some((a >= b) as i32),
+                    BinaryOp::Shl | BinaryOp::Shr if !(0..32).contains(&b) => {
+                        return Err(Error::new(Code::OutOfRange, format!("Shift amount {} out of range [0, 31]", b)));
+                    }
+                    BinaryOp::Shl => a.checked_mul(1 << b),
+                    BinaryOp::Shr => Some(a >> b),
+                    BinaryOp::Add => a.checked_add(b),
+                    BinaryOp::Sub => a.checked_sub(b),
+                    BinaryOp::Mul => a.checked_mul(b),
+                    BinaryOp::Div | BinaryOp::Rem if b == 0 => return Err(expr_error("Division by zero in expression")),
+                    BinaryOp::Div => a.checked_div(b),
+                    BinaryOp::Rem => a.checked_rem(b),
+                };
+                result.ok_or_else(overflow)
+            }
+        }
+    }
+}
+
+// values are computed in 32 bits and range checked when they are used, so wrapping around would go unnoticed
+fn overflow() -> Error {
+    Error::new(Code::OutOfRange, "Overflow in constant expression")
+}
+
+fn expr_error(message: impl Into<String>) -> Error {
+    Error::new(Code::InvalidExpression, message)
+}
+
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+// binary operators grouped by precedence level, lowest first
+const BINARY_LEVELS: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::LogicalOr)],
+    &[("&&", BinaryOp::LogicalAnd)],
+    &[("|", BinaryOp::Or)],
+    &[("^", BinaryOp::Xor)],
+    &[("&", BinaryOp::And)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[("<", BinaryOp::Lt), ("<=", BinaryOp::Le), (">", BinaryOp::Gt), (">=", BinaryOp::Ge)],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[("*", BinaryOp::Mul), ("/", BinaryOp::Div), ("%", BinaryOp::Rem)],
+];
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), Error> {
+        match self.next() {
+            Some(token) if token.is_punct(op) => Ok(()),
+            Some(token) => Err(expr_error(format!("Expected '{}', found '{}'", op, token.text)).at(std::slice::from_ref(token))),
+            None => Err(expr_error(format!("Expected '{}' at the end of the expression", op))),
+        }
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, Error> {
+        if level == BINARY_LEVELS.len() {
+            return self.parse_unary();
+        }
+
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Some(TokenKind::Punct(found)) = self.peek().map(|token| &token.kind) {
+            let Some(&(_, op)) = BINARY_LEVELS[level].iter().find(|(symbol, _)| symbol == found) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, Error> {
+        match self.peek().map(|token| &token.kind) {
+            Some(TokenKind::Punct("-")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.parse_unary()?)))
+            }
+            Some(TokenKind::Punct("~")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(UnaryOp::Not, Box::new(self.parse_unary()?)))
+            }
+            Some(TokenKind::Punct("+")) => {
+                self.pos += 1;
+                self.parse_unary()
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, Error> {
+        let Some(token) = self.next().cloned() else {
+            return Err(expr_error("Unexpected end of expression"));
+        };
+        match token.kind {
+            TokenKind::Number(n) => Ok(Expr::Num(n)),
+            TokenKind::Char(c) => Ok(Expr::Num(c as i32)),
+            TokenKind::Ident(name) => {
+                let selector = match name.to_lowercase().as_str() {
+                    "lo" => Some(UnaryOp::Lo),
+                    "hi" => Some(UnaryOp::Hi),
+                    _ => None,
+                };
+                match selector {
+                    Some(op) if self.peek().is_some_and(|token| token.is_punct("(")) => {
+                        self.pos += 1;
+                        let arg = self.parse_binary(0)?;
+                        self.expect(")")?;
+                        Ok(Expr::Unary(op, Box::new(arg)))
+                    }
+                    _ => Ok(Expr::Symbol(name, token.span)),
+                }
+            }
+            TokenKind::Punct("(") => {
+                let expr = self.parse_binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            _ => Err(expr_error(format!("Unexpected token '{}' in expression", token.text)).at(&[token])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    fn eval(input: &str) -> Result<i32, Error> {
+        Expr::parse_tokens(&tokenize(input)?)?.eval(&|name| match name {
+            "buf_size" => Ok(10),
+            "start" => Ok(0x20),
+            "end" => Ok(0x2a),
+            _ => Err(Error::new(Code::UndefinedSymbol, format!("Symbol {} not found", name))),
+        })
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("1 << 4 | 1"), Ok(17));
+        assert_eq!(eval("-2 - -3"), Ok(1));
+        assert_eq!(eval("~0 & 0xff"), Ok(255));
+        assert_eq!(eval("17 % 5 / 2"), Ok(1));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(eval("buf_size == 10"), Ok(1));
+        assert_eq!(eval("buf_size != 10"), Ok(0));
+        assert_eq!(eval("end - start >= 10 && start < 0x20"), Ok(0));
+        assert_eq!(eval("1 < 2 || 0"), Ok(1));
+        assert_eq!(eval("2 > 1 == 1"), Ok(1));
+        assert_eq!(eval("1 | 2 == 2"), Ok(1));
+        assert_eq!(eval("-1 <= -1 << 1"), Ok(0));
+    }
+
+    #[test]
+    fn test_symbols_and_selectors() {
+        assert_eq!(eval("(buf_size*2 - 1)"), Ok(19));
+        assert_eq!(eval("end - start"), Ok(10));
+        assert_eq!(eval("hi(0x1234) + lo(0x1234)"), Ok(0x12 + 0x34));
+        assert_eq!(eval("'a' - 'A'"), Ok(32));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("missing").is_err());
+        assert!(eval("1 $ 2").is_err());
+        assert!(eval("0xzz").is_err());
+        assert!(eval("t0 + 1").is_err());
+        assert_eq!(eval("1 << 40").unwrap_err().code, Code::OutOfRange);
+        assert_eq!(eval("0x10000 * 0x10000").unwrap_err().code, Code::OutOfRange);
+        assert_eq!(eval("-1 << 4"), Ok(-16));
+        assert_eq!(eval("start + missing").unwrap_err().span, Some(8..15));
+        assert_eq!(eval("(1 + 2]").unwrap_err().span, Some(6..7));
+    }
+}
@@ -1,100 +1,119 @@
+use crate::expr::Expr;
+use lunacore_isa::{self as isa, AluOp, Condition, MemOp, Offset, Src2};
+
+// an instruction of the program, the instruction set's own once its operands are known. the other
+// variants hold what has to wait for the addresses of labels
 #[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
-    Dp { cmd: u8, td: u8, tn: u8, src2: Src2 },
+    Isa(isa::Instruction),
 
-    Mem { bsl: u8, td: u8, tn: u8, src2: Src2 },
+    // a dp or mem instruction whose immediate depends on labels. it is always wide, the second pass
+    // puts the value of the expression into its wide immediate
+    Expr(isa::Instruction, Expr),
 
-    BranchLabel { cond: u8, label: String },
+    // wide when written with a `.w` suffix, otherwise relaxation picks the shortest offset
+    BranchLabel { cond: Condition, label: String, wide: bool },
 
-    BranchOffset { cond: u8, offset: Offset },
+    // a raw word from `.word` in the text section
+    Word(u16),
 }
 
 impl Instruction {
     pub fn to_binary(&self) -> Vec<u16> {
         match self {
-            Self::Dp { cmd, td, tn, src2 } => {
-                let (imm, src2, wide) = match src2 {
-                    Src2::Reg(r) => (0b00, *r as u16, false),
-                    Src2::ZeroImm3(i) => (0b01, *i as u16, false),
-                    Src2::OneImm3(i) => (0b10, *i as u16, false),
-                    Src2::WideImm16(i) => (0b11, *i as u16, true),
-                };
-
-                let instr: u16 = (00 << 14) | (imm << 12) | ((*cmd as u16) << 9) | ((*td as u16) << 6) | ((*tn as u16) << 3);
-
-                if !wide {
-                    vec![(instr | (src2 & 0b111))]
-                } else {
-                    vec![instr, src2]
-                }
+            Self::Isa(instruction) => instruction.encode(),
+            Self::Expr(..) => panic!("Trying to convert an unresolved immediate to binary"),
+            Self::BranchLabel { .. } => {
+                panic!("Trying to convert BranchLabel type to binary, when it has to be converted to a branch offset beforehand");
             }
-            Self::Mem { bsl, td, tn, src2 } => {
-                let (imm, src2, wide) = match src2 {
-                    Src2::Reg(r) => (0b00, *r as u16, false),
-                    Src2::ZeroImm3(i) => (0b01, *i as u16, false),
-                    Src2::OneImm3(i) => (0b10, *i as u16, false),
-                    Src2::WideImm16(i) => (0b11, *i as u16, true),
-                };
-
-                let instr: u16 = (01 << 14) | (imm << 12) | ((*bsl as u16) << 9) | ((*td as u16) << 6) | ((*tn as u16) << 3);
+            Self::Word(word) => vec![*word],
+        }
+    }
 
-                if !wide {
-                    vec![(instr | (src2 & 0b111))]
-                } else {
-                    vec![instr, src2]
-                }
-            }
-            Self::BranchOffset { cond, offset } => {
-                let (w, offset, wide) = match offset {
-                    Offset::SignImm9(i) => (0, *i as u16, false),
-                    Offset::WideImm16(i) => (1, *i as u16, true),
-                };
+    // the instruction at the start of `words`, None for the unused op 11 and for a wide instruction
+    // whose immediate is missing. fields the encoding ignores are dropped, so to_binary may differ
+    pub fn decode(words: &[u16]) -> Option<Instruction> {
+        isa::Instruction::decode(words).map(Instruction::Isa)
+    }
 
-                let instr = (10 << 14) | (w << 13) | ((*cond as u16) << 9);
+    // None for what the encoder cannot take yet: unresolved immediates and branch labels, raw words
+    pub(crate) fn to_isa(&self) -> Option<isa::Instruction> {
+        match self {
+            Self::Isa(instruction) => Some(*instruction),
+            _ => None,
+        }
+    }
 
-                if !wide {
-                    vec![(instr | offset & 0x1f)]
-                } else {
-                    vec![instr, offset]
-                }
+    // the instruction as assembly, `branch` names the target of a resolved branch
+    pub fn text(&self, branch: &dyn Fn(Offset) -> String) -> String {
+        let instruction = match self {
+            Self::Isa(instruction) | Self::Expr(instruction, _) => instruction,
+            Self::BranchLabel { cond, label, .. } => return format!("{} {}", cond.mnemonic(), label),
+            Self::Word(word) => return format!(".word 0x{:04x}", word),
+        };
+        // the value of an expression is not known yet
+        let src2_text = |src2: &Src2| match self {
+            Self::Expr(..) => "!?".to_string(),
+            _ => src2_text(src2),
+        };
+        match instruction {
+            isa::Instruction::Dp { op: AluOp::Mov, td, src2, .. } => format!("mov {}, {}", td.name(), src2_text(src2)),
+            isa::Instruction::Dp { op, td, tn, src2 } => {
+                format!("{} {}, {}, {}", op.mnemonic(), td.name(), tn.name(), src2_text(src2))
             }
-            Self::BranchLabel { .. } => {
-                panic!("Trying to convert BranchLabel type to binary, when it has to be converted to BranchOffset beforehand");
+            isa::Instruction::Mem { op, byte, td, tn, src2 } => {
+                let byte = if *byte { "b" } else { "" };
+                match (op, src2) {
+                    (MemOp::Push, Src2::Reg(_)) => format!("push{} {}", byte, td.name()),
+                    (MemOp::Push, imm) => format!("push{} {}", byte, src2_text(imm)),
+                    (MemOp::Pop, _) => format!("pop{} {}", byte, td.name()),
+                    (op, src2) => {
+                        let address = match src2 {
+                            Src2::ZeroImm3(0) => String::new(),
+                            Src2::OneImm3(i) => format!(" - !{}", -i),
+                            src2 => format!(" + {}", src2_text(src2)),
+                        };
+                        format!("{}{} {}, [{}{}]", op.mnemonic(), byte, td.name(), tn.name(), address)
+                    }
+                }
             }
+            isa::Instruction::Branch { cond, offset } => format!("{} {}", cond.mnemonic(), branch(*offset)),
         }
     }
 
     pub fn is_wide(&self) -> bool {
-        if matches!(
-            self,
-            Instruction::Dp {
-                src2: Src2::WideImm16(_),
-                ..
-            } | Instruction::Mem {
-                src2: Src2::WideImm16(_),
-                ..
-            } | Instruction::BranchOffset {
-                offset: Offset::WideImm16(_),
-                ..
-            } | Instruction::BranchLabel { .. } // Assumming every branch label is wide
-        ) {
-            true
-        } else {
-            false
+        match self {
+            Self::Isa(instruction) | Self::Expr(instruction, _) => instruction.is_wide(),
+            // until relaxed, a branch label is assumed to be wide
+            Self::BranchLabel { .. } => true,
+            Self::Word(_) => false,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Src2 {
-    Reg(u8),
-    ZeroImm3(u8),
-    OneImm3(i8),
-    WideImm16(i16),
+// a 16-bit field holds both signed and unsigned values, 0x8000..=0xffff are stored as negative numbers
+const IMM16_MIN: i32 = i16::MIN as i32;
+const IMM16_MAX: i32 = u16::MAX as i32;
+
+// the shortest encoding of an immediate
+pub fn imm_src2(value: i32) -> Result<Src2, String> {
+    Src2::shortest(value).ok_or_else(|| out_of_range(value))
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Offset {
-    SignImm9(i16),
-    WideImm16(i16),
+pub fn wide_src2(value: i32) -> Result<Src2, String> {
+    Src2::wide(value).ok_or_else(|| out_of_range(value))
+}
+
+fn out_of_range(value: i32) -> String {
+    format!("Immediate {} out of range [{}, {}]", value, IMM16_MIN, IMM16_MAX)
+}
+
+// wide immediates are written signed, as they read in the source most of the time
+fn src2_text(src2: &Src2) -> String {
+    match src2 {
+        Src2::Reg(r) => r.name().to_string(),
+        Src2::ZeroImm3(i) => format!("!{}", i),
+        Src2::OneImm3(i) => format!("!{}", i),
+        Src2::WideImm16(i) => format!("!{}", *i as i16),
+    }
 }
@@ -0,0 +1,120 @@
+// where the sections of an executable are placed, read from a small layout file:
+//
+//   ; section  base    size
+//   text       0x0000  0x4000
+//   data       0x0000  0x1000
+//   rodata     0x1000
+//   bss        0x2000  0x2000
+//   stack      0xff00
+//
+// text addresses and sizes count words of program memory, the others bytes of data memory.
+// a section that is not listed starts at 0 (text and data) or right after the one before it
+// (rodata after data, bss after rodata), and a region without a size runs until the end of memory.
+// `stack` is the initial sp, 0 when it is not listed so the first push goes to the top of memory
+
+use crate::parser::Section;
+
+pub const MEMORY_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub base: u16,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Layout {
+    regions: Vec<(Section, Region)>,
+    stack: Option<u16>,
+}
+
+impl Layout {
+    pub fn parse(input: &str) -> Result<Layout, String> {
+        let mut layout = Layout::default();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.split([';', '#']).next().unwrap_or_default();
+            let error = |message: String| format!("line {}: {}", i + 1, message);
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (name, base, size) = match fields[..] {
+                [] => continue,
+                [name, base] => (name, base, None),
+                [name, base, size] => (name, base, Some(size)),
+                _ => return Err(error(format!("Expected a section, its base and optionally its size, found '{}'", line.trim()))),
+            };
+
+            if name == "stack" {
+                if layout.stack.is_some() {
+                    return Err(error("The stack is placed twice".to_string()));
+                }
+                if size.is_some() {
+                    return Err(error("The stack takes only its initial sp, not a size".to_string()));
+                }
+                let sp = parse_number(base)
+                    .filter(|sp| *sp < MEMORY_SIZE)
+                    .ok_or_else(|| error(format!("Invalid stack address {}", base)))?;
+                layout.stack = Some(sp as u16);
+                continue;
+            }
+            let section = Section::from_name(&format!(".{}", name.trim_start_matches('.')))
+                .ok_or_else(|| error(format!("Unknown section {}", name)))?;
+            if layout.region(section).is_some() {
+                return Err(error(format!("Section {} is placed twice", name)));
+            }
+            let base = parse_number(base)
+                .filter(|base| *base < MEMORY_SIZE)
+                .ok_or_else(|| error(format!("Invalid base address {}", base)))?;
+            let size = match size {
+                Some(size) => parse_number(size)
+                    .filter(|size| base + size <= MEMORY_SIZE)
+                    .ok_or_else(|| error(format!("Invalid size {}, the region has to end within memory", size)))?,
+                None => MEMORY_SIZE - base,
+            };
+            layout.regions.push((section, Region { base: base as u16, size }));
+        }
+        Ok(layout)
+    }
+
+    // None when the section is placed by default
+    pub fn region(&self, section: Section) -> Option<Region> {
+        self.regions.iter().find(|(placed, _)| *placed == section).map(|(_, region)| *region)
+    }
+
+    // the initial sp of the program
+    pub fn stack(&self) -> u16 {
+        self.stack.unwrap_or(0)
+    }
+}
+
+// a decimal or 0x-prefixed hexadecimal number
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let layout = Layout::parse("; program ROM\ntext 0x100 0x700\n\n.data 0x0000 4096  # RAM\nrodata 0x1000\n").unwrap();
+        assert_eq!(layout.region(Section::Text), Some(Region { base: 0x100, size: 0x700 }));
+        assert_eq!(layout.region(Section::Data), Some(Region { base: 0, size: 0x1000 }));
+        assert_eq!(layout.region(Section::Rodata), Some(Region { base: 0x1000, size: 0xf000 }));
+        assert_eq!(layout.region(Section::Bss), None);
+        assert_eq!(layout.stack(), 0);
+        assert_eq!(Layout::parse("stack 0xff00").unwrap().stack(), 0xff00);
+
+        assert_eq!(Layout::parse("heap 0x1000").unwrap_err(), "line 1: Unknown section heap");
+        assert!(Layout::parse("text 0x10000").is_err());
+        assert!(Layout::parse("data 0xff00 0x200").is_err());
+        assert!(Layout::parse("data 0\ndata 0x100").is_err());
+        assert!(Layout::parse("bss").is_err());
+        assert!(Layout::parse("bss 1 2 3").is_err());
+        assert!(Layout::parse("stack 0x10000").is_err());
+        assert!(Layout::parse("stack 0xff00 0x100").is_err());
+        assert!(Layout::parse("stack 0xff00\nstack 0xfe00").is_err());
+    }
+}
@@ -0,0 +1,294 @@
+// splits a line of assembly into typed tokens
+//
+// registers are matched case-insensitively, every other identifier keeps its case;
+// `;` and `//` start a comment that runs until the end of the line
+
+use crate::diagnostic::{Code, Error};
+use lunacore_isa::Register;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Ident(String), // mnemonics, directives (with their leading '.'), labels and symbols
+    Register(Register),
+    Number(i32),
+    Char(u8),
+    Str(Vec<u8>),
+    Punct(&'static str),
+    Comment(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    // byte range in the line
+    pub span: Range<usize>,
+}
+
+impl Token {
+    // tokens made up by the parser, e.g. the implicit `!1` of `inc`
+    pub fn synthetic(kind: TokenKind) -> Token {
+        let text = match &kind {
+            TokenKind::Register(r) => r.name().to_string(),
+            TokenKind::Number(n) => n.to_string(),
+            TokenKind::Punct(p) => p.to_string(),
+            _ => String::new(),
+        };
+        Token { kind, text, span: 0..0 }
+    }
+
+    pub fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.kind, TokenKind::Punct(p) if p == punct)
+    }
+
+    pub fn ident(&self) -> Option<&str> {
+        match &self.kind {
+            TokenKind::Ident(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+// longest first, so that `<<` is not read as two `<`
+const PUNCTUATION: [&str; 27] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "<", ">", ",", ":", "[", "]", "(", ")", "!", "=", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+];
+
+pub fn tokenize(line: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while let Some(c) = line[pos..].chars().next() {
+        let rest = &line[pos..];
+        let start = pos;
+
+        let kind = if c.is_whitespace() {
+            pos += c.len_utf8();
+            continue;
+        } else if c == ';' || rest.starts_with("//") {
+            pos = line.len();
+            TokenKind::Comment(rest.to_string())
+        } else if c.is_ascii_digit() {
+            pos += rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            let word = &line[start..pos];
+            // `1b` and `1f` refer to the numeric label `1:` before or after the line
+            match numeric_reference(word) {
+                Some(_) => TokenKind::Ident(word.to_string()),
+                None => TokenKind::Number(parse_number(word).map_err(|err| syntax_error(err, start..pos))?),
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '.' {
+            pos += rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            let word = &line[start..pos];
+            match Register::from_name(word) {
+                Some(r) => TokenKind::Register(r),
+                None => TokenKind::Ident(word.to_string()),
+            }
+        } else if c == '"' {
+            let (bytes, len) = parse_quoted(rest, '"').map_err(|err| syntax_error(err, start..line.len()))?;
+            pos += len;
+            TokenKind::Str(bytes)
+        } else if c == '\'' {
+            let (bytes, len) = parse_quoted(rest, '\'').map_err(|err| syntax_error(err, start..line.len()))?;
+            pos += len;
+            match bytes[..] {
+                [byte] => TokenKind::Char(byte),
+                _ => {
+                    let err = format!("Invalid character literal {}", &line[start..pos]);
+                    return Err(syntax_error(err, start..pos));
+                }
+            }
+        } else if let Some(punct) = PUNCTUATION.iter().find(|p| rest.starts_with(*p)) {
+            pos += punct.len();
+            TokenKind::Punct(punct)
+        } else {
+            return Err(syntax_error(format!("Unexpected character '{}'", c), start..start + c.len_utf8()));
+        };
+
+        tokens.push(Token {
+            kind,
+            text: line[start..pos].to_string(),
+            span: start..pos,
+        });
+    }
+    Ok(tokens)
+}
+
+fn syntax_error(message: String, span: Range<usize>) -> Error {
+    Error {
+        code: Code::Syntax,
+        message,
+        span: Some(span),
+    }
+}
+
+// the part of the line covered by the tokens
+pub fn source_text<'a>(line: &'a str, tokens: &[Token]) -> &'a str {
+    match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => &line[first.span.start..last.span.end],
+        _ => "",
+    }
+}
+
+// the tokens as they were written, for error messages
+pub fn token_text(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && tokens[i - 1].span.end < token.span.start {
+            text.push(' ');
+        }
+        text.push_str(&token.text);
+    }
+    text
+}
+
+// the label number and direction of `<digits>b` or `<digits>f`
+pub fn numeric_reference(word: &str) -> Option<(&str, char)> {
+    let direction = word.chars().last().filter(|c| *c == 'b' || *c == 'f')?;
+    let number = &word[..word.len() - 1];
+    (!number.is_empty() && number.chars().all(|c| c.is_ascii_digit())).then_some((number, direction))
+}
+
+fn parse_number(token: &str) -> Result<i32, String> {
+    let lower = token.to_lowercase();
+    let (digits, radix, name) = if let Some(hex) = lower.strip_prefix("0x") {
+        (hex, 16, "hexadecimal")
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        (binary, 2, "binary")
+    } else if let Some(octal) = lower.strip_prefix("0o") {
+        (octal, 8, "octal")
+    } else {
+        (&lower[..], 10, "decimal")
+    };
+    i32::from_str_radix(digits, radix).map_err(|_| format!("Invalid {} immediate: {}", name, token))
+}
+
+// reads a string or character literal starting at the opening quote,
+// returns its bytes and the length of the literal including both quotes
+fn parse_quoted(input: &str, quote: char) -> Result<(Vec<u8>, usize), String> {
+    let mut bytes = Vec::new();
+    let mut chars = input.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        let c = match c {
+            c if c == quote => return Ok((bytes, i + 1)),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('"') => '"',
+                Some('\'') => '\'',
+                Some('x') => {
+                    let hex = chars.by_ref().take(2).map(|(_, c)| c).collect::<String>();
+                    let byte =
+                        u8::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape sequence \\x{} in literal", hex))?;
+                    bytes.push(byte);
+                    continue;
+                }
+                Some(other) => return Err(format!("Invalid escape sequence \\{} in literal", other)),
+                None => break,
+            },
+            c => c,
+        };
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+    Err(format!("Unterminated literal: {}", input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<TokenKind> {
+        tokenize(line).unwrap().into_iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn test_instruction() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("Loop: ADD T0, t1, !0x10 ; done"),
+            vec![
+                Ident("Loop".into()),
+                Punct(":"),
+                Ident("ADD".into()),
+                Register(lunacore_isa::Register::T0),
+                Punct(","),
+                Register(lunacore_isa::Register::T1),
+                Punct(","),
+                Punct("!"),
+                Number(16),
+                Comment("; done".into()),
+            ]
+        );
+        assert_eq!(
+            kinds("lod t0, [bp - !(N<<1)] // x"),
+            vec![
+                Ident("lod".into()),
+                Register(lunacore_isa::Register::T0),
+                Punct(","),
+                Punct("["),
+                Register(lunacore_isa::Register::Bp),
+                Punct("-"),
+                Punct("!"),
+                Punct("("),
+                Ident("N".into()),
+                Punct("<<"),
+                Number(1),
+                Punct(")"),
+                Punct("]"),
+                Comment("// x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds(".ascii \"a;b\\n\\x41\", 'c', '\\''"),
+            vec![
+                Ident(".ascii".into()),
+                Str(b"a;b\nA".to_vec()),
+                Punct(","),
+                Char(b'c'),
+                Punct(","),
+                Char(b'\''),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numbers() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("10 0x1F 0XfF 0b101 0B11 0o17 0"),
+            vec![Number(10), Number(31), Number(255), Number(5), Number(3), Number(15), Number(0)]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokenize("  mov t0,  !5").unwrap();
+        let starts = tokens.iter().map(|token| token.span.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![2, 6, 8, 11, 12]);
+        assert_eq!(tokens[0].span, 2..5);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(tokenize("mov t0, #5").is_err());
+        assert!(tokenize(".ascii \"abc").is_err());
+        assert!(tokenize("'ab'").is_err());
+        assert!(tokenize("0xzz").is_err());
+        assert!(tokenize("0b12").is_err());
+        assert!(tokenize("0o9").is_err());
+        assert!(tokenize("0x100000000").is_err());
+        assert!(tokenize("\"\\q\"").is_err());
+    }
+}
@@ -0,0 +1,23 @@
+pub mod analysis;
+pub mod assembler;
+#[allow(arithmetic_overflow)]
+pub mod compiler;
+pub mod debuginfo;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod export;
+pub mod expr;
+#[allow(arithmetic_overflow)]
+pub mod instructions;
+pub mod layout;
+pub mod lexer;
+pub mod linker;
+pub mod listing;
+pub mod macros;
+pub mod object;
+#[allow(arithmetic_overflow)]
+pub mod parser;
+pub mod source;
+
+pub use assembler::{assemble, Options, Program};
+pub use diagnostic::Diagnostic;
@@ -0,0 +1,176 @@
+// links relocatable objects into one program: the text and data sections of every module are
+// placed one after the other in the given order, so the first module holds the entry point
+
+use crate::object::{Object, RelocationKind, SymbolSection, Target};
+use crate::parser::Section;
+use std::collections::{BTreeMap, HashMap};
+
+const ADDRESS_SPACE: usize = 1 << 16;
+
+pub struct Module {
+    // the object file it was read from, for error messages
+    pub name: String,
+    pub object: Object,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Executable {
+    // a global _start, or the beginning of the first module
+    pub entry: u16,
+    pub text: Vec<u16>,
+    pub data: Vec<u8>,
+}
+
+pub fn link(modules: &[Module]) -> Result<Executable, Vec<String>> {
+    let mut errors = Vec::new();
+
+    // where every module's sections start
+    let (mut text_bases, mut data_bases) = (Vec::new(), Vec::new());
+    let (mut text_len, mut data_len) = (0, 0usize);
+    for module in modules {
+        text_bases.push(text_len);
+        text_len += module.object.text.len();
+        data_len = data_len.next_multiple_of(module.object.data_align.max(1));
+        data_bases.push(data_len);
+        data_len += module.object.data.len();
+    }
+    if text_len > ADDRESS_SPACE {
+        errors.push(format!("Program has {} words, more than the {} of the address space", text_len, ADDRESS_SPACE));
+    }
+    if data_len > ADDRESS_SPACE {
+        errors.push(format!("Data has {} bytes, more than the {} of the address space", data_len, ADDRESS_SPACE));
+    }
+
+    let mut globals = HashMap::new();
+    for (m, module) in modules.iter().enumerate() {
+        for symbol in module.object.symbols.iter().filter(|symbol| symbol.global) {
+            let value = match symbol.section {
+                SymbolSection::Text => text_bases[m] as i32 + symbol.value as i32,
+                SymbolSection::Data => data_bases[m] as i32 + symbol.value as i32,
+                SymbolSection::Abs => symbol.value as i32,
+            };
+            if let Some((other, _)) = globals.insert(symbol.name.as_str(), (m, value)) {
+                let (first, second) = (&modules[other].name, &module.name);
+                errors.push(format!("Duplicate symbol {} defined in {} and {}", symbol.name, first, second));
+            }
+        }
+    }
+
+    let mut text = modules.iter().flat_map(|module| module.object.text.iter().copied()).collect::<Vec<_>>();
+    let mut data = vec![0; data_len];
+    for (m, module) in modules.iter().enumerate() {
+        data[data_bases[m]..data_bases[m] + module.object.data.len()].copy_from_slice(&module.object.data);
+    }
+
+    // the modules that use every undefined symbol
+    let mut undefined = BTreeMap::<&str, Vec<&str>>::new();
+    for (m, module) in modules.iter().enumerate() {
+        for relocation in &module.object.relocations {
+            let target = match &relocation.target {
+                Target::Text => text_bases[m] as i32,
+                Target::Data => data_bases[m] as i32,
+                Target::Extern(name) => match globals.get(name.as_str()) {
+                    Some((_, value)) => *value,
+                    None => {
+                        let users = undefined.entry(name).or_default();
+                        if !users.contains(&module.name.as_str()) {
+                            users.push(&module.name);
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            // a text relocation patches one word, a data relocation two bytes
+            let (place, size, len) = match relocation.section {
+                Section::Text => (text_bases[m] + relocation.offset, 1, module.object.text.len()),
+                _ => (data_bases[m] + relocation.offset, 2, module.object.data.len()),
+            };
+            if relocation.offset + size > len {
+                errors.push(format!("Relocation at 0x{:04x} is outside of {}", relocation.offset, module.name));
+                continue;
+            }
+
+            let value = match relocation.kind {
+                RelocationKind::Abs16 => target + relocation.addend,
+                // program addresses wrap around, so every branch reaches
+                RelocationKind::Branch16 => target + relocation.addend - (place as i32 + 2),
+            };
+            if relocation.kind == RelocationKind::Abs16 && !(i16::MIN as i32..=u16::MAX as i32).contains(&value) {
+                errors.push(format!("Relocated value {} does not fit in 16 bits in {}", value, module.name));
+                continue;
+            }
+            match relocation.section {
+                Section::Text => text[place] = value as u16,
+                _ => data[place..place + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+            }
+        }
+    }
+    for (name, users) in undefined {
+        errors.push(format!("Undefined symbol {} referenced in {}", name, users.join(", ")));
+    }
+
+    match errors.is_empty() {
+        true => {
+            let entry = globals.get("_start").map_or(0, |(_, value)| *value as u16);
+            Ok(Executable { entry, text, data })
+        }
+        false => Err(errors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn module(name: &str, program: &str) -> Module {
+        let mut parser = Parser::new();
+        parser.relocatable = true;
+        parser.parse_program(program, name).unwrap();
+        Module {
+            name: name.to_string(),
+            object: Object::from_parser(&parser),
+        }
+    }
+
+    #[test]
+    fn test_link() {
+        let main = module(
+            "main.lunaobj",
+            ".extern double, table\n.data\nvalue: .byte 3\n.text\nmain: lodb t0, [in + !value]\ncall double\nla t1, table\n",
+        );
+        let lib = module(
+            "lib.lunaobj",
+            ".global double, table\n.data\ntable: .word double, 7\n.text\ndouble: add t0, t0, t0\nret\n",
+        );
+        let executable = link(&[main, lib]).unwrap();
+
+        // main: lodb (wide), push pc, jmp double (wide), mov t1 (wide); lib at 7: add, ret
+        assert_eq!(executable.text.len(), 9);
+        assert_eq!(executable.text[1], 0);
+        assert_eq!(executable.text[4], (7 - (3 + 3)) as u16);
+        assert_eq!(executable.text[6], 2);
+        // the lib's data is aligned after main's single byte
+        assert_eq!(executable.data, vec![3, 0, 7, 0, 7, 0]);
+        assert_eq!(executable.entry, 0);
+
+        let start = module("start.lunaobj", ".global _start\nnop\n_start: nop");
+        assert_eq!(link(&[start]).unwrap().entry, 1);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let a = module("a.lunaobj", ".global f\n.extern g, h\nf: call g\njmp h");
+        let b = module("b.lunaobj", ".global f\n.extern g\nf: call g");
+
+        assert_eq!(
+            link(&[a, b]),
+            Err(vec![
+                "Duplicate symbol f defined in a.lunaobj and b.lunaobj".to_string(),
+                "Undefined symbol g referenced in a.lunaobj, b.lunaobj".to_string(),
+                "Undefined symbol h referenced in a.lunaobj".to_string(),
+            ])
+        );
+    }
+}
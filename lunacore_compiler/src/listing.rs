@@ -0,0 +1,218 @@
+use crate::parser::{split_label, Parser, Section};
+use crate::source::SourceLine;
+use std::fmt::Write;
+use std::ops::Range;
+
+const DATA_BYTES_PER_ROW: usize = 8;
+
+// a line as it was assembled, with the number of instructions emitted before it and the data it emitted
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListingLine {
+    pub source: SourceLine,
+    pub section: Section,
+    pub instruction: usize,
+    // addresses in data memory
+    pub data: Range<usize>,
+    // a pseudo-instruction, listed with the instructions it expanded to
+    pub pseudo: bool,
+}
+
+// renders every assembled line as
+//   line  address  hex word  binary word split into fields  source
+// followed by the symbol table
+pub fn listing(parser: &Parser) -> String {
+    let mut out = String::new();
+    let end_pc = parser.program.last().map_or(0, |(pc, instr)| pc + 1 + instr.is_wide() as u16);
+    let pc_at = |index: usize| parser.program.get(index).map_or(end_pc, |(pc, _)| *pc);
+
+    let _ = writeln!(out, " line  addr  hex   binary                 source");
+    let mut file = None;
+    for (i, line) in parser.listing.iter().enumerate() {
+        let location = &line.source.location;
+        if file != Some(&location.file) {
+            let _ = writeln!(out, "\n{}:", location.file);
+            file = Some(&location.file);
+        }
+
+        // everything emitted up to the next line belongs to this one
+        let instructions = match parser.listing.get(i + 1) {
+            Some(next) => line.instruction..next.instruction,
+            None => line.instruction..parser.program.len(),
+        };
+        let marker = if location.expansion.is_some() { '+' } else { ' ' };
+        let prefix = format!("{:>5}{}", location.line, marker);
+        let text = line.source.text.trim_end();
+
+        let indent = &text[..text.len() - text.trim_start().len()];
+        let mut rows = Vec::new();
+        for index in instructions.clone() {
+            let (pc, instruction) = &parser.program[index];
+            for (j, word) in instruction.to_binary().into_iter().enumerate() {
+                let fields = if j == 0 { instruction_fields(word) } else { wide_fields(word) };
+                let mut row = format!("{:04x}  {:04x}  {:<21}", pc + j as u16, word, fields);
+                if line.pseudo && j == 0 {
+                    row += &format!("  {}    {}", indent, instruction_text(parser, index));
+                }
+                rows.push(row);
+            }
+        }
+        // the line itself goes above its expansion
+        if line.pseudo {
+            rows.insert(0, format!("{:04x}  {:27}", pc_at(instructions.start), ""));
+        }
+        // .bss is not part of the data image
+        let data = parser.data.get(line.data.clone()).unwrap_or_default();
+        for (j, bytes) in data.chunks(DATA_BYTES_PER_ROW).enumerate() {
+            let bytes = bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+            rows.push(format!("{:04x}  {:<27}", line.data.start + j * DATA_BYTES_PER_ROW, bytes));
+        }
+
+        // a line that only defines a label still shows the address it points to
+        if rows.is_empty() && split_label(text.trim()).0.is_some() {
+            let addr = match line.section {
+                Section::Text => pc_at(line.instruction),
+                _ => line.data.start as u16,
+            };
+            rows.push(format!("{:04x}  {:27}", addr, ""));
+        }
+
+        match rows.split_first() {
+            Some((first, rest)) => {
+                let _ = writeln!(out, "{} {}  {}", prefix, first, text);
+                for row in rest {
+                    let _ = writeln!(out, "{:6} {}", "", row.trim_end());
+                }
+            }
+            None => {
+                let _ = writeln!(out, "{} {:33}  {}", prefix, "", text);
+            }
+        }
+    }
+
+    out + &symbol_table(parser)
+}
+
+// an assembled instruction written back as assembly, branches show their target
+fn instruction_text(parser: &Parser, index: usize) -> String {
+    let (pc, instruction) = &parser.program[index];
+    let next_wide = parser.program.get(index + 1).is_some_and(|(_, next)| next.is_wide());
+    instruction.text(&|offset| {
+        let target = offset.target(*pc, next_wide);
+        let label = parser.label_map.iter().filter(|(_, pc)| **pc == target).map(|(name, _)| name).min();
+        label.cloned().unwrap_or_else(|| format!("0x{:04x}", target))
+    })
+}
+
+// splits an instruction word into its fields:
+//   DP/MEM   op imm cmd td tn src2
+//   BRANCH   op w cond offset
+fn instruction_fields(word: u16) -> String {
+    let bits = format!("{:016b}", word);
+    match &bits[..2] {
+        "10" => format!("{} {} {} {}", &bits[..2], &bits[2..3], &bits[3..7], &bits[7..]),
+        _ => format!(
+            "{} {} {} {} {} {}",
+            &bits[..2],
+            &bits[2..4],
+            &bits[4..7],
+            &bits[7..10],
+            &bits[10..13],
+            &bits[13..]
+        ),
+    }
+}
+
+// the second word of a wide instruction is a plain 16-bit immediate
+fn wide_fields(word: u16) -> String {
+    format!("{:08b} {:08b}", word >> 8, word & 0xff)
+}
+
+fn symbol_table(parser: &Parser) -> String {
+    let mut symbols = Vec::new();
+    for (name, pc) in parser.text_symbols() {
+        symbols.push((0, *pc as i32, name.as_str(), "text"));
+    }
+    for (name, addr) in &parser.data_label_map {
+        symbols.push((1, *addr as i32, name.as_str(), "data"));
+    }
+    for (name, expr) in &parser.constants {
+        if let Some(Ok(value)) = parser.evaluate(expr, true) {
+            symbols.push((2, value, name.as_str(), "constant"));
+        }
+    }
+    symbols.sort();
+
+    let mut out = String::from("\nSymbols:\n");
+    for (_, value, name, kind) in symbols {
+        let _ = writeln!(out, "  {:<24} {:04x}  {:<8}  {}", name, value as u16, kind, value);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(instruction_fields(0b0001101000000011), "00 01 101 000 000 011");
+        assert_eq!(instruction_fields(0b0111010000101000), "01 11 010 000 101 000");
+        assert_eq!(instruction_fields(0b1000001111111100), "10 0 0001 111111100");
+        assert_eq!(wide_fields(0x0100), "00000001 00000000");
+    }
+
+    #[test]
+    fn test_pseudo_listing() {
+        let mut parser = Parser::new();
+        parser.parse_program("main:\n    call f\nf:  swap t0, t1\n", "test").unwrap();
+        let listing = listing(&parser);
+        let lines = listing.lines().skip(3).take(8).map(str::trim_end).collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                "    1  0000                               main:",
+                "    2  0000                                   call f",
+                "       0000  45a8  01 00 010 110 101 000          push pc",
+                "       0001  9dff  10 0 1110 111111111            jmp f",
+                "    3  0002                               f:  swap t0, t1",
+                "       0002  0801  00 00 100 000 000 001      xor t0, t0, t1",
+                "       0003  0848  00 00 100 001 001 000      xor t1, t1, t0",
+                "       0004  0801  00 00 100 000 000 001      xor t0, t0, t1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let program = ".equ COUNT 3\n.data\nmsg: .asciz \"hello\"\n.text\nmain:\n    mov t0, !COUNT\nloop:\n    dec t0\n    jnz loop\n    mov t1, !0x100\n";
+
+        let mut parser = Parser::new();
+        parser.parse_program(program, "test").unwrap();
+        let listing = listing(&parser);
+        let lines = listing.lines().skip(3).map(str::trim_end).collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                "    1                                     .equ COUNT 3",
+                "    2                                     .data",
+                "    3  0000  68 65 6c 6c 6f 00            msg: .asciz \"hello\"",
+                "    4                                     .text",
+                "    5  0000                               main:",
+                "    6  0000  1a03  00 01 101 000 000 011      mov t0, !COUNT",
+                "    7  0001                               loop:",
+                "    8  0001  1201  00 01 001 000 000 001      dec t0",
+                "    9  0002  83fc  10 0 0001 111111100        jnz loop",
+                "   10  0003  3a40  00 11 101 001 000 000      mov t1, !0x100",
+                "       0004  0100  00000001 00000000",
+                "",
+                "Symbols:",
+                "  main                     0000  text      0",
+                "  loop                     0001  text      1",
+                "  msg                      0000  data      0",
+                "  COUNT                    0003  constant  3",
+            ]
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use crate::lexer::{source_text, token_text, Token};
+use crate::parser::{is_identifier, split_label, split_operands};
+use crate::source::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub const MAX_EXPANSION_DEPTH: usize = 64;
+
+// .macro name param1, param2=default
+//     ... \param1 ... \param2 ...
+// .endm
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<(String, Option<String>)>,
+    pub body: Vec<SourceLine>,
+    pub location: Location,
+}
+
+impl Macro {
+    // `args` are the tokens after .macro, `line` is the text they come from
+    pub fn new(args: &[Token], line: &str, location: &Location) -> Result<Macro, String> {
+        let Some((name, params)) = args.split_first() else {
+            return Err("Missing macro name".into());
+        };
+        let name = name
+            .ident()
+            .filter(|name| is_identifier(name))
+            .ok_or_else(|| format!("Invalid macro name {}", name.text))?;
+
+        let mut parsed: Vec<(String, Option<String>)> = Vec::new();
+        for param in split_operands(params) {
+            let (param, default) = match param {
+                [param, equals, default @ ..] if equals.is_punct("=") => {
+                    (param, Some(source_text(line, default).to_string()))
+                }
+                [param] => (param, None),
+                _ => return Err(format!("Invalid macro parameter {}", token_text(param))),
+            };
+            let param = param
+                .ident()
+                .filter(|param| is_identifier(param))
+                .ok_or_else(|| format!("Invalid macro parameter {}", param.text))?;
+            if parsed.iter().any(|(p, _)| p == param) {
+                return Err(format!("Duplicate macro parameter {}", param));
+            }
+            parsed.push((param.to_string(), default));
+        }
+
+        Ok(Macro {
+            name: name.to_string(),
+            params: parsed,
+            body: Vec::new(),
+            location: location.clone(),
+        })
+    }
+
+    // `id` has to be unique for every expansion, it is used to rename the labels of the body
+    pub fn expand(&self, args: &[&str], call: &Location, id: usize) -> Result<Vec<SourceLine>, String> {
+        if call.expansion_depth() >= MAX_EXPANSION_DEPTH {
+            return Err(format!("Macro expansion too deep, is {} recursive?", self.name));
+        }
+
+        // bind positional and `param=value` arguments, empty arguments fall back to the default
+        let mut values: Vec<Option<&str>> = vec![None; self.params.len()];
+        let mut positional = 0;
+        for arg in args {
+            let (index, value) = match arg.split_once('=').filter(|(param, _)| is_identifier(param.trim())) {
+                Some((param, value)) => {
+                    let param = param.trim();
+                    let index = self
+                        .params
+                        .iter()
+                        .position(|(p, _)| p == param)
+                        .ok_or_else(|| format!("Macro {} has no parameter {}", self.name, param))?;
+                    (index, value.trim())
+                }
+                None => {
+                    positional += 1;
+                    if positional > self.params.len() {
+                        return Err(format!("Too many arguments for macro {}", self.name));
+                    }
+                    (positional - 1, *arg)
+                }
+            };
+
+            if values[index].is_some() {
+                return Err(format!("Parameter {} of macro {} given twice", self.params[index].0, self.name));
+            }
+            if !value.is_empty() {
+                values[index] = Some(value);
+            }
+        }
+
+        let mut substitutions = HashMap::new();
+        for ((param, default), value) in self.params.iter().zip(values) {
+            let value = match (value, default) {
+                (Some(value), _) => value.to_string(),
+                (None, Some(default)) => default.clone(),
+                (None, None) => return Err(format!("Missing argument {} for macro {}", param, self.name)),
+            };
+            substitutions.insert(param.as_str(), value);
+        }
+
+        // labels written in the body get a new name on every expansion, so the macro can be used more than once.
+        // a label passed as an argument (`\name:`) keeps the name the caller gave it
+        let labels = self
+            .body
+            .iter()
+            .filter_map(|line| split_label(line.text.trim()).0)
+            // numeric labels can be defined any number of times anyway
+            .filter(|label| !label.starts_with(|c: char| c.is_ascii_digit()))
+            .map(|label| (label, format!("{}.{}.{}", self.name, id, label.trim_start_matches('.'))))
+            .collect::<HashMap<_, _>>();
+
+        let expansion = Rc::new(Expansion {
+            name: self.name.clone(),
+            definition: self.location.clone(),
+            call: call.clone(),
+        });
+
+        Ok(self
+            .body
+            .iter()
+            .map(|line| SourceLine {
+                text: substitute_params(&rename_identifiers(&line.text, &labels), &substitutions),
+                location: Location {
+                    expansion: Some(expansion.clone()),
+                    ..line.location.clone()
+                },
+            })
+            .collect())
+    }
+}
+
+// replaces every `\param` with its value, except in string and character literals and comments, whose
+// backslashes are escapes: `"hi\n"` stays as it is even when the macro has a parameter n
+fn substitute_params(text: &str, params: &HashMap<&str, String>) -> String {
+    let mut result = String::new();
+    for (code, segment) in segments(text) {
+        let mut rest = segment;
+        while let Some(i) = rest.find('\\').filter(|_| code) {
+            result.push_str(&rest[..i]);
+            let after = &rest[i + 1..];
+            let len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+
+            match params.get(&after[..len]) {
+                Some(value) => {
+                    result.push_str(value);
+                    rest = &after[len..];
+                }
+                None => {
+                    result.push('\\');
+                    rest = after;
+                }
+            }
+        }
+        result.push_str(rest);
+    }
+    result
+}
+
+// replaces whole identifiers, except in literals and comments and parameters (`\name`)
+fn rename_identifiers(text: &str, names: &HashMap<&str, String>) -> String {
+    let is_ident_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+    let mut result = String::new();
+    for (code, segment) in segments(text) {
+        if !code {
+            result.push_str(segment);
+            continue;
+        }
+        let mut word = String::new();
+        let mut param = false;
+        for c in segment.chars().chain(std::iter::once('\n')) {
+            if is_ident_char(c) {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() {
+                let renamed = names.get(word.as_str()).filter(|_| !param);
+                result.push_str(renamed.map_or(word.as_str(), |name| name.as_str()));
+                word.clear();
+            }
+            param = c == '\\';
+            result.push(c);
+        }
+        result.pop(); // the '\n' sentinel
+    }
+    result
+}
+
+// the line in pieces, with true for code and false for string and character literals and the comment
+fn segments(text: &str) -> Vec<(bool, &str)> {
+    let mut segments = Vec::new();
+    let (mut start, mut i) = (0, 0);
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        let len = match c {
+            ';' => Some(rest.len()),
+            '/' if rest.starts_with("//") => Some(rest.len()),
+            '"' => {
+                let mut escaped = false;
+                let end = rest[1..].find(|c: char| {
+                    let end = !escaped && c == '"';
+                    escaped = !escaped && c == '\\';
+                    end
+                });
+                Some(end.map_or(rest.len(), |end| end + 2))
+            }
+            // 'a', '\n', '\x41' and '\''
+            '\'' => match rest[1..].chars().next() {
+                Some('\\') => rest.get(3..).and_then(|after| after.find('\'')).map(|end| end + 4),
+                Some(c) if rest[1 + c.len_utf8()..].starts_with('\'') => Some(c.len_utf8() + 2),
+                _ => None,
+            },
+            _ => None,
+        };
+        match len {
+            Some(len) => {
+                if start < i {
+                    segments.push((true, &text[start..i]));
+                }
+                segments.push((false, &text[i..i + len]));
+                i += len;
+                start = i;
+            }
+            None => i += c.len_utf8(),
+        }
+    }
+    if start < text.len() {
+        segments.push((true, &text[start..]));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::diagnostic::Diagnostic;
+    use crate::parser::Parser;
+
+    // the rendered diagnostics of a failed parse
+    fn errors(result: Result<(), Vec<Diagnostic>>) -> Vec<String> {
+        result.unwrap_err().iter().map(Diagnostic::to_string).collect()
+    }
+
+    #[test]
+    fn test_substitute_params() {
+        let params = HashMap::from([("reg", "t0".to_string()), ("n", "!4".to_string())]);
+        assert_eq!(substitute_params("add \\reg, \\reg, \\n", &params), "add t0, t0, !4");
+        assert_eq!(substitute_params(".ascii \"a\\t\"", &params), ".ascii \"a\\t\"");
+
+        // escapes in literals look like parameters with one-letter names
+        let params = HashMap::from([("n", "7".to_string()), ("t", "t1".to_string()), ("x", "!1".to_string())]);
+        assert_eq!(substitute_params(".asciz \"hi\\n\", \"\\\"\\n\"", &params), ".asciz \"hi\\n\", \"\\\"\\n\"");
+        assert_eq!(substitute_params("mov \\t, !'\\t' + \\n ; \\n", &params), "mov t1, !'\\t' + 7 ; \\n");
+        assert_eq!(substitute_params(".byte '\\x41', '\\'', \\n // \\x", &params), ".byte '\\x41', '\\'', 7 // \\x");
+    }
+
+    #[test]
+    fn test_rename_identifiers() {
+        let names = HashMap::from([("loop", "m.1.loop".to_string())]);
+        assert_eq!(rename_identifiers("loop: jnz loop", &names), "m.1.loop: jnz m.1.loop");
+        assert_eq!(rename_identifiers("jmp loop_end", &names), "jmp loop_end");
+        assert_eq!(rename_identifiers(".ascii \"loop \\\" loop\"", &names), ".ascii \"loop \\\" loop\"");
+        assert_eq!(rename_identifiers("jmp loop ; loop", &names), "jmp m.1.loop ; loop");
+        assert_eq!(rename_identifiers("mov \\loop, !'l' // loop", &names), "mov \\loop, !'l' // loop");
+    }
+
+    #[test]
+    fn parse_macros() {
+        let program = "
+            .macro delay reg, count=!3
+                mov \\reg, \\count
+            loop:
+                dec \\reg
+                jnz loop
+            .endm
+
+            main:
+                delay t0
+                delay reg=t1, count=!0x100
+            ";
+
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse_program(program, "test"), Ok(()));
+
+        assert_eq!(parser.label_map.get("delay.1.loop"), Some(&1));
+        assert_eq!(parser.label_map.get("delay.2.loop"), Some(&5));
+        assert_eq!(parser.label_map.get("loop"), None);
+
+        let binary = compile(&parser.get_program());
+        let expected: Vec<u16> = vec![
+            // delay t0
+            0b0001101000000011,
+            0b0001001000000001,
+            // the next instruction is wide, so pc reads one word further ahead
+            0b1000001111111100,
+            // delay reg=t1, count=!0x100
+            0b0011101001000000, 0x100,
+            0b0001001001001001,
+            0b1000001111111101,
+        ];
+        assert_eq!(binary, expected);
+    }
+
+    #[test]
+    fn parse_macro_labels() {
+        let program = "
+            .macro msg name, n
+            \\name:  .asciz \"hi\\n\"
+            end:    .byte \\n
+            .endm
+            .data
+                msg greet, 7
+                msg bye, 8
+            ";
+
+        let mut parser = Parser::new();
+        assert_eq!(parser.parse_program(program, "test"), Ok(()));
+        // the caller names the labels passed in, the ones of the body are renamed on every expansion
+        assert_eq!(parser.data, b"hi\n\x00\x07hi\n\x00\x08".to_vec());
+        assert_eq!(parser.data_label_map.get("greet"), Some(&0));
+        assert_eq!(parser.data_label_map.get("bye"), Some(&5));
+        assert_eq!(parser.data_label_map.get("msg.1.end"), Some(&4));
+        assert_eq!(parser.data_label_map.get("msg.2.end"), Some(&9));
+        assert_eq!(parser.data_label_map.get("msg.1.greet"), None);
+    }
+
+    #[test]
+    fn macro_errors() {
+        let program = "
+            .macro load reg
+                mov \\reg, !1
+            .endm
+            load t9
+            ";
+
+        let mut parser = Parser::new();
+        assert_eq!(
+            errors(parser.parse_program(program, "test")),
+            vec![
+                "error[E0005]: Invalid register t9\n --> test:3:21\n  |\n3 |                 mov t9, !1\n  |                     ^^\n  = note: in expansion of macro load (defined at test:2), called at test:5"
+            ]
+        );
+
+        assert!(parser.parse_program(".macro m a\n.endm\nm", "test").is_err());
+        assert!(parser.parse_program(".macro m a\n.endm\nm t0, t1", "test").is_err());
+        assert!(parser.parse_program(".macro m\nm\n.endm\nm", "test").is_err());
+        assert!(parser.parse_program(".macro m\nnop", "test").is_err());
+        assert!(parser.parse_program(".macro add\n.endm", "test").is_err());
+    }
+}
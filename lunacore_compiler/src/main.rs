@@ -1,162 +1,358 @@
-#[allow(arithmetic_overflow)]
-mod compiler;
-#[allow(arithmetic_overflow)]
-mod instructions;
-#[allow(arithmetic_overflow)]
-mod parser;
-
-use compiler::compile;
-use parser::*;
+use compiler::export::{Format, Image};
+use compiler::expr::Expr;
+use compiler::layout::Layout;
+use compiler::parser::parse_define;
+use compiler::{assemble, Options};
 use std::env;
-use std::fs::{self, File};
+use std::fs;
 use std::io::{self, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-fn main() -> io::Result<()> {
+// what the compiler writes to its output file
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    // the executable container, or bare words with --raw
+    Bin,
+    // the instruction words as Intel HEX
+    Hex,
+    Lst,
+    Sym,
+    // a relocatable object file for the linker
+    Obj,
+}
+
+impl Emit {
+    fn from_name(name: &str) -> Option<Emit> {
+        match name {
+            "bin" => Some(Emit::Bin),
+            "hex" => Some(Emit::Hex),
+            "lst" => Some(Emit::Lst),
+            "sym" => Some(Emit::Sym),
+            "obj" => Some(Emit::Obj),
+            _ => None,
+        }
+    }
+
+    // prog.luna -> prog.lunaexe, prog.lunaobj, ... and prog.hex
+    fn default_output(&self, input_filename: &str) -> String {
+        match self {
+            Emit::Bin => input_filename.to_owned() + "exe",
+            Emit::Hex => Path::new(input_filename).with_extension("hex").display().to_string(),
+            Emit::Lst => input_filename.to_owned() + "lst",
+            Emit::Sym => input_filename.to_owned() + "sym",
+            Emit::Obj => input_filename.to_owned() + "obj",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Emit::Bin => "Executable",
+            Emit::Hex => "Hex image",
+            Emit::Lst => "Listing",
+            Emit::Sym => "Symbol table",
+            Emit::Obj => "Object file",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+struct Args {
+    // `-` for stdin
+    input_filename: String,
+    // `-` for stdout, None for the default name
+    output_filename: Option<String>,
+    emit: Emit,
+    raw: bool,
+    write_listing: bool,
+    write_debug_info: bool,
+    export: Option<Format>,
+    depth: Option<usize>,
+    data_depth: Option<usize>,
+    fill: usize,
+    include_paths: Vec<PathBuf>,
+    defines: Vec<(String, Expr)>,
+    layout_filename: Option<String>,
+    verbosity: Verbosity,
+}
+
+fn main() -> ExitCode {
     let args: Vec<String> = env::args().collect();
+    let usage = format!(
+        "Usage: {} [-o <output_filename>] [--emit <bin|hex|lst|sym|obj>] [-c] [-l] [-g] [--raw] [-q|-v] [-I <include_dir>]... \
+         [-D <name>[=<value>]]... [--layout <layout_file>] [--export <ihex|readmemh|readmemb|logisim>] [--depth <words>] [--data-depth <bytes>] \
+         [--fill <value>] <input_filename|->",
+        args[0]
+    );
 
-    if args.len() < 2 {
-        eprintln!("Missing Filename\nUsage: {} <input_filename>", args[0]);
-        std::process::exit(1);
+    let args = match parse_args(&args[1..]) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{}\n{}", error, usage);
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args {
+        input_filename: String::new(),
+        output_filename: None,
+        emit: Emit::Bin,
+        raw: false,
+        write_listing: false,
+        write_debug_info: false,
+        export: None,
+        depth: None,
+        data_depth: None,
+        fill: 0,
+        include_paths: Vec::new(),
+        defines: Vec::new(),
+        layout_filename: None,
+        verbosity: Verbosity::Normal,
+    };
+    let mut input_filename = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        if arg == "-o" {
+            let output = rest.next().ok_or("Missing filename after -o")?;
+            parsed.output_filename = Some(output.clone());
+        } else if arg == "--emit" {
+            parsed.emit = rest.next().and_then(|name| Emit::from_name(name)).ok_or("Missing or unknown kind after --emit")?;
+        } else if arg == "--layout" {
+            let layout = rest.next().ok_or("Missing filename after --layout")?;
+            parsed.layout_filename = Some(layout.clone());
+        } else if arg == "-l" || arg == "--listing" {
+            parsed.write_listing = true;
+        } else if arg == "-g" {
+            parsed.write_debug_info = true;
+        } else if arg == "-c" {
+            parsed.emit = Emit::Obj;
+        } else if arg == "--raw" {
+            parsed.raw = true;
+        } else if arg == "-q" || arg == "--quiet" {
+            parsed.verbosity = Verbosity::Quiet;
+        } else if arg == "-v" || arg == "--verbose" {
+            parsed.verbosity = Verbosity::Verbose;
+        } else if arg == "--export" {
+            parsed.export = Some(rest.next().and_then(|name| Format::from_name(name)).ok_or("Missing or unknown format after --export")?);
+        } else if arg == "--depth" || arg == "--data-depth" || arg == "--fill" {
+            let value = rest
+                .next()
+                .and_then(|value| parse_number(value))
+                .ok_or_else(|| format!("Missing or invalid number after {}", arg))?;
+            match arg.as_str() {
+                "--depth" => parsed.depth = Some(value),
+                "--data-depth" => parsed.data_depth = Some(value),
+                _ => parsed.fill = value,
+            }
+        } else if arg == "-I" {
+            let dir = rest.next().ok_or("Missing directory after -I")?;
+            parsed.include_paths.push(PathBuf::from(dir));
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            parsed.include_paths.push(PathBuf::from(dir));
+        } else if let Some(define) = arg.strip_prefix("-D") {
+            let define = match define {
+                "" => rest.next().map(String::as_str).unwrap_or_default(),
+                define => define,
+            };
+            parsed.defines.push(parse_define(define).map_err(|error| format!("Invalid -D {}: {}", define, error))?);
+        } else if arg.starts_with('-') && arg != "-" {
+            return Err(format!("Unknown option {}", arg));
+        } else if input_filename.is_none() {
+            input_filename = Some(arg.clone());
+        } else {
+            return Err(format!("Unexpected argument {}", arg));
+        }
+    }
+
+    parsed.input_filename = input_filename.ok_or("Missing Filename")?;
+    if parsed.raw && parsed.emit != Emit::Bin {
+        return Err("--raw only applies to --emit bin".to_string());
+    }
+    if parsed.layout_filename.is_some() && parsed.emit == Emit::Obj {
+        return Err("--layout only applies to executables, the linker places the sections of object files".to_string());
+    }
+    // the listing and debug info are named after the output, the exported images after the input
+    let to_stdout = parsed.output_filename.as_deref().map_or(parsed.input_filename == "-", |output| output == "-");
+    if to_stdout && (parsed.write_listing || parsed.write_debug_info) {
+        return Err("-l and -g need an output file to name their files after".to_string());
     }
+    if parsed.input_filename == "-" && parsed.export.is_some() {
+        return Err("--export needs an input file to name its files after".to_string());
+    }
+    Ok(parsed)
+}
 
-    let input_filename = &args[1];
-    let output_filename = input_filename.to_owned() + "exe";
+// on failure returns the message to print last, the diagnostics are already on stderr
+fn run(args: &Args) -> Result<(), String> {
+    let from_stdin = args.input_filename == "-";
+    let input = match from_stdin {
+        true => io::read_to_string(io::stdin()),
+        false => fs::read_to_string(&args.input_filename),
+    };
+    let input = input.map_err(|error| format!("{}: {}", args.input_filename, error))?;
 
+    let layout = match &args.layout_filename {
+        Some(filename) => {
+            let input = fs::read_to_string(filename).map_err(|error| format!("{}: {}", filename, error))?;
+            Layout::parse(&input).map_err(|error| format!("{}: {}", filename, error))?
+        }
+        None => Layout::default(),
+    };
+    let output_filename = match &args.output_filename {
+        Some(output_filename) => output_filename.clone(),
+        None if from_stdin => "-".to_string(),
+        None => args.emit.default_output(&args.input_filename),
+    };
+    let status = |message: String| {
+        // stdout only ever carries the output written to `-`
+        if args.verbosity > Verbosity::Quiet {
+            eprintln!("{}", message);
+        }
+    };
+    let detail = |message: String| {
+        if args.verbosity == Verbosity::Verbose {
+            eprintln!("{}", message);
+        }
+    };
 
-    let input = fs::read_to_string(input_filename).unwrap();
-    let mut parser = Parser::new();
-    match parser.parse_program(&input, input_filename) {
-        Err(err) => {
-            eprintln!("{}\nNo file was generated", err);
-            return Ok(());
+    let options = Options {
+        filename: if from_stdin { "<stdin>".to_string() } else { args.input_filename.clone() },
+        include_paths: args.include_paths.clone(),
+        relocatable: args.emit == Emit::Obj,
+        defines: args.defines.clone(),
+        layout,
+    };
+    let program = match assemble(&input, &options) {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}\n", diagnostic);
+            }
+            let errors = diagnostics.iter().filter(|diagnostic| diagnostic.is_error()).count();
+            return Err(format!("{} error(s), no file was generated", errors));
         }
-        Ok(_) => (),
+    };
+    for diagnostic in &program.warnings {
+        eprintln!("{}\n", diagnostic);
+    }
+
+    detail(format!("{} instructions parsed", program.lines.len()));
+    for section in &program.sections {
+        detail(format!("{:?} section: {} bytes at {:#06x}", section.kind, section.bytes.len(), section.address));
+    }
+    detail(format!("Entry point: {:#06x}", program.entry));
+
+    // bare words hold neither an entry point nor an initial sp, the emulator starts them at 0
+    if args.raw && program.entry != 0 {
+        let message = format!("--raw executables start at address 0, but the entry point is {:#06x}", program.entry);
+        return Err(format!("{}; leave out --raw to keep it in the executable", message));
+    }
+    if args.raw && program.sp != 0 {
+        return Err(format!("--raw executables have no initial sp, but the layout sets it to {:#06x}", program.sp));
+    }
+    let contents = match args.emit {
+        Emit::Bin if args.raw => program.words.iter().flat_map(|word| word.to_le_bytes()).collect(),
+        Emit::Bin => program.container().to_bytes(),
+        Emit::Hex => Image::words(&program.words).export(Format::IntelHex).into_bytes(),
+        Emit::Lst => program.listing().into_bytes(),
+        Emit::Sym => program.symbol_table().into_bytes(),
+        Emit::Obj => program.object().to_string().into_bytes(),
+    };
+    // the data image of bare words goes to a file of its own, it has no place on stdout next to them
+    let write_data = args.raw && !program.data.is_empty();
+    if write_data && output_filename == "-" {
+        return Err("The data image of a --raw executable cannot be written to stdout".to_string());
+    }
+    write_output(&output_filename, &contents)?;
+    status(format!("{} generated succesfully at: {}", args.emit.description(), output_filename));
+
+    if write_data {
+        let data_filename = sidecar_filename(&output_filename, "data");
+        write_output(&data_filename, &program.data)?;
+        status(format!("Data image generated succesfully at: {}", data_filename));
+    }
+    if args.emit == Emit::Hex && !program.data.is_empty() {
+        status("Note: the data image is not part of the hex image, --export ihex writes it".to_string());
     }
 
-    println!("{} instructions parsed:\n{:?}", parser.program.len(), parser.program);
-    println!("Labels: {:#?}", parser.label_map);
+    // objects are not debugged directly, their addresses change when they are linked
+    if args.write_debug_info && args.emit != Emit::Obj {
+        let debug_filename = sidecar_filename(&output_filename, "dbg");
+        write_output(&debug_filename, program.debug_info().as_bytes())?;
+        status(format!("Debug info generated succesfully at: {}", debug_filename));
+    }
 
-    let binary = compile(&parser.get_program());
-    let mut file = File::create(&Path::new(&output_filename))?;
-    for word in &binary {
-        file.write_all(&word.to_le_bytes())?;
+    if let Some(format) = args.export {
+        let text = Image::words(&program.words);
+        let data = Image::bytes(&program.data);
+        for (image, depth, name) in [(text, args.depth, "text"), (data, args.data_depth, "data")] {
+            let path = Path::new(&args.input_filename).with_extension(format!("{}.{}", name, format.extension()));
+            if write_image(image, depth, args.fill, format, &path)? {
+                status(format!("Image generated succesfully at: {}", path.display()));
+            }
+        }
     }
 
-    println!("Executable generated succesfully at: {}", output_filename);
+    if args.write_listing {
+        let listing_filename = sidecar_filename(&output_filename, "lst");
+        write_output(&listing_filename, program.listing().as_bytes())?;
+        status(format!("Listing generated succesfully at: {}", listing_filename));
+    }
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::instructions::*;
-
-    #[test]
-    fn parser_test() {
-        let program = "
-                _start: \n
-                        mov t0, !5 \n
-                        mov t1, !0x1000\n
-                        xor t2, t2\n
-                        push pc \n
-                        JMP loop \n
-                        Jmp _end \n
-                \n
-                loop:   cmp t0, !0 \n
-                        jeq endloop \n
-                        savB T0, [T1+T2] \n
-                        dec t0 \n 
-                        inc t2 \n
-                        jmp loop \n
-                endloop:\n
-                        ret \n
-                _end: \n
-            ";
-
-        let mut parser = Parser::new();
-        assert_eq!(parser.parse_program(program, "test"), Ok(()));
-
-        println!("Parsed Program: {:#?}", parser.program);
-        println!("Labels: {:#?}", parser.label_map);
-
-        assert_eq!(parser.label_map.len(), 4);
-        assert_eq!(parser.label_map.get("_start"), Some(&0));
-        assert_eq!(parser.label_map.get("loop"), Some(&9));
-        assert_eq!(parser.label_map.get("endloop"), Some(&17));
-        assert_eq!(parser.label_map.get("_end"), Some(&18));
-
-        assert_eq!(
-            parser.program[4],
-            (
-                5,
-                Instruction::BranchOffset {
-                    cond: 0b1110,
-                    offset: Offset::WideImm16(1)
-                }
-            )
-        );
-    }
-
-    #[test]
-    fn parse_and_compile() {
-        let program = "
-            main:
-                push !104
-                push pc
-                jmp natural_sum
-                mov t3, t0
-                jmp end
-
-            natural_sum:
-                mov t0, !1
-                mov t1, !1
-                lod t2, [sp + !0x02]
-            while:
-                cmp t1, t2
-                jge endwhile
-                add t1, t1, !1 
-                nop
-                add t0, t0, t1
-                jmp while
-
-            endwhile:
-                ret
-
-            end:
-            ";
-
-        let mut parser = Parser::new();
-        assert_eq!(parser.parse_program(program, "test"), Ok(()));
-
-        println!("Parsed Program: {:#?}", parser.program);
-        println!("Labels: {:#?}", parser.label_map);
-
-        let binary = compile(&parser.get_program());
-        let expected: Vec<u16> = vec![
-            // main:
-            0b0111010000101000, 104,
-            0b0100010110101000,
-            0b1011110000000000, 2,
-            0b0000101011000000,
-            0b1011110000000000, 11,
-            // natural_sum:
-            0b0001101000000001,
-            0b0001101001000001,
-            0b0101001010101010,
-            // while:
-            0b0000001111001010,
-            0b1010101000000000, 4,
-            0b0001000001001001,
-            0b1001111000000000,
-            0b0000000000000001,
-            0b1011110000000000, -9i16 as u16,
-            // endwhile:
-            0b0100011110101000,
-            // end:
-        ];
-
-        assert_eq!(binary, expected);
+// writes to stdout for `-`
+fn write_output(filename: &str, contents: &[u8]) -> Result<(), String> {
+    let written = match filename {
+        "-" => io::stdout().lock().write_all(contents),
+        filename => fs::write(Path::new(filename), contents),
+    };
+    written.map_err(|error| format!("{}: {}", filename, error))
+}
+
+// a file that goes with the output, e.g. prog.lunaexe -> prog.lunadata or prog.lunadbg, as the
+// emulator looks for them next to the executable
+fn sidecar_filename(output_filename: &str, kind: &str) -> String {
+    match output_filename.strip_suffix("exe") {
+        Some(stem) => stem.to_owned() + kind,
+        None => format!("{}.luna{}", output_filename, kind),
+    }
+}
+
+// a memory image padded to the memory depth; an empty data image is only written when padded,
+// returns whether it was written
+fn write_image(mut image: Image, depth: Option<usize>, fill: usize, format: Format, path: &Path) -> Result<bool, String> {
+    if image.entries.is_empty() && depth.is_none() {
+        return Ok(false);
+    }
+    if let Some(depth) = depth {
+        let fill = u16::try_from(fill).map_err(|_| format!("Fill value {} does not fit in 16 bits", fill))?;
+        image.pad(depth, fill)?;
+    }
+    fs::write(path, image.export(format)).map_err(|error| format!("{}: {}", path.display(), error))?;
+    Ok(true)
+}
+
+// a decimal or 0x-prefixed hexadecimal count
+fn parse_number(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
     }
 }
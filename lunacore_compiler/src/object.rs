@@ -0,0 +1,302 @@
+// relocatable object files (.lunaobj), written by `compiler -c` and read by the linker
+//
+// the format is line based text:
+//   lunaobj 1
+//   text <words>             followed by the words in hex, 8 per line
+//   data <bytes> <align>     followed by the bytes in hex, 16 per line
+//   symbol <text|data|abs> <value> <global|local> <name>
+//   extern <name>
+//   reloc <text|data> <offset> <abs16|branch16> <.text|.data|symbol> <addend>
+
+use crate::compiler::compile;
+use crate::parser::{Parser, Section};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub text: Vec<u16>,
+    pub data: Vec<u8>,
+    // the data section has to start at a multiple of this
+    pub data_align: usize,
+    pub symbols: Vec<Symbol>,
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolSection {
+    Text,
+    Data,
+    Abs,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: SymbolSection,
+    pub value: u16,
+    pub global: bool,
+}
+
+// what a relocated value is relative to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Text,
+    Data,
+    Extern(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    // the address of the target plus the addend
+    Abs16,
+    // the second word of a wide branch, relative to the address after the branch
+    Branch16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub section: Section,
+    // word index in text, byte offset in data
+    pub offset: usize,
+    pub kind: RelocationKind,
+    pub target: Target,
+    pub addend: i32,
+}
+
+impl Object {
+    pub fn from_parser(parser: &Parser) -> Object {
+        let mut symbols = Vec::new();
+        for (name, pc) in parser.text_symbols() {
+            symbols.push(Symbol::new(name, SymbolSection::Text, *pc, parser));
+        }
+        for (name, addr) in &parser.data_label_map {
+            symbols.push(Symbol::new(name, SymbolSection::Data, *addr, parser));
+        }
+        // constants relative to a label are exported as labels
+        for (name, expr) in &parser.constants {
+            let section = match parser.relocate(expr) {
+                Ok((value, None)) => (SymbolSection::Abs, value),
+                Ok((value, Some(Target::Text))) => (SymbolSection::Text, value),
+                Ok((value, Some(Target::Data))) => (SymbolSection::Data, value),
+                _ => continue,
+            };
+            symbols.push(Symbol::new(name, section.0, section.1 as u16, parser));
+        }
+        symbols.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut externs = parser.externs.keys().cloned().collect::<Vec<_>>();
+        externs.sort();
+
+        Object {
+            text: compile(&parser.get_program()),
+            data: parser.data.clone(),
+            data_align: parser.data_align,
+            symbols,
+            externs,
+            relocations: parser.relocations.clone(),
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<Object, String> {
+        let mut object = Object {
+            text: Vec::new(),
+            data: Vec::new(),
+            data_align: 1,
+            symbols: Vec::new(),
+            externs: Vec::new(),
+            relocations: Vec::new(),
+        };
+
+        let mut lines = input.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        match lines.next() {
+            Some((_, "lunaobj 1")) => (),
+            _ => return Err("Not a LunaCore object file".to_string()),
+        }
+        let (mut text_len, mut data_len) = (0, 0);
+
+        for (i, line) in lines {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let error = |message: &str| format!("line {}: {}", i + 1, message);
+            let number = |field: &str| field.parse::<i32>().map_err(|_| error(&format!("Invalid number {}", field)));
+            let hex = |field: &str| u16::from_str_radix(field, 16).map_err(|_| error(&format!("Invalid hex value {}", field)));
+
+            match fields[..] {
+                ["text", len] => text_len = number(len)? as usize,
+                ["data", len, align] => {
+                    data_len = number(len)? as usize;
+                    object.data_align = number(align)? as usize;
+                }
+                ["symbol", section, value, binding, name] => {
+                    let section = match section {
+                        "text" => SymbolSection::Text,
+                        "data" => SymbolSection::Data,
+                        "abs" => SymbolSection::Abs,
+                        _ => return Err(error(&format!("Invalid section {}", section))),
+                    };
+                    object.symbols.push(Symbol {
+                        name: name.to_string(),
+                        section,
+                        value: hex(value)?,
+                        global: binding == "global",
+                    });
+                }
+                ["extern", name] => object.externs.push(name.to_string()),
+                ["reloc", section, offset, kind, target, addend] => {
+                    let section = match section {
+                        "text" => Section::Text,
+                        "data" => Section::Data,
+                        _ => return Err(error(&format!("Invalid section {}", section))),
+                    };
+                    let kind = match kind {
+                        "abs16" => RelocationKind::Abs16,
+                        "branch16" => RelocationKind::Branch16,
+                        _ => return Err(error(&format!("Invalid relocation {}", kind))),
+                    };
+                    let target = match target {
+                        ".text" => Target::Text,
+                        ".data" => Target::Data,
+                        name => Target::Extern(name.to_string()),
+                    };
+                    object.relocations.push(Relocation {
+                        section,
+                        offset: hex(offset)? as usize,
+                        kind,
+                        target,
+                        addend: number(addend)?,
+                    });
+                }
+                // section contents
+                _ if object.text.len() < text_len => {
+                    for field in fields {
+                        object.text.push(hex(field)?);
+                    }
+                }
+                _ if object.data.len() < data_len => {
+                    for field in fields {
+                        object.data.push(u8::from_str_radix(field, 16).map_err(|_| error(&format!("Invalid byte {}", field)))?);
+                    }
+                }
+                _ => return Err(error(&format!("Unexpected line '{}'", line.trim()))),
+            }
+        }
+
+        if object.text.len() != text_len || object.data.len() != data_len {
+            return Err("Truncated object file".to_string());
+        }
+        Ok(object)
+    }
+}
+
+impl Symbol {
+    fn new(name: &str, section: SymbolSection, value: u16, parser: &Parser) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            section,
+            value,
+            global: parser.globals.contains_key(name),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "lunaobj 1")?;
+        writeln!(f, "text {}", self.text.len())?;
+        for words in self.text.chunks(8) {
+            writeln!(f, "  {}", words.iter().map(|w| format!("{:04x}", w)).collect::<Vec<_>>().join(" "))?;
+        }
+        writeln!(f, "data {} {}", self.data.len(), self.data_align)?;
+        for bytes in self.data.chunks(16) {
+            writeln!(f, "  {}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "))?;
+        }
+        for symbol in &self.symbols {
+            let section = match symbol.section {
+                SymbolSection::Text => "text",
+                SymbolSection::Data => "data",
+                SymbolSection::Abs => "abs",
+            };
+            let binding = if symbol.global { "global" } else { "local" };
+            writeln!(f, "symbol {} {:04x} {} {}", section, symbol.value, binding, symbol.name)?;
+        }
+        for name in &self.externs {
+            writeln!(f, "extern {}", name)?;
+        }
+        for relocation in &self.relocations {
+            // the data memory sections of an object are merged into its data section
+            let section = match relocation.section {
+                Section::Text => "text",
+                _ => "data",
+            };
+            let kind = match relocation.kind {
+                RelocationKind::Abs16 => "abs16",
+                RelocationKind::Branch16 => "branch16",
+            };
+            let target = match &relocation.target {
+                Target::Text => ".text",
+                Target::Data => ".data",
+                Target::Extern(name) => name,
+            };
+            writeln!(f, "reloc {} {:04x} {} {} {}", section, relocation.offset, kind, target, relocation.addend)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let program = "
+            .global main
+            .extern udiv
+            .data
+            count:  .word 7
+            ptr:    .word count + 2
+            .text
+            main:   la t0, count
+                    call udiv
+                    jmp main
+            ";
+
+        let mut parser = Parser::new();
+        parser.relocatable = true;
+        parser.parse_program(program, "test").unwrap();
+        let object = Object::from_parser(&parser);
+
+        assert_eq!(
+            object.relocations,
+            vec![
+                Relocation { section: Section::Text, offset: 1, kind: RelocationKind::Abs16, target: Target::Data, addend: 0 },
+                Relocation {
+                    section: Section::Text,
+                    offset: 4,
+                    kind: RelocationKind::Branch16,
+                    target: Target::Extern("udiv".into()),
+                    addend: 0
+                },
+                Relocation { section: Section::Data, offset: 2, kind: RelocationKind::Abs16, target: Target::Data, addend: 2 },
+            ]
+        );
+        assert_eq!(
+            object.symbols,
+            vec![
+                Symbol { name: "count".into(), section: SymbolSection::Data, value: 0, global: false },
+                Symbol { name: "main".into(), section: SymbolSection::Text, value: 0, global: true },
+                Symbol { name: "ptr".into(), section: SymbolSection::Data, value: 2, global: false },
+            ]
+        );
+        assert_eq!(object.externs, vec!["udiv".to_string()]);
+        assert_eq!(Object::parse(&object.to_string()), Ok(object));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Object::parse("").is_err());
+        assert!(Object::parse("lunaobj 1\ntext 2\n  0001").is_err());
+        assert!(Object::parse("lunaobj 1\nsymbol code 0000 local x").is_err());
+        assert!(Object::parse("lunaobj 1\nreloc text zz abs16 .data 0").is_err());
+    }
+}
@@ -1,432 +1,2444 @@
+use crate::analysis::{Cfg, Roots};
+use crate::diagnostic::*;
+use crate::expr::*;
 use crate::instructions::*;
-use std::collections::HashMap;
+use crate::layout::{Layout, Region, MEMORY_SIZE};
+use crate::lexer::*;
+use crate::listing::ListingLine;
+use crate::macros::{Macro, MAX_EXPANSION_DEPTH};
+use crate::object::{Relocation, RelocationKind, Target};
+use crate::source::*;
+use lunacore_isa::{AluOp, Condition, Instruction as Isa, MemOp, Offset, Register, Src2};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    Data,
+    // constants, placed in data memory like .data
+    Rodata,
+    // zeroed memory, it takes space but nothing is stored for it
+    Bss,
+}
+
+impl Section {
+    pub fn from_name(name: &str) -> Option<Section> {
+        match name {
+            ".text" => Some(Section::Text),
+            ".data" => Some(Section::Data),
+            ".rodata" => Some(Section::Rodata),
+            ".bss" => Some(Section::Bss),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Data => ".data",
+            Section::Rodata => ".rodata",
+            Section::Bss => ".bss",
+        }
+    }
+}
+
+// the sections of data memory, in the order they follow each other by default
+const DATA_SECTIONS: [Section; 3] = [Section::Data, Section::Rodata, Section::Bss];
+
+// where a section was placed, in words for .text and in bytes for the others
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub section: Section,
+    pub address: u16,
+    pub size: usize,
+}
+
+// the words of a .proc block, prologue and epilogue included
+#[derive(Debug, Clone, PartialEq)]
+pub struct Procedure {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+}
 
 pub struct Parser {
     pub program: Vec<(u16, Instruction)>,
+    // the line every instruction of `program` comes from
+    pub sources: Vec<SourceLine>,
     pub label_map: HashMap<String, u16>,
+    // the data memory image from address 0, .bss only takes space in it for object files
+    pub data: Vec<u8>,
+    pub data_label_map: HashMap<String, u16>,
+    pub constants: HashMap<String, Expr>,
+    pub macros: HashMap<String, Macro>,
+    // searched for .include and .incbin files not found next to the including file
+    pub include_paths: Vec<PathBuf>,
+    // every line that was assembled, in order, including included files and macro expansions
+    pub listing: Vec<ListingLine>,
+    // errors and warnings of the last parse_program, in the order they were found
+    pub diagnostics: Vec<Diagnostic>,
+    // assembling an object file: addresses stay relative to their section and symbols can be .extern
+    pub relocatable: bool,
+    // .global and .extern declarations, with the line that declared them
+    pub globals: HashMap<String, SourceLine>,
+    pub externs: HashMap<String, SourceLine>,
+    // values that the linker has to fix once the sections are placed
+    pub relocations: Vec<Relocation>,
+    // the largest alignment the data memory sections asked for
+    pub data_align: usize,
+    // constants defined from outside the source, like -D on the command line
+    pub defines: Vec<(String, Expr)>,
+    // where the sections go, not used for object files
+    pub layout: Layout,
+    // every section that is not empty, .text first
+    pub placements: Vec<Placement>,
+    // the .proc blocks, in the order they were defined
+    pub procedures: Vec<Procedure>,
+    // the contents of every data memory section until they are placed, and the alignment they need
+    section_data: HashMap<Section, Vec<u8>>,
+    section_align: HashMap<Section, usize>,
+    // labels of sections that are placed after the first pass, with their offset in the section
+    pending_labels: HashMap<String, (Section, usize)>,
+    data_fixups: Vec<DataFixup>,
+    expansion_count: usize,
 }
 
-impl Parser {
-    pub fn new() -> Self {
-        Parser {
-            label_map: HashMap::new(),
-            program: Vec::new(),
+// a data value that depends on a label, patched once every label has its address
+struct DataFixup {
+    section: Section,
+    offset: usize,
+    size: usize,
+    expr: Expr,
+    source: SourceLine,
+}
+
+// state of the first pass that is not part of the result
+struct FirstPass {
+    pc: u16,
+    section: Section,
+    lines: VecDeque<SourceLine>,
+    // the macro being defined and the line of its .macro
+    definition: Option<(Macro, SourceLine)>,
+    // the body of a macro whose header had errors is skipped until .endm
+    skip_definition: bool,
+    // text labels by the index of the instruction they point to, their address changes during relaxation
+    text_labels: Vec<(String, usize)>,
+    // the open .if blocks, innermost last
+    conditionals: Vec<Conditional>,
+    // the last label without a dot, `.name` labels belong to it
+    scope: Option<String>,
+    // how many times every numeric label was defined so far
+    numeric_labels: HashMap<String, usize>,
+    // the .org directives of .text, their address is only checked once branches are relaxed
+    origins: Vec<Origin>,
+    // the last line that added to every data memory section, where placement errors are reported
+    section_lines: HashMap<Section, SourceLine>,
+    // the last .frame, its slots are the ones `[bp + name]` refers to
+    frame: Option<Frame>,
+    // the .proc whose .endproc has not been reached yet
+    procedure: Option<OpenProcedure>,
+    // the instructions of every .proc, their addresses are only known once branches are relaxed
+    procedures: Vec<(String, Range<usize>)>,
+    // the open .if blocks on registers and .while loops, innermost last, and how many there were so far
+    blocks: Vec<Block>,
+    block_count: usize,
+}
+
+// `.org address` in .text, before the instruction at `index`
+struct Origin {
+    index: usize,
+    address: u16,
+    source: SourceLine,
+}
+
+impl FirstPass {
+    // the full name of a label written as `.name`, `1b` or `1f`, None for other names
+    fn local_name(&self, name: &str) -> Result<Option<String>, Error> {
+        if let Some(local) = name.strip_prefix('.').filter(|local| is_identifier(local)) {
+            return match &self.scope {
+                Some(scope) => Ok(Some(format!("{}.{}", scope, local))),
+                None => Err(Error::new(Code::UndefinedSymbol, format!("Local label {} has no label above it", name))),
+            };
+        }
+        let Some((number, direction)) = numeric_reference(name) else {
+            return Ok(None);
+        };
+        let count = self.numeric_labels.get(number).copied().unwrap_or(0);
+        match direction {
+            'b' if count == 0 => Err(Error::new(Code::UndefinedSymbol, format!("Numeric label {} is not defined above {}", number, name))),
+            'b' => Ok(Some(numeric_label(number, count))),
+            // the next definition, if there is one
+            _ => Ok(Some(numeric_label(number, count + 1))),
+        }
+    }
+
+    fn resolve_locals(&self, tokens: &[Token]) -> Result<Vec<Token>, Error> {
+        let mut resolved = tokens.to_vec();
+        for token in &mut resolved {
+            let Some(name) = token.ident() else { continue };
+            if let Some(name) = self.local_name(name).map_err(|error| error.at(std::slice::from_ref(token)))? {
+                token.kind = TokenKind::Ident(name);
+            }
+        }
+        Ok(resolved)
+    }
+
+    // `[bp + name]`, for an argument or local of the active frame, becomes `[bp + !routine.name]`.
+    // the frame lasts until the routine ends, the labels of loops inside the routine do not end it
+    fn resolve_slots(&self, tokens: Vec<Token>) -> Result<Vec<Token>, Error> {
+        let mut resolved: Vec<Token> = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            let after_bp = matches!(&resolved[..], [.., open, base, plus]
+                if open.is_punct("[") && base.kind == TokenKind::Register(Register::Bp) && plus.is_punct("+"));
+            let Some(name) = token.ident().filter(|_| after_bp) else {
+                resolved.push(token);
+                continue;
+            };
+            // a name after `bp +` can only be a slot, it is never taken from the frame of another routine
+            let frame = match &self.frame {
+                Some(frame) if frame.slots.iter().any(|slot| slot.name == *name) => frame,
+                Some(frame) => {
+                    let message = format!("{} is not an argument or local of the frame of {}", name, frame.scope);
+                    return Err(Error::new(Code::Frame, message).at(std::slice::from_ref(&token)));
+                }
+                None => {
+                    let message = format!("{} is not an argument or local, no .frame is active here", name);
+                    return Err(Error::new(Code::Frame, message).at(std::slice::from_ref(&token)));
+                }
+            };
+            let name = format!("{}.{}", frame.scope, name);
+            resolved.push(Token::synthetic(TokenKind::Punct("!")));
+            resolved.push(Token { kind: TokenKind::Ident(name), ..token });
+        }
+        Ok(resolved)
+    }
+}
+
+// the arguments and locals declared by .frame for the routine `scope`, at offsets from bp
+struct Frame {
+    scope: String,
+    slots: Vec<Slot>,
+    // where the next argument and local go when they are not given an offset
+    next_arg: i32,
+    next_local: i32,
+    // until .endframe
+    open: bool,
+    // a ret outside of a .proc was reached, the next label without a dot starts another routine
+    returned: bool,
+    source: SourceLine,
+}
+
+impl Frame {
+    // what `sub sp` reserves, sp stays word aligned
+    fn size(&self) -> i32 {
+        (-self.next_local + 1) & !1
+    }
+}
+
+struct Slot {
+    name: String,
+    offset: i32,
+    size: i32,
+}
+
+// a .if on registers or a .while, which branch at run time
+struct Block {
+    id: usize,
+    looping: bool,
+    // how many .elseif branches there were, the next one starts at the `else` label of this count
+    branches: usize,
+    seen_else: bool,
+    // the number of open assembly time .if blocks it is in
+    depth: usize,
+    source: SourceLine,
+}
+
+impl Block {
+    // labels nobody can write, e.g. endw@3
+    fn label(&self, name: &str) -> String {
+        format!("{}@{}", name, self.id)
+    }
+
+    fn next_branch(&self) -> String {
+        self.label(&format!("else{}", self.branches))
+    }
+}
+
+fn is_block_label(name: &str) -> bool {
+    name.contains('@')
+}
+
+// the comparisons of .if and .while, with the branch condition for when they are false
+const COMPARISONS: [(&str, bool, Condition); 10] = [
+    ("==", false, Condition::Nz),
+    ("!=", false, Condition::Z),
+    ("<", false, Condition::Ge),
+    ("<=", false, Condition::Gt),
+    (">", false, Condition::Le),
+    (">=", false, Condition::Lt),
+    ("<", true, Condition::Uge),
+    ("<=", true, Condition::Ugt),
+    (">", true, Condition::Ule),
+    (">=", true, Condition::Ult),
+];
+
+struct OpenProcedure {
+    name: String,
+    // the callee-saved registers the body uses
+    saved: Vec<Register>,
+    // the offsets from bp the saved registers are kept at, once the prologue is assembled
+    saved_at: Option<Vec<(Register, i32)>>,
+    // the first instruction and the listing line of the .proc
+    start: usize,
+    listing_line: usize,
+    // the body ended with a `return` so far
+    returned: bool,
+    source: SourceLine,
+}
+
+// preserved across calls, t0 and t1 carry the arguments and results
+const CALLEE_SAVED: [Register; 2] = [Register::T2, Register::T3];
+
+// `push bp` at bp and the return address pushed by the call at bp+2, the arguments come after them
+const FRAME_HEADER: i32 = 4;
+
+// every definition of a numeric label gets its own symbol, which no identifier can clash with
+fn numeric_label(number: &str, count: usize) -> String {
+    format!("{}.{}", number, count)
+}
+
+struct Conditional {
+    // the lines of the current branch are assembled
+    active: bool,
+    // one of the branches was taken, the ones after it are skipped
+    taken: bool,
+    // the whole block sits in an assembled branch
+    enclosing: bool,
+    seen_else: bool,
+    source: SourceLine,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser {
+            label_map: HashMap::new(),
+            program: Vec::new(),
+            sources: Vec::new(),
+            data: Vec::new(),
+            data_label_map: HashMap::new(),
+            constants: HashMap::new(),
+            macros: HashMap::new(),
+            include_paths: Vec::new(),
+            listing: Vec::new(),
+            diagnostics: Vec::new(),
+            relocatable: false,
+            globals: HashMap::new(),
+            externs: HashMap::new(),
+            relocations: Vec::new(),
+            data_align: 1,
+            defines: Vec::new(),
+            layout: Layout::default(),
+            placements: Vec::new(),
+            procedures: Vec::new(),
+            section_data: HashMap::new(),
+            section_align: HashMap::new(),
+            pending_labels: HashMap::new(),
+            data_fixups: Vec::new(),
+            expansion_count: 0,
+        }
+    }
+
+    // every line is parsed even after an error, so that all of them are reported at once.
+    // on failure returns the errors, warnings are kept in `diagnostics` either way
+    pub fn parse_program(&mut self, input: &str, filename: &str) -> Result<(), Vec<Diagnostic>> {
+        self.program.clear();
+        self.sources.clear();
+        self.label_map.clear();
+        self.data.clear();
+        self.data_label_map.clear();
+        self.constants.clear();
+        self.macros.clear();
+        self.listing.clear();
+        self.diagnostics.clear();
+        self.globals.clear();
+        self.externs.clear();
+        self.relocations.clear();
+        self.data_align = 1;
+        self.placements.clear();
+        self.procedures.clear();
+        self.section_data.clear();
+        self.section_align.clear();
+        self.pending_labels.clear();
+        self.data_fixups.clear();
+        self.expansion_count = 0;
+        self.constants.extend(self.defines.iter().cloned());
+
+        let mut pass = FirstPass {
+            pc: self.region(Section::Text).base,
+            section: Section::Text,
+            lines: source_lines(input, filename, None),
+            definition: None,
+            skip_definition: false,
+            text_labels: Vec::new(),
+            conditionals: Vec::new(),
+            scope: None,
+            numeric_labels: HashMap::new(),
+            origins: Vec::new(),
+            section_lines: HashMap::new(),
+            frame: None,
+            procedure: None,
+            procedures: Vec::new(),
+            blocks: Vec::new(),
+            block_count: 0,
+        };
+
+        // first pass - branches to labels take two words until relaxed, so the pcs are provisional:
+        // relax_branches starts them short (unless written with .w) and recomputes every address
+        while let Some(source) = pass.lines.pop_front() {
+            let section = pass.section;
+            let start = self.section_len(section);
+            self.listing.push(ListingLine {
+                source: source.clone(),
+                section,
+                instruction: self.program.len(),
+                data: start..start,
+                pseudo: false,
+            });
+            let line = self.listing.len() - 1;
+            if let Err(error) = self.parse_line(&source, &mut pass) {
+                self.diagnostics.push(Diagnostic::error(error, &source));
+            }
+            let end = self.section_len(section);
+            self.listing[line].data.end = end;
+            if end > start {
+                pass.section_lines.insert(section, source);
+            }
+        }
+
+        if let Some((mac, source)) = &pass.definition {
+            let error = Error::new(Code::Macro, format!("Missing .endm for macro {}", mac.name));
+            self.diagnostics.push(Diagnostic::error(error, source));
+        }
+        for conditional in &pass.conditionals {
+            let error = Error::new(Code::Conditional, "Missing .endif");
+            self.diagnostics.push(Diagnostic::error(error, &conditional.source));
+        }
+        for block in std::mem::take(&mut pass.blocks) {
+            let error = Error::new(Code::Conditional, if block.looping { "Missing .endw" } else { "Missing .endif" });
+            self.diagnostics.push(Diagnostic::error(error, &block.source));
+            // its branches still need a target
+            let exit = match block.looping {
+                true => block.label("endw"),
+                false if block.seen_else => block.label("endif"),
+                false => block.next_branch(),
+            };
+            self.define_text_label(exit, &mut pass);
+        }
+        if let Some(procedure) = &pass.procedure {
+            let error = Error::new(Code::Procedure, format!("Missing .endproc for {}", procedure.name));
+            self.diagnostics.push(Diagnostic::error(error, &procedure.source));
+        }
+        if let Some(frame) = pass.frame.as_ref().filter(|frame| frame.open) {
+            let error = Error::new(Code::Frame, format!("Missing .endframe for the frame of {}", frame.scope));
+            self.diagnostics.push(Diagnostic::error(error, &frame.source));
+        }
+
+        self.place_sections(&pass);
+        self.check_declarations();
+        let wide = self.relax_branches(&pass.text_labels, &pass.origins);
+
+        // second pass - convert BranchLabel to branch offsets and resolve immediates that depend on labels
+        let pcs = self.program.iter().map(|(pc, _)| *pc as i32).collect::<Vec<_>>();
+        for i in 0..self.program.len() {
+            let (pc, instruction) = self.program[i].clone();
+            let resolved = match instruction {
+                Instruction::BranchLabel { cond, label, .. } => self.resolve_branch(cond, &label, &wide, &pcs, i),
+                Instruction::Expr(instruction, expr) => {
+                    self.resolve_imm(&expr, pc).map(|src2| Instruction::Isa(instruction.with_src2(src2)))
+                }
+                _ => continue,
+            };
+            match resolved {
+                Ok(resolved) => self.program[i].1 = resolved,
+                Err(error) => self.diagnostics.push(Diagnostic::error(error, &self.sources[i])),
+            }
+        }
+
+        // patch data values that depend on labels
+        for fixup in std::mem::take(&mut self.data_fixups) {
+            let address = self.address_of(fixup.section) + fixup.offset;
+            let value = self.relocate(&fixup.expr).and_then(|(value, target)| {
+                if let Some(target) = target {
+                    if fixup.size != 2 {
+                        return Err(Error::new(Code::InvalidExpression, "Only .word values can hold a relocatable address"));
+                    }
+                    self.relocations.push(Relocation {
+                        section: Section::Data,
+                        offset: address,
+                        kind: RelocationKind::Abs16,
+                        target,
+                        addend: value,
+                    });
+                }
+                check_data_value(value, fixup.size)
+            });
+            match value {
+                Ok(value) => self.data[address..address + fixup.size].copy_from_slice(&value.to_le_bytes()[..fixup.size]),
+                Err(error) => self.diagnostics.push(Diagnostic::error(error, &fixup.source)),
+            }
+        }
+
+        self.procedures = pass
+            .procedures
+            .iter()
+            .filter(|(_, range)| !range.is_empty())
+            .map(|(name, range)| {
+                let (end, last) = &self.program[range.end - 1];
+                Procedure {
+                    name: name.clone(),
+                    start: self.program[range.start].0,
+                    end: end + 1 + last.is_wide() as u16,
+                }
+            })
+            .collect();
+
+        self.place_text();
+        self.check_shifts();
+        self.check_control_flow();
+
+        let errors = self.diagnostics.iter().filter(|d| d.is_error()).cloned().collect::<Vec<_>>();
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    fn parse_line(&mut self, source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        let location = &source.location;
+
+        // Collect macro bodies until .endm, they are only tokenized once expanded
+        if pass.definition.is_some() || pass.skip_definition {
+            match directive_name(&source.text).as_str() {
+                ".endm" => {
+                    if let Some((mac, _)) = pass.definition.take() {
+                        self.macros.insert(mac.name.to_lowercase(), mac);
+                    }
+                    pass.skip_definition = false;
+                }
+                ".macro" => return Err(Error::new(Code::Macro, "Nested macro definitions are not supported")),
+                _ => {
+                    if let Some((mac, _)) = pass.definition.as_mut() {
+                        mac.body.push(source.clone());
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        // conditional directives are followed even in skipped branches, everything else there is ignored
+        let directive = directive_name(&source.text);
+        if matches!(directive.as_str(), ".if" | ".ifdef" | ".ifndef" | ".elif" | ".elseif" | ".else" | ".endif") {
+            return self.parse_conditional(&directive, source, pass);
+        }
+        if pass.conditionals.last().is_some_and(|conditional| !conditional.active) {
+            return Ok(());
+        }
+
+        let tokens = tokenize_line(&source.text)?;
+        let mut tokens = &tokens[..];
+
+        // the prologue of a .proc goes before the first line of its body that is not a directive,
+        // so that the .frame it reserves is declared by then
+        let directive_line = match tokens {
+            [_, colon, ..] if colon.is_punct(":") => false,
+            [first, ..] => first.ident().is_some_and(|name| name.starts_with('.')),
+            [] => true,
+        };
+        if !directive_line && pass.section == Section::Text && pass.procedure.is_some() {
+            self.parse_prologue(pass)?;
+            if let Some(procedure) = pass.procedure.as_mut() {
+                procedure.returned = false;
+            }
+        }
+
+        // Handle labels
+        if let [first, colon, rest @ ..] = tokens {
+            let label = match &first.kind {
+                _ if !colon.is_punct(":") => None,
+                TokenKind::Ident(name) if is_identifier(name) => {
+                    // macro labels are renamed with dots and do not start a new scope
+                    if !name.contains('.') {
+                        pass.scope = Some(name.clone());
+                        // the frame of a routine outside of a .proc ends at the first label after its ret
+                        if pass.section == Section::Text && pass.frame.as_ref().is_some_and(|frame| frame.returned && !frame.open) {
+                            pass.frame = None;
+                        }
+                    }
+                    Some(name.clone())
+                }
+                TokenKind::Ident(name) if name.starts_with('.') => pass.local_name(name).map_err(|error| error.at(&tokens[..1]))?,
+                TokenKind::Number(_) if first.text.chars().all(|c| c.is_ascii_digit()) => {
+                    let count = pass.numeric_labels.entry(first.text.clone()).or_default();
+                    *count += 1;
+                    Some(numeric_label(&first.text, *count))
+                }
+                _ => None,
+            };
+            if let Some(label) = label {
+                if self.is_defined(&label) {
+                    return Err(Error::new(Code::DuplicateSymbol, format!("Duplicate label: {}", label)).at(&tokens[..1]));
+                }
+                match pass.section {
+                    Section::Text => self.define_text_label(label, pass),
+                    section => {
+                        let offset = self.section_len(section);
+                        if let Some(base) = self.fixed_base(section) {
+                            self.data_label_map.insert(label, (base as usize + offset) as u16);
+                        } else {
+                            self.pending_labels.insert(label, (section, offset));
+                        }
+                    }
+                };
+                tokens = rest;
+            }
+        }
+
+        // Skip empty lines and comments
+        let Some(first) = tokens.first() else {
+            return Ok(());
+        };
+        let Some(name) = first.ident() else {
+            let message = format!("Expected an instruction or directive, found '{}'", first.text);
+            return Err(Error::new(Code::Syntax, message).at(&tokens[..1]));
+        };
+        // section names start with a dot like local labels
+        let args = match name.to_lowercase().as_str() {
+            ".section" => tokens[1..].to_vec(),
+            _ => pass.resolve_slots(pass.resolve_locals(&tokens[1..]).map_err(|error| error.at(tokens))?)?,
+        };
+        let args = &args[..];
+
+        // Handle directives
+        if name.starts_with('.') {
+            return match name.to_lowercase().as_str() {
+                ".macro" => match self.parse_macro(args, &source.text, location) {
+                    Ok(mac) => {
+                        pass.definition = Some((mac, source.clone()));
+                        Ok(())
+                    }
+                    Err(error) => {
+                        pass.skip_definition = true;
+                        Err(error)
+                    }
+                },
+                ".endm" => Err(Error::new(Code::Macro, ".endm without .macro").at(&tokens[..1])),
+                ".include" => {
+                    let included = self.include_file(args, location)?;
+                    for line in included.into_iter().rev() {
+                        pass.lines.push_front(line);
+                    }
+                    Ok(())
+                }
+                ".word" if pass.section == Section::Text => self.parse_text_words(args, source, pass),
+                ".org" => self.parse_org(args, source, pass),
+                ".frame" | ".endframe" | ".arg" | ".local" => self.parse_frame(name.to_lowercase().as_str(), args, source, pass),
+                ".while" | ".endw" => self.parse_block(name.to_lowercase().as_str(), args, source, pass),
+                ".proc" => self.parse_proc(args, source, pass),
+                ".endproc" => self.parse_endproc(args, pass),
+                directive => self.parse_directive(directive, args, &mut pass.section, source),
+            }
+            .map_err(|error| error.at(tokens));
+        }
+
+        // Expand macro invocations in place
+        if let Some(mac) = self.macros.get(&name.to_lowercase()) {
+            self.expansion_count += 1;
+            let operands = split_operands(args)
+                .into_iter()
+                .map(|operand| source_text(&source.text, operand))
+                .collect::<Vec<_>>();
+            let expanded = mac
+                .expand(&operands, location, self.expansion_count)
+                .map_err(|err| Error::new(Code::Macro, err).at(tokens))?;
+            for line in expanded.into_iter().rev() {
+                pass.lines.push_front(line);
+            }
+            return Ok(());
+        }
+
+        if pass.section != Section::Text {
+            return Err(Error::new(Code::Section, "Instruction outside of .text section").at(tokens));
+        }
+
+        // returns from a .proc through its epilogue
+        if name.eq_ignore_ascii_case("return") {
+            expect_no_operands(name, args).map_err(|error| error.at(tokens))?;
+            self.parse_epilogue(pass).map_err(|error| error.at(tokens))?;
+            if let Some(procedure) = pass.procedure.as_mut() {
+                procedure.returned = true;
+            }
+            return Ok(());
+        }
+
+        let operands = split_operands(args);
+        let instructions = parse_instruction(name, &operands, &|expr| self.evaluate(expr, false))
+            .map_err(|error| error.at(tokens))?;
+        if is_pseudo(&name.to_lowercase(), &operands) {
+            if let Some(line) = self.listing.last_mut() {
+                line.pseudo = true;
+            }
+        }
+        self.push_instructions(instructions, source, pass);
+        Ok(())
+    }
+
+    fn push_instructions(&mut self, instructions: Vec<Instruction>, source: &SourceLine, pass: &mut FirstPass) {
+        for instruction in instructions {
+            if pass.procedure.is_none() && matches!(instruction, Instruction::Isa(Isa::Mem { op: MemOp::Pop, td: Register::Pc, .. })) {
+                if let Some(frame) = pass.frame.as_mut() {
+                    frame.returned = true;
+                }
+            }
+            let pc_step = if instruction.is_wide() { 2 } else { 1 };
+            self.program.push((pass.pc, instruction));
+            self.sources.push(source.clone());
+            pass.pc += pc_step;
+        }
+    }
+
+    fn define_text_label(&mut self, label: String, pass: &mut FirstPass) {
+        pass.text_labels.push((label.clone(), self.program.len()));
+        self.label_map.insert(label, pass.pc);
+    }
+
+    fn parse_conditional(&mut self, directive: &str, source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        let tokens = tokenize_line(&source.text)?;
+        let args = &tokens[1..];
+        let enclosing = pass.conditionals.last().is_none_or(|conditional| conditional.active);
+
+        // a condition on registers can only be checked at run time, the rest of the block follows it
+        // unless an assembly time .if was opened inside it
+        let registers = args.iter().any(|token| matches!(token.kind, TokenKind::Register(_)));
+        let in_block = pass.blocks.last().is_some_and(|block| block.depth == pass.conditionals.len());
+        if (directive == ".if" && registers && enclosing) || (in_block && !matches!(directive, ".if" | ".ifdef" | ".ifndef")) {
+            let args = pass.resolve_slots(pass.resolve_locals(args).map_err(|error| error.at(&tokens))?)?;
+            return self.parse_block(directive, &args, source, pass).map_err(|error| error.at(&tokens));
+        }
+
+        match directive {
+            ".if" | ".ifdef" | ".ifndef" => {
+                // conditions in a skipped branch are not evaluated, they may not even be valid there
+                let condition = if enclosing { self.condition(directive, args) } else { Ok(false) };
+                let active = *condition.as_ref().unwrap_or(&false);
+                pass.conditionals.push(Conditional {
+                    active,
+                    taken: active,
+                    enclosing,
+                    seen_else: false,
+                    source: source.clone(),
+                });
+                condition.map(|_| ())
+            }
+            ".elif" | ".elseif" | ".else" => {
+                let Some(conditional) = pass.conditionals.last_mut() else {
+                    return Err(Error::new(Code::Conditional, format!("{} without .if", directive)).at(&tokens[..1]));
+                };
+                if conditional.seen_else {
+                    return Err(Error::new(Code::Conditional, format!("{} after .else", directive)).at(&tokens[..1]));
+                }
+                let condition = match directive {
+                    ".elif" | ".elseif" if conditional.enclosing && !conditional.taken => self.condition(".if", args),
+                    ".elif" | ".elseif" => Ok(false),
+                    _ => {
+                        conditional.seen_else = true;
+                        expect_no_operands(directive, args).map(|_| conditional.enclosing && !conditional.taken)
+                    }
+                };
+                conditional.active = *condition.as_ref().unwrap_or(&false);
+                conditional.taken |= conditional.active;
+                condition.map(|_| ())
+            }
+            _ => {
+                if pass.conditionals.pop().is_none() {
+                    return Err(Error::new(Code::Conditional, ".endif without .if").at(&tokens[..1]));
+                }
+                expect_no_operands(directive, args)
+            }
+        }
+    }
+
+    // whether the branch of an .if or .ifdef/.ifndef is taken
+    fn condition(&self, directive: &str, args: &[Token]) -> Result<bool, Error> {
+        if directive != ".if" {
+            let name = match args {
+                [name] => name.ident().filter(|name| is_identifier(name)),
+                _ => None,
+            };
+            let Some(name) = name else {
+                return Err(Error::new(Code::InvalidOperands, format!("Invalid operands for {}, expected a symbol", directive)).at(args));
+            };
+            let defined = self.is_defined(name) || self.externs.contains_key(name);
+            return Ok(defined == (directive == ".ifdef"));
+        }
+
+        if args.is_empty() {
+            return Err(Error::new(Code::InvalidOperands, "Missing condition for .if"));
+        }
+        let expr = Expr::parse_tokens(args).map_err(|error| error.at(args))?;
+        match self.evaluate(&expr, false) {
+            Some(value) => Ok(value.map_err(|error| error.at(args))? != 0),
+            None => {
+                let message = "Condition has to be known where it is written, it cannot use code labels or later constants";
+                Err(Error::new(Code::Conditional, message).at(args))
+            }
+        }
+    }
+
+    // .if/.elseif/.else/.endif on registers and .while/.endw: every condition becomes a cmp and a branch
+    // past its body for when it is false, e.g. `.while t1 <u t2` loops with `cmp t1, t2` / `juge` to the end
+    fn parse_block(&mut self, directive: &str, args: &[Token], source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        if pass.section != Section::Text {
+            return Err(Error::new(Code::Section, format!("{} outside of .text section", directive)));
+        }
+        self.parse_prologue(pass)?;
+        if let Some(procedure) = pass.procedure.as_mut() {
+            procedure.returned = false;
+        }
+        if let Some(line) = self.listing.last_mut() {
+            line.pseudo = true;
+        }
+
+        let depth = pass.conditionals.len();
+        if matches!(directive, ".if" | ".while") {
+            pass.block_count += 1;
+            let block = Block {
+                id: pass.block_count,
+                looping: directive == ".while",
+                branches: 0,
+                seen_else: false,
+                depth,
+                source: source.clone(),
+            };
+            let exit = match block.looping {
+                true => block.label("endw"),
+                false => block.next_branch(),
+            };
+            if block.looping {
+                self.define_text_label(block.label("while"), pass);
+            }
+            // the block is open even when its condition is invalid, so that its end is not reported too
+            pass.blocks.push(block);
+            return self.parse_comparison(args, exit, source, pass);
+        }
+
+        let Some(block) = pass.blocks.pop_if(|block| block.depth == depth) else {
+            let message = match directive {
+                ".endw" => ".endw without .while",
+                _ => "Missing .endif for the assembly time .if inside the run time block",
+            };
+            return Err(Error::new(Code::Conditional, message));
+        };
+        if block.looping != (directive == ".endw") {
+            let message = match block.looping {
+                true => format!("Missing .endw before {}", directive),
+                false => format!("Missing .endif before {}", directive),
+            };
+            pass.blocks.push(block);
+            return Err(Error::new(Code::Conditional, message));
+        }
+        let jump = |label: String| Instruction::BranchLabel {
+            cond: Condition::Mp,
+            label,
+            wide: false,
+        };
+
+        match directive {
+            ".endw" => {
+                expect_no_operands(directive, args)?;
+                self.push_instructions(vec![jump(block.label("while"))], source, pass);
+                self.define_text_label(block.label("endw"), pass);
+            }
+            ".endif" => {
+                expect_no_operands(directive, args)?;
+                if !block.seen_else {
+                    self.define_text_label(block.next_branch(), pass);
+                }
+                self.define_text_label(block.label("endif"), pass);
+            }
+            // .elif, .elseif and .else: the branch before jumps over the rest
+            _ => {
+                if block.seen_else {
+                    let message = format!("{} after .else", directive);
+                    pass.blocks.push(block);
+                    return Err(Error::new(Code::Conditional, message));
+                }
+                self.push_instructions(vec![jump(block.label("endif"))], source, pass);
+                self.define_text_label(block.next_branch(), pass);
+                let mut block = block;
+                let result = match directive {
+                    ".else" => {
+                        block.seen_else = true;
+                        expect_no_operands(directive, args)
+                    }
+                    _ => {
+                        block.branches += 1;
+                        let exit = block.next_branch();
+                        self.parse_comparison(args, exit, source, pass)
+                    }
+                };
+                pass.blocks.push(block);
+                return result;
+            }
+        }
+        Ok(())
+    }
+
+    // `register op register` or `register op !imm`, where op is one of == != < <= > >= compared as signed
+    // values, or <u <=u >u >=u as unsigned ones; branches to `exit` when the comparison is false
+    fn parse_comparison(&mut self, args: &[Token], exit: String, source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        let invalid = || {
+            let message = format!("Expected a comparison like `t0 < t1` or `t0 == !0`, found '{}'", token_text(args));
+            Error::new(Code::InvalidOperands, message).at(args)
+        };
+        let i = args
+            .iter()
+            .position(|token| COMPARISONS.iter().any(|(op, _, _)| token.is_punct(op)))
+            .ok_or_else(invalid)?;
+        let (left, op, right) = (&args[..i], &args[i], &args[i + 1..]);
+        let (unsigned, right) = match right {
+            [u, rest @ ..] if u.ident() == Some("u") && u.span.start == op.span.end => (true, rest),
+            _ => (false, right),
+        };
+        let (_, _, condition) = COMPARISONS
+            .iter()
+            .find(|(name, comparison, _)| op.is_punct(name) && *comparison == unsigned)
+            .ok_or_else(|| {
+                let message = format!("{}u is not a comparison, only ordering can be unsigned", op.text);
+                Error::new(Code::InvalidOperands, message).at(&args[i..i + 2])
+            })?;
+
+        let mut instructions = parse_instruction("cmp", &[left, right], &|expr| self.evaluate(expr, false))?;
+        instructions.push(Instruction::BranchLabel {
+            cond: *condition,
+            label: exit,
+            wide: false,
+        });
+        self.push_instructions(instructions, source, pass);
+        Ok(())
+    }
+
+    // .frame ... .endframe declares the arguments and locals of the routine it is in with .arg and .local,
+    // as constants named after the routine; the arguments go up from bp+4 and the locals down from bp
+    fn parse_frame(&mut self, directive: &str, args: &[Token], source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        let open = pass.frame.as_mut().filter(|frame| frame.open);
+        match directive {
+            ".frame" => {
+                expect_no_operands(directive, args)?;
+                if let Some(frame) = open {
+                    return Err(Error::new(Code::Frame, format!("Missing .endframe for the frame of {}", frame.scope)));
+                }
+                let scope = pass.scope.clone().ok_or_else(|| Error::new(Code::Frame, ".frame has no label above it to belong to"))?;
+                if pass.frame.as_ref().is_some_and(|frame| frame.scope == scope) {
+                    return Err(Error::new(Code::Frame, format!("{} already has a frame", scope)));
+                }
+                // the frame is still declared, so that its slots do not cause more errors
+                let late = pass.procedure.as_ref().filter(|procedure| procedure.saved_at.is_some()).map(|procedure| {
+                    let message = format!(".frame has to come before the first instruction of {}, whose prologue reserves it", procedure.name);
+                    Error::new(Code::Frame, message)
+                });
+                pass.frame = Some(Frame {
+                    scope,
+                    slots: Vec::new(),
+                    next_arg: FRAME_HEADER,
+                    next_local: 0,
+                    open: true,
+                    returned: false,
+                    source: source.clone(),
+                });
+                late.map_or(Ok(()), Err)
+            }
+            ".endframe" => {
+                expect_no_operands(directive, args)?;
+                let frame = open.ok_or_else(|| Error::new(Code::Frame, ".endframe without .frame"))?;
+                frame.open = false;
+                self.define_slot(&frame.scope, "frame_size", frame.size())
+            }
+            // .arg name {, size {, offset}} / .local name {, size {, offset}}
+            _ => {
+                let frame = open.ok_or_else(|| Error::new(Code::Frame, format!("{} outside of .frame", directive)))?;
+                let operands = split_operands(args);
+                let (name, size, offset) = match operands[..] {
+                    [name] => (name, 2, None),
+                    [name, size] => (name, self.parse_constant(size, 1, 0x8000)?, None),
+                    [name, size, offset] => (name, self.parse_constant(size, 1, 0x8000)?, Some(self.parse_constant(offset, -0x8000, 0x7fff)?)),
+                    _ => return Err(Error::new(Code::InvalidOperands, format!("Invalid operands for {}", directive))),
+                };
+                let name = match name {
+                    [token] => token.ident().filter(|name| is_identifier(name) && !name.contains('.')),
+                    _ => None,
+                }
+                .ok_or_else(|| Error::new(Code::InvalidOperands, format!("Invalid slot name {}", token_text(name))).at(name))?;
+
+                // words are kept at even offsets, bytes go anywhere
+                let align = if size >= 2 { 2 } else { 1 };
+                let argument = directive == ".arg";
+                let kind = if argument { "Argument" } else { "Local" };
+                let offset = match offset {
+                    Some(offset) => offset,
+                    None if argument => (frame.next_arg + align - 1) / align * align,
+                    None => (frame.next_local - size) - (frame.next_local - size).rem_euclid(align),
+                };
+                let range = slot_range(offset, size);
+
+                if argument && offset < FRAME_HEADER {
+                    let message = format!("Argument {} at {} is below bp+4, where the arguments start after the saved bp and return address", name, range);
+                    return Err(Error::new(Code::Frame, message).at(args));
+                }
+                if !argument && offset + size > 0 {
+                    return Err(Error::new(Code::Frame, format!("Local {} at {} is not below bp, where the locals are", name, range)).at(args));
+                }
+                if offset % align != 0 {
+                    return Err(Error::new(Code::Frame, format!("{} {} at {} is a word at an odd offset", kind, name, range)).at(args));
+                }
+                if let Some(slot) = frame.slots.iter().find(|slot| offset < slot.offset + slot.size && slot.offset < offset + size) {
+                    let message = format!("{} {} at {} overlaps {} at {}", kind, name, range, slot.name, slot_range(slot.offset, slot.size));
+                    return Err(Error::new(Code::Frame, message).at(args));
+                }
+                self.define_slot(&frame.scope, name, offset).map_err(|error| error.at(operands[0]))?;
+
+                frame.slots.push(Slot { name: name.to_string(), offset, size });
+                frame.next_arg = frame.next_arg.max(offset + size);
+                frame.next_local = frame.next_local.min(offset);
+                Ok(())
+            }
+        }
+    }
+
+    // .proc name ... .endproc assembles the body between the prologue and epilogue of the calling convention,
+    // saving the callee-saved registers the body uses; `return` in the body jumps out through the epilogue
+    fn parse_proc(&mut self, args: &[Token], source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        let name = match args {
+            [token] => token.ident().filter(|name| is_identifier(name) && !name.contains('.')),
+            _ => None,
+        }
+        .ok_or_else(|| Error::new(Code::InvalidOperands, format!("Expected a procedure name after .proc, found '{}'", token_text(args))).at(args))?;
+        if let Some(procedure) = &pass.procedure {
+            return Err(Error::new(Code::Procedure, format!("Missing .endproc for {} before .proc", procedure.name)));
+        }
+        if pass.section != Section::Text {
+            return Err(Error::new(Code::Section, ".proc outside of .text section"));
+        }
+
+        // the registers to save are only known from the lines up to .endproc
+        let end = pass.lines.iter().position(|line| directive_name(&line.text) == ".endproc");
+        let body = pass.lines.range(..end.ok_or_else(|| Error::new(Code::Procedure, format!("Missing .endproc for {}", name)))?);
+        let mut used = [false; 8];
+        for line in body {
+            self.find_registers(&line.text, &mut used, 0);
+        }
+
+        // a .proc starts another routine, the frame of the code above it ends here
+        pass.frame = pass.frame.take().filter(|frame| frame.open);
+        self.parse_generated(&[format!("{}:", name)], source, pass)?;
+        pass.procedure = Some(OpenProcedure {
+            name: name.to_string(),
+            saved: CALLEE_SAVED.into_iter().filter(|register| used[register.bits() as usize]).collect(),
+            saved_at: None,
+            start: self.program.len(),
+            listing_line: self.listing.len() - 1,
+            returned: false,
+            source: source.clone(),
+        });
+        Ok(())
+    }
+
+    fn parse_endproc(&mut self, args: &[Token], pass: &mut FirstPass) -> Result<(), Error> {
+        expect_no_operands(".endproc", args)?;
+        if pass.procedure.is_none() {
+            return Err(Error::new(Code::Procedure, ".endproc without .proc"));
+        }
+        self.parse_prologue(pass)?;
+        // falling off the end of the body returns too
+        if !pass.procedure.as_ref().is_some_and(|procedure| procedure.returned) {
+            self.parse_epilogue(pass)?;
+        }
+        let procedure = pass.procedure.take().unwrap();
+        pass.procedures.push((procedure.name.clone(), procedure.start..self.program.len()));
+        if pass.frame.as_ref().is_some_and(|frame| frame.scope == procedure.name) {
+            pass.frame = None;
+        }
+        Ok(())
+    }
+
+    // push bp / mov bp, sp / sub sp, !frame_size / push the saved registers, once per .proc
+    fn parse_prologue(&mut self, pass: &mut FirstPass) -> Result<(), Error> {
+        let Some(procedure) = pass.procedure.as_mut().filter(|procedure| procedure.saved_at.is_none()) else {
+            return Ok(());
+        };
+        let frame_size = match &pass.frame {
+            Some(frame) if frame.scope == procedure.name && frame.open => {
+                let message = format!("Missing .endframe before the first instruction of {}", procedure.name);
+                return Err(Error::new(Code::Frame, message));
+            }
+            Some(frame) if frame.scope == procedure.name => frame.size(),
+            _ => 0,
+        };
+
+        let mut lines = vec!["push bp".to_string(), "mov bp, sp".to_string()];
+        if frame_size > 0 {
+            lines.push(format!("sub sp, !{}", frame_size));
+        }
+        let mut saved_at = Vec::new();
+        for &register in &procedure.saved {
+            lines.push(format!("push {}", register.name()));
+            saved_at.push((register, -frame_size - 2 * (saved_at.len() as i32 + 1)));
+        }
+        procedure.saved_at = Some(saved_at);
+        let (source, listing_line) = (procedure.source.clone(), procedure.listing_line);
+
+        let start = self.program.len();
+        self.parse_generated(&lines, &source, pass)?;
+        // listed under the .proc, the lines in between only declare the frame
+        self.listing[listing_line].pseudo = true;
+        for line in &mut self.listing[listing_line + 1..] {
+            line.instruction += self.program.len() - start;
+        }
+        Ok(())
+    }
+
+    // restores the saved registers from where the prologue pushed them, so the stack does not have to be balanced
+    fn parse_epilogue(&mut self, pass: &mut FirstPass) -> Result<(), Error> {
+        let procedure = pass.procedure.as_ref().ok_or_else(|| Error::new(Code::Procedure, "return outside of .proc"))?;
+        let mut lines = Vec::new();
+        for (register, offset) in procedure.saved_at.iter().flatten().rev() {
+            lines.push(format!("lod {}, [bp + !{}]", register.name(), offset));
+        }
+        lines.extend(["mov sp, bp", "pop bp", "ret"].map(String::from));
+
+        // listed under the `return` or .endproc line being parsed
+        let line = self.listing.last_mut().unwrap();
+        line.pseudo = true;
+        let source = line.source.clone();
+        self.parse_generated(&lines, &source, pass)
+    }
+
+    // lines the assembler writes itself, assembled as if they were at `source`
+    fn parse_generated(&mut self, lines: &[String], source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        for text in lines {
+            let line = SourceLine {
+                text: text.clone(),
+                location: source.location.clone(),
+            };
+            self.parse_line(&line, pass)?;
+        }
+        Ok(())
+    }
+
+    // marks the registers a line uses, including in the bodies of the macros it invokes
+    fn find_registers(&self, line: &str, used: &mut [bool; 8], depth: usize) {
+        let tokens = tokenize(line).unwrap_or_default();
+        for token in &tokens {
+            if let TokenKind::Register(r) = token.kind {
+                used[r.bits() as usize] = true;
+            }
+        }
+        let name = match &tokens[..] {
+            [_, colon, name, ..] if colon.is_punct(":") => name.ident(),
+            [name, ..] => name.ident(),
+            [] => None,
+        };
+        if let Some(mac) = name.and_then(|name| self.macros.get(&name.to_lowercase())).filter(|_| depth < MAX_EXPANSION_DEPTH) {
+            for line in &mac.body {
+                self.find_registers(&line.text, used, depth + 1);
+            }
+        }
+    }
+
+    fn define_slot(&mut self, scope: &str, name: &str, value: i32) -> Result<(), Error> {
+        let name = format!("{}.{}", scope, name);
+        if self.is_defined(&name) {
+            return Err(Error::new(Code::DuplicateSymbol, format!("Duplicate symbol: {}", name)));
+        }
+        self.constants.insert(name, Expr::Num(value));
+        Ok(())
+    }
+
+    // picks the shortest encoding for every BranchLabel: all of them start short and the ones whose
+    // target is out of range are widened, until no label moves anymore. widening only ever moves labels
+    // further apart, so this always terminates. updates the pcs and label_map, returns which instructions are wide
+    fn relax_branches(&mut self, text_labels: &[(String, usize)], origins: &[Origin]) -> Vec<bool> {
+        let mut wide = self
+            .program
+            .iter()
+            .map(|(_, instruction)| match instruction {
+                // the linker places externs, so they may be anywhere
+                Instruction::BranchLabel { label, wide, .. } => *wide || (self.relocatable && self.externs.contains_key(label)),
+                instruction => instruction.is_wide(),
+            })
+            .collect::<Vec<_>>();
+
+        let base = self.region(Section::Text).base as i32;
+        loop {
+            // pcs[i] is the address of instruction i, the last entry is the end of the program
+            let mut pcs = Vec::with_capacity(wide.len() + 1);
+            let mut pc = base;
+            let mut pending = origins.iter().peekable();
+            for i in 0..=wide.len() {
+                while let Some(origin) = pending.next_if(|origin| origin.index == i) {
+                    pc = pc.max(origin.address as i32);
+                }
+                pcs.push(pc);
+                pc += 1 + wide.get(i).copied().unwrap_or(false) as i32;
+            }
+            let labels = text_labels
+                .iter()
+                .map(|(label, index)| (label.as_str(), pcs[*index]))
+                .collect::<HashMap<_, _>>();
+
+            let mut changed = false;
+            for (i, (_, instruction)) in self.program.iter().enumerate() {
+                // branches to missing labels are reported by the second pass
+                if let Instruction::BranchLabel { label, .. } = instruction {
+                    let Some(target) = labels.get(label.as_str()) else {
+                        continue;
+                    };
+                    let offset = target - (pcs[i] + 2 + next_wide(&wide, &pcs, i));
+                    if !wide[i] && Offset::short(offset).is_none() {
+                        wide[i] = true;
+                        changed = true;
+                    }
+                }
+            }
+
+            if !changed {
+                for (i, (pc, _)) in self.program.iter_mut().enumerate() {
+                    *pc = pcs[i] as u16;
+                }
+                for (label, pc) in labels {
+                    self.label_map.insert(label.to_string(), pc as u16);
+                }
+                self.check_text(&pcs, &wide, origins);
+                return wide;
+            }
+        }
+    }
+
+    // every .org has to be ahead of the code before it, and the code has to stay in its region
+    fn check_text(&mut self, pcs: &[i32], wide: &[bool], origins: &[Origin]) {
+        let end = |i: usize| pcs[i] + 1 + wide[i] as i32;
+        for origin in origins {
+            let end = match origin.index {
+                0 => self.region(Section::Text).base as i32,
+                i => end(i - 1),
+            };
+            if end > origin.address as i32 {
+                let message = format!(".org 0x{:04x} is behind the end of the code before it at 0x{:04x}", origin.address, end);
+                self.diagnostics.push(Diagnostic::error(Error::new(Code::Section, message), &origin.source));
+            }
+        }
+
+        let region = self.region(Section::Text);
+        let limit = region.base as i32 + region.size as i32;
+        if let Some(i) = (0..wide.len()).find(|&i| end(i) > limit) {
+            let message = format!("Code does not fit in the .text region, which ends at 0x{:04x}", limit);
+            self.diagnostics.push(Diagnostic::error(Error::new(Code::Section, message), &self.sources[i]));
+        }
+    }
+
+    // the ALU only uses the low 4 bits of the shift amount, so `shl t0, !16` leaves t0 unchanged
+    fn check_shifts(&mut self) {
+        for ((_, instruction), source) in self.program.iter().zip(&self.sources) {
+            let amount = match instruction {
+                Instruction::Isa(Isa::Dp { op: AluOp::Shl | AluOp::Shr, src2, .. }) => src2.imm(),
+                _ => None,
+            };
+            let Some(amount) = amount.map(|imm| imm as i16 as i32) else {
+                continue;
+            };
+            if !(0..16).contains(&amount) {
+                let message = format!("Shift amount {} is out of range, the hardware shifts by {}", amount, amount & 15);
+                self.diagnostics.push(Diagnostic::warning(Error::new(Code::OutOfRange, message), source));
+            }
+        }
+    }
+
+    // warnings about unreachable code, routines that do not return and stacks that do not balance, see
+    // analysis.rs. the program has to be complete, so nothing is checked when there were errors
+    fn check_control_flow(&mut self) {
+        if self.program.is_empty() || self.diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+            return;
+        }
+        let externs = self
+            .relocations
+            .iter()
+            .filter(|relocation| relocation.kind == RelocationKind::Branch16 && matches!(relocation.target, Target::Extern(_)))
+            .map(|relocation| relocation.offset as u16 - 1)
+            .collect::<HashSet<_>>();
+        let cfg = Cfg::build(&self.program, &externs);
+        let index = self.program.iter().enumerate().map(|(i, (address, _))| (*address, i)).collect::<HashMap<_, _>>();
+        let at = |label: &str| self.label_map.get(label).and_then(|address| index.get(address)).copied();
+
+        // a label without dots names a routine better than local, macro and generated ones
+        let mut names = HashMap::new();
+        let mut labels = self.label_map.keys().collect::<Vec<_>>();
+        labels.sort_by_key(|label| (label.contains(['.', '@']), label.len(), label.as_str()));
+        for label in labels.into_iter().rev() {
+            if let Some(i) = at(label) {
+                names.insert(i, label.clone());
+            }
         }
+        let procedures = self.procedures.iter().filter_map(|procedure| index.get(&procedure.start).copied());
+        let globals = self.globals.keys().filter_map(|name| at(name));
+        // immediates, .words and data words holding the address of a label may be jumped to through a register
+        let addresses = self.label_map.values().collect::<HashSet<_>>();
+        let text = self.program.iter().filter_map(|(_, instruction)| match instruction {
+            Instruction::Isa(Isa::Dp { src2: Src2::WideImm16(value), .. } | Isa::Mem { src2: Src2::WideImm16(value), .. }) => Some(*value),
+            Instruction::Word(value) => Some(*value),
+            _ => None,
+        });
+        let data = self.data.chunks_exact(2).map(|word| u16::from_le_bytes([word[0], word[1]]));
+        let relocated = self
+            .relocations
+            .iter()
+            .filter(|relocation| relocation.section == Section::Data && relocation.target == Target::Text)
+            .map(|relocation| relocation.addend as u16);
+        let addressed = text
+            .chain(data)
+            .chain(relocated)
+            .filter(|value| addresses.contains(value))
+            .filter_map(|value| index.get(&value).copied())
+            .collect();
+
+        let roots = Roots {
+            entry: at("_start").or(Some(0)),
+            routines: procedures.chain(globals).collect(),
+            addressed,
+            names,
+        };
+        let warnings = cfg.check(&self.sources, &roots);
+        self.diagnostics.extend(warnings);
     }
 
-    pub fn parse_program(&mut self, input: &str, filename: &str) -> Result<(), String> {
-        self.program.clear();
-        self.label_map.clear();
+    // the text labels of the program, without the ones .if and .while blocks branch to: nobody can
+    // write those, so they are no symbols
+    pub fn text_symbols(&self) -> impl Iterator<Item = (&String, &u16)> {
+        self.label_map.iter().filter(|(name, _)| !is_block_label(name))
+    }
 
-        let mut pc: u16 = 0;
-        let mut line_number = 0;
+    pub fn get_program(&self) -> Vec<Instruction> {
+        self.program.clone().into_iter().map(|(_, instr)| instr).collect::<Vec<_>>()
+    }
 
-        // first pass — assuming every BranchLabel is wide
-        for line in input.lines() {
-            line_number += 1;
-            let mut line = line.trim();
+    fn is_defined(&self, name: &str) -> bool {
+        self.label_map.contains_key(name)
+            || self.data_label_map.contains_key(name)
+            || self.pending_labels.contains_key(name)
+            || self.constants.contains_key(name)
+    }
 
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with(";") || line.starts_with("//") {
-                continue;
-            }
+    // Evaluates an expression with the symbols known so far.
+    // Text labels only count once `labels` is set, since their addresses are not final during the
+    // first pass; None means the expression depends on a symbol that is not available yet.
+    pub fn evaluate(&self, expr: &Expr, labels: bool) -> Option<Result<i32, Error>> {
+        self.evaluate_at_depth(expr, labels, None, 0)
+    }
 
-            // Handle labels
-            if let Some((label, rest)) = line.split_once(':') {
-                let label = label.trim().to_string();
-                if self.label_map.contains_key(&label) {
-                    return Err(format!(
-                        "Error in {} line {}\nDuplicate label: {}",
-                        filename, line_number, label
-                    ));
-                }
-                self.label_map.insert(label, pc);
+    // Splits the final value of an expression into an offset and what it is relative to.
+    // In a relocatable object the expression is evaluated again with each section or extern moved:
+    // the value has to move along with exactly one of them, or with none when it is absolute.
+    pub fn relocate(&self, expr: &Expr) -> Result<(i32, Option<Target>), Error> {
+        let value = self.evaluate(expr, true).unwrap()?;
+        if !self.relocatable {
+            return Ok((value, None));
+        }
 
-                // Parse the instruction after the label
-                line = rest.trim();
-                if line.is_empty() || line.starts_with(";") || line.starts_with("//") {
-                    continue;
+        let mut candidates = vec![Target::Text, Target::Data];
+        candidates.extend(self.externs.keys().map(|name| Target::Extern(name.clone())));
+        let mut relative_to = None;
+        for candidate in candidates {
+            let moved = self.evaluate_at_depth(expr, true, Some(&candidate), 0).unwrap()?;
+            match moved - value {
+                0 => (),
+                RELOCATION_PROBE if relative_to.is_none() => relative_to = Some(candidate),
+                _ => {
+                    let message = "Expression cannot be relocated, it has to be an address plus or minus a constant";
+                    return Err(Error::new(Code::InvalidExpression, message));
                 }
             }
+        }
+        Ok((value, relative_to))
+    }
 
-            match parse_instruction(line) {
-                Ok(instruction) => {
-                    let pc_step = if instruction.is_wide() { 2 } else { 1 };
-                    self.program.push((pc, instruction));
-                    pc += pc_step;
+    fn evaluate_at_depth(&self, expr: &Expr, labels: bool, moved: Option<&Target>, depth: usize) -> Option<Result<i32, Error>> {
+        let unknown = Cell::new(false);
+        let result = expr.eval(&|name| {
+            if let Some(expr) = self.constants.get(name) {
+                if depth == MAX_CONSTANT_DEPTH {
+                    return Err(Error::new(Code::InvalidExpression, format!("Recursive definition of constant {}", name)));
                 }
-                Err(err) => {
-                    return Err(format!(
-                        "Error in {} line {}\n{}",
-                        filename, line_number, err
-                    ));
+                return match self.evaluate_at_depth(expr, labels, moved, depth + 1) {
+                    Some(result) => result,
+                    None => {
+                        unknown.set(true);
+                        Ok(0)
+                    }
+                };
+            }
+            let offset = |target: Target| if moved == Some(&target) { RELOCATION_PROBE } else { 0 };
+            match (self.data_label_map.get(name), self.label_map.get(name)) {
+                // data addresses are only final in the first pass when nothing moves them later
+                (Some(&addr), _) if labels || !self.relocatable => Ok(addr as i32 + offset(Target::Data)),
+                (None, Some(&pc)) if labels => Ok(pc as i32 + offset(Target::Text)),
+                (None, None) if self.externs.contains_key(name) && !self.relocatable => Err(extern_error(name)),
+                (None, None) if labels && self.externs.contains_key(name) => Ok(offset(Target::Extern(name.to_string()))),
+                _ if labels => Err(undefined_symbol("Symbol", name)),
+                _ => {
+                    unknown.set(true);
+                    Ok(0)
                 }
             }
+        });
+
+        if unknown.get() {
+            None
+        } else {
+            Some(result)
         }
+    }
+
+    fn resolve_branch(&mut self, cond: Condition, label: &str, wide: &[bool], pcs: &[i32], i: usize) -> Result<Instruction, Error> {
+        let pc = self.program[i].0 as i32;
+        let Some(&target) = self.label_map.get(label) else {
+            // branches to an extern are always wide and filled in by the linker
+            if self.relocatable && self.externs.contains_key(label) {
+                self.relocations.push(Relocation {
+                    section: Section::Text,
+                    offset: pc as usize + 1,
+                    kind: RelocationKind::Branch16,
+                    target: Target::Extern(label.to_string()),
+                    addend: 0,
+                });
+                return Ok(Instruction::Isa(Isa::Branch { cond, offset: Offset::wide(0) }));
+            }
+            let error = match self.externs.contains_key(label) {
+                true => extern_error(label),
+                false => undefined_symbol("Label", label),
+            };
+            return Err(Error { span: find_symbol(&self.sources[i].text, label), ..error });
+        };
+
+        let target = target as i32;
+        let offset = match wide[i] {
+            true => Offset::wide(target - (pc + 3)),
+            // relax_branches made sure the offset fits
+            false => Offset::short(target - (pc + 2 + next_wide(wide, pcs, i))).unwrap(),
+        };
+        Ok(Instruction::Isa(Isa::Branch { cond, offset }))
+    }
 
-        // second pass - validating BranchLabel instructions
-        for line in input.lines() {
-            line_number += 1;
-            let mut line = line.trim();
+    // immediates that had to wait for the final symbol table always keep their wide encoding
+    fn resolve_imm(&mut self, expr: &Expr, pc: u16) -> Result<Src2, Error> {
+        let (value, target) = self.relocate(expr)?;
+        if let Some(target) = target {
+            self.relocations.push(Relocation {
+                section: Section::Text,
+                offset: pc as usize + 1,
+                kind: RelocationKind::Abs16,
+                target,
+                addend: value,
+            });
+        }
+        wide_src2(value).map_err(|err| Error::new(Code::OutOfRange, err))
+    }
 
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with(";") || line.starts_with("//") {
-                continue;
+    // every .global has to be defined here and no .extern may be
+    fn check_declarations(&mut self) {
+        let mut errors = Vec::new();
+        for (name, source) in &self.globals {
+            if !self.is_defined(name) {
+                let error = Error::new(Code::UndefinedSymbol, format!("Global symbol {} is not defined", name));
+                errors.push(Diagnostic::error(Error { span: find_symbol(&source.text, name), ..error }, source));
             }
+        }
+        for (name, source) in &self.externs {
+            if self.is_defined(name) {
+                let error = Error::new(Code::DuplicateSymbol, format!("Extern symbol {} is also defined here", name));
+                errors.push(Diagnostic::error(Error { span: find_symbol(&source.text, name), ..error }, source));
+            }
+        }
+        // hash map order is random
+        errors.sort_by_key(|diagnostic| (diagnostic.location.file.clone(), diagnostic.location.line));
+        self.diagnostics.extend(errors);
+    }
+
+    fn parse_macro(&self, args: &[Token], line: &str, location: &Location) -> Result<Macro, Error> {
+        let mac = Macro::new(args, line, location).map_err(|err| Error::new(Code::Macro, err))?;
+        if is_mnemonic(&mac.name.to_lowercase()) {
+            let message = format!("Macro name {} conflicts with an instruction", mac.name);
+            return Err(Error::new(Code::Macro, message).at(&args[..1]));
+        }
+        if self.macros.contains_key(&mac.name.to_lowercase()) {
+            return Err(Error::new(Code::DuplicateSymbol, format!("Duplicate macro: {}", mac.name)).at(&args[..1]));
+        }
+        Ok(mac)
+    }
 
-            // skip labels
-            if let Some((_, rest)) = line.split_once(':') {
-                // Parse the instruction after the label
-                line = rest.trim();
-                if line.is_empty() || line.starts_with(";") || line.starts_with("//") {
-                    continue;
+    fn include_file(&self, args: &[Token], location: &Location) -> Result<VecDeque<SourceLine>, Error> {
+        let path = self.find_file(&parse_path(args)?, location).map_err(|error| error.at(args))?;
+
+        // every file still being included up the chain, including the current one
+        let mut active = vec![location.file.clone()];
+        let mut included_from = &location.included_from;
+        while let Some(include) = included_from {
+            active.push(include.file.clone());
+            included_from = &include.included_from;
+        }
+        if active.iter().any(|file| same_file(Path::new(&**file), &path)) {
+            return Err(Error::new(Code::File, format!("Circular include of {}", path.display())).at(args));
+        }
+
+        let input = fs::read_to_string(&path).map_err(|err| file_error(&path, err).at(args))?;
+        Ok(source_lines(&input, &path.to_string_lossy(), Some(location)))
+    }
+
+    // looks next to the file containing the directive first, then in every include path
+    fn find_file(&self, name: &str, location: &Location) -> Result<PathBuf, Error> {
+        let current_dir = Path::new(&*location.file).parent().unwrap_or(Path::new(""));
+        std::iter::once(current_dir)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| Error::new(Code::File, format!("File {} not found", name)))
+    }
+
+    fn parse_directive(
+        &mut self,
+        directive: &str,
+        args: &[Token],
+        section: &mut Section,
+        source: &SourceLine,
+    ) -> Result<(), Error> {
+        match directive {
+            // .equ NAME value / .equ NAME, value
+            ".equ" => {
+                let [name, value @ ..] = args else {
+                    return Err(Error::new(Code::InvalidOperands, "Invalid operands for .equ"));
+                };
+                let value = match value {
+                    [comma, value @ ..] if comma.is_punct(",") => value,
+                    _ => value,
+                };
+                let name = name.ident().filter(|name| is_identifier(name)).ok_or_else(|| {
+                    Error::new(Code::InvalidOperands, format!("Invalid constant name {}", name.text)).at(&args[..1])
+                })?;
+                if self.is_defined(name) {
+                    return Err(Error::new(Code::DuplicateSymbol, format!("Duplicate symbol: {}", name)).at(&args[..1]));
                 }
+                let value = Expr::parse_tokens(value).map_err(|error| error.at(value))?;
+                self.constants.insert(name.to_string(), value);
+                return Ok(());
             }
+            ".text" | ".data" | ".rodata" | ".bss" => {
+                expect_no_operands(directive, args)?;
+                *section = Section::from_name(directive).unwrap();
+                return Ok(());
+            }
+            ".section" => {
+                let name = match args {
+                    [name] => Section::from_name(&name.text.to_lowercase()),
+                    _ => return Err(Error::new(Code::InvalidOperands, "Expected a section name after .section").at(args)),
+                };
+                let message = format!("Unknown section {}, expected .text, .data, .rodata or .bss", args[0].text);
+                *section = name.ok_or_else(|| Error::new(Code::Section, message).at(args))?;
+                return Ok(());
+            }
+            // .global name, ... / .extern name, ...
+            ".global" | ".extern" => {
+                let names = split_operands(args);
+                if names.is_empty() {
+                    return Err(Error::new(Code::InvalidOperands, format!("Invalid operands for {}", directive)));
+                }
+                for name in names {
+                    let symbol = match name {
+                        [token] => token.ident().filter(|name| is_identifier(name)),
+                        _ => None,
+                    };
+                    let Some(symbol) = symbol else {
+                        let message = format!("Invalid symbol name {}", token_text(name));
+                        return Err(Error::new(Code::InvalidOperands, message).at(name));
+                    };
+                    let declarations = if directive == ".global" { &mut self.globals } else { &mut self.externs };
+                    declarations.insert(symbol.to_string(), source.clone());
+                }
+                return Ok(());
+            }
+            ".word" | ".byte" | ".ascii" | ".asciz" | ".space" | ".align" | ".incbin" => (),
+            _ => return Err(Error::new(Code::UnknownDirective, format!("Invalid directive {}", directive))),
+        }
 
-            match parse_instruction(line) {
-                Ok(instruction) => {
-                    if let Instruction::BranchLabel { label, .. } = instruction {
-                        match self.label_map.get(&label) {
-                            Some(_) => (),
-                            None => {
-                                return Err(format!(
-                                    "Error in {} line {}\nLabel {} not found",
-                                    filename, line_number, &label
-                                ))
-                            }
-                        }
+        let section = *section;
+        if section == Section::Text {
+            let message = format!("Data directive {} outside of a data section (.data, .rodata or .bss)", directive);
+            return Err(Error::new(Code::Section, message));
+        }
+        if section == Section::Bss && !matches!(directive, ".space" | ".align") {
+            let message = format!("Data directive {} in .bss, which only reserves zeroed space with .space and .align", directive);
+            return Err(Error::new(Code::Section, message));
+        }
+
+        match directive {
+            ".word" => {
+                let len = self.section_len(section);
+                if !len.is_multiple_of(2) {
+                    let message = format!("Misaligned .word at offset 0x{:04x} of {} (use .align 2)", len, section.name());
+                    return Err(Error::new(Code::Section, message));
+                }
+                self.align_section(section, 2);
+                self.parse_data_values(args, 2, section, source)?;
+            }
+            ".byte" => {
+                self.parse_data_values(args, 1, section, source)?;
+            }
+            ".ascii" | ".asciz" => {
+                let mut bytes = match args {
+                    [Token { kind: TokenKind::Str(bytes), .. }] => bytes.clone(),
+                    _ => {
+                        let message = format!("Expected a string literal after {}, found '{}'", directive, token_text(args));
+                        return Err(Error::new(Code::InvalidOperands, message).at(args));
                     }
+                };
+                if directive == ".asciz" {
+                    bytes.push(0);
                 }
-                Err(err) => {
-                    return Err(format!(
-                        "Error in {} line {}\n{}",
-                        filename, line_number, err
-                    ));
+                self.buffer(section).append(&mut bytes);
+            }
+            ".space" => {
+                let values = split_operands(args);
+                let (size, fill) = match values[..] {
+                    [size] => (self.parse_constant(size, 0, 0xffff)?, 0),
+                    [size, fill] => (self.parse_constant(size, 0, 0xffff)?, self.parse_constant(fill, -0x80, 0xff)?),
+                    _ => return Err(Error::new(Code::InvalidOperands, "Invalid operands for .space")),
+                };
+                if section == Section::Bss && fill != 0 {
+                    return Err(Error::new(Code::Section, ".bss is always zeroed, .space cannot fill it with another value").at(values[1]));
                 }
+                let data = self.buffer(section);
+                data.resize(data.len() + size as usize, fill as u8);
             }
-        }
+            ".align" => {
+                let values = split_operands(args);
+                let align = match values[..] {
+                    [align] => self.parse_constant(align, 1, 0x8000)? as usize,
+                    _ => return Err(Error::new(Code::InvalidOperands, "Invalid operands for .align")),
+                };
+                if !align.is_power_of_two() {
+                    let message = format!("Alignment must be a power of two, found {}", align);
+                    return Err(Error::new(Code::OutOfRange, message).at(args));
+                }
+                let data = self.buffer(section);
+                data.resize(data.len().next_multiple_of(align), 0);
+                self.align_section(section, align);
+            }
+            // .incbin "file" {, offset {, length}}
+            ".incbin" => {
+                let values = split_operands(args);
+                let file = values.first().copied().unwrap_or_default();
+                let path = self.find_file(&parse_path(file)?, &source.location).map_err(|error| error.at(file))?;
+                let bytes = fs::read(&path).map_err(|err| file_error(&path, err).at(file))?;
 
-        // third pass - convert BranchLabel to BranchOffset
-        for (pc, instruction) in self.program.iter_mut() {
-            if let Instruction::BranchLabel { cond, label } = instruction {
-                if let Some(&label_pc) = self.label_map.get(label) {
-                    let w = 1; // assuming every BranchLabel is wide
-                    let offset = (label_pc as i16) - (*pc as i16 + 2 + w);
-                    *instruction = Instruction::BranchOffset {
-                        cond: *cond,
-                        offset: Offset::WideImm16(offset),
+                let (offset, length) = match values[1..] {
+                    [] => (0, bytes.len()),
+                    [offset] => {
+                        let offset = self.parse_constant(offset, 0, i32::MAX)? as usize;
+                        (offset, bytes.len().saturating_sub(offset))
                     }
-                } else {
-                    return Err(format!("\nLabel '{}' not found", &label));
+                    [offset, length] => (
+                        self.parse_constant(offset, 0, i32::MAX)? as usize,
+                        self.parse_constant(length, 0, i32::MAX)? as usize,
+                    ),
+                    _ => return Err(Error::new(Code::InvalidOperands, "Invalid operands for .incbin")),
+                };
+                if offset + length > bytes.len() {
+                    let message = format!("{} has only {} bytes", path.display(), bytes.len());
+                    return Err(Error::new(Code::OutOfRange, message).at(&args[1..]));
+                }
+                self.buffer(section).extend_from_slice(&bytes[offset..offset + length]);
+            }
+            _ => unreachable!(),
+        }
+        self.check_region(section)
+    }
+
+    fn parse_data_values(&mut self, args: &[Token], size: usize, section: Section, source: &SourceLine) -> Result<(), Error> {
+        let values = split_operands(args);
+        if values.is_empty() || values.iter().any(|value| value.is_empty()) {
+            let message = format!("Invalid operands for {}", if size == 2 { ".word" } else { ".byte" });
+            return Err(Error::new(Code::InvalidOperands, message));
+        }
+
+        for value in values {
+            let expr = Expr::parse_tokens(value).map_err(|error| error.at(value))?;
+            match self.evaluate(&expr, false) {
+                Some(result) => {
+                    let result = result.and_then(|result| check_data_value(result, size));
+                    let result = result.map_err(|error| error.at(value))?;
+                    self.buffer(section).extend_from_slice(&result.to_le_bytes()[..size]);
+                }
+                None => {
+                    let data = self.buffer(section);
+                    let offset = data.len();
+                    data.resize(offset + size, 0);
+                    self.data_fixups.push(DataFixup {
+                        section,
+                        offset,
+                        size,
+                        expr,
+                        source: source.clone(),
+                    });
                 }
             }
         }
+        Ok(())
+    }
 
+    // raw instruction words, for encodings that have no mnemonic
+    fn parse_text_words(&mut self, args: &[Token], source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        let values = split_operands(args);
+        if values.iter().any(|value| value.is_empty()) {
+            return Err(Error::new(Code::InvalidOperands, "Invalid operands for .word"));
+        }
+        for value in values {
+            let word = self.parse_constant(value, i16::MIN as i32, u16::MAX as i32)?;
+            self.program.push((pass.pc, Instruction::Word(word as u16)));
+            self.sources.push(source.clone());
+            pass.pc += 1;
+        }
         Ok(())
     }
 
-    pub fn get_program(&self) -> Vec<Instruction> {
-        self.program.clone().into_iter().map(|(_, instr)| instr).collect::<Vec<_>>()
+    // .org address continues the current section at an absolute address, the gap is filled with zeros
+    fn parse_org(&mut self, args: &[Token], source: &SourceLine, pass: &mut FirstPass) -> Result<(), Error> {
+        if self.relocatable {
+            return Err(Error::new(Code::Section, ".org cannot be used in an object file, the linker places its sections"));
+        }
+        let address = self.parse_constant(args, 0, 0xffff)? as usize;
+        let section = pass.section;
+        let Some(base) = self.fixed_base(section) else {
+            let message = format!(".org needs a base address for {} in the layout", section.name());
+            return Err(Error::new(Code::Section, message));
+        };
+        let (base, end) = (base as usize, base as usize + self.region(section).size);
+        if address < base || address > end {
+            let message = format!("Address 0x{:04x} is outside of the {} region 0x{:04x}-0x{:04x}", address, section.name(), base, end.max(base + 1) - 1);
+            return Err(Error::new(Code::OutOfRange, message).at(args));
+        }
+
+        // code can still grow when its branches are relaxed, check_text compares the addresses
+        if section == Section::Text {
+            pass.origins.push(Origin {
+                index: self.program.len(),
+                address: address as u16,
+                source: source.clone(),
+            });
+            pass.pc = address as u16;
+            return Ok(());
+        }
+        let data = self.buffer(section);
+        if address < base + data.len() {
+            let message = format!(".org 0x{:04x} is behind the end of the data before it at 0x{:04x}", address, base + data.len());
+            return Err(Error::new(Code::Section, message).at(args));
+        }
+        data.resize(address - base, 0);
+        Ok(())
+    }
+
+    // where a section may go: its region in the layout, or all of memory for object files and sections left out of it
+    fn region(&self, section: Section) -> Region {
+        match self.layout.region(section) {
+            Some(region) if !self.relocatable => region,
+            _ => Region { base: 0, size: MEMORY_SIZE },
+        }
+    }
+
+    // where a section starts when that is known before the sections are placed
+    fn fixed_base(&self, section: Section) -> Option<u16> {
+        match self.layout.region(section) {
+            Some(region) if !self.relocatable => Some(region.base),
+            _ => matches!(section, Section::Text | Section::Data).then_some(0),
+        }
+    }
+
+    fn buffer(&mut self, section: Section) -> &mut Vec<u8> {
+        self.section_data.entry(section).or_default()
+    }
+
+    fn section_len(&self, section: Section) -> usize {
+        self.section_data.get(&section).map_or(0, Vec::len)
+    }
+
+    fn align_section(&mut self, section: Section, align: usize) {
+        let current = self.section_align.entry(section).or_insert(1);
+        *current = (*current).max(align);
+    }
+
+    fn check_region(&self, section: Section) -> Result<(), Error> {
+        let (len, size) = (self.section_len(section), self.region(section).size);
+        if len > size {
+            let message = format!("{} is {} bytes, more than the {} bytes of its region", section.name(), len, size);
+            return Err(Error::new(Code::Section, message));
+        }
+        Ok(())
+    }
+
+    // the address of a placed section
+    fn address_of(&self, section: Section) -> usize {
+        self.placements.iter().find(|placement| placement.section == section).map_or(0, |placement| placement.address as usize)
+    }
+
+    // places the data memory sections where the layout puts them or one after the other, gives the labels
+    // in them their address and builds the data image
+    fn place_sections(&mut self, pass: &FirstPass) {
+        let mut placed: Vec<(Section, usize, usize)> = Vec::new();
+        let mut end = 0usize;
+        for section in DATA_SECTIONS {
+            let size = self.section_len(section);
+            let align = self.section_align.get(&section).copied().unwrap_or(1);
+            let address = self.fixed_base(section).map_or(end.next_multiple_of(align), |base| base as usize);
+            end = address + size;
+            self.data_align = self.data_align.max(align);
+
+            if size > 0 {
+                let source = &pass.section_lines[&section];
+                let mut errors = Vec::new();
+                if !address.is_multiple_of(align) {
+                    errors.push(format!("{} starts at 0x{:04x}, which is not aligned to the {} bytes it needs", section.name(), address, align));
+                }
+                if end > MEMORY_SIZE {
+                    errors.push(format!("{} ends at 0x{:05x}, beyond the end of memory", section.name(), end));
+                }
+                for (other, other_address, other_size) in &placed {
+                    if *other_size > 0 && address < other_address + other_size && *other_address < end {
+                        errors.push(format!(
+                            "{} at 0x{:04x}-0x{:04x} overlaps {} at 0x{:04x}-0x{:04x}",
+                            section.name(),
+                            address,
+                            end - 1,
+                            other.name(),
+                            other_address,
+                            other_address + other_size - 1
+                        ));
+                    }
+                }
+                for message in errors {
+                    self.diagnostics.push(Diagnostic::error(Error::new(Code::Section, message), source));
+                }
+            }
+            placed.push((section, address, size));
+        }
+
+        // .bss takes no room in an executable, data memory starts out zeroed
+        let stored = |section: Section| self.relocatable || section != Section::Bss;
+        let len = placed.iter().filter(|(section, ..)| stored(*section)).map(|(_, address, size)| address + size).max().unwrap_or(0);
+        let mut data = vec![0; len];
+        for (section, address, size) in &placed {
+            if stored(*section) && *size > 0 {
+                data[*address..address + size].copy_from_slice(&self.section_data[section]);
+            }
+        }
+        self.data = data;
+
+        for (section, address, size) in placed {
+            self.placements.push(Placement {
+                section,
+                address: address as u16,
+                size,
+            });
+        }
+        for (name, (section, offset)) in std::mem::take(&mut self.pending_labels) {
+            self.data_label_map.insert(name, (self.address_of(section) + offset) as u16);
+        }
+        for line in &mut self.listing {
+            if line.section != Section::Text {
+                let address = self.placements.iter().find(|placement| placement.section == line.section).unwrap().address as usize;
+                line.data = line.data.start + address..line.data.end + address;
+            }
+        }
+    }
+
+    // .text goes first, from its base to the end of the last instruction
+    fn place_text(&mut self) {
+        let address = self.region(Section::Text).base;
+        let end = self
+            .program
+            .last()
+            .map_or(address as usize, |(pc, instruction)| *pc as usize + 1 + instruction.is_wide() as usize);
+        let placement = Placement {
+            section: Section::Text,
+            address,
+            size: end - address as usize,
+        };
+        self.placements.insert(0, placement);
+    }
+
+    // the program memory image from address 0, the gaps in front of the text base and left by .org are zero
+    pub fn words(&self) -> Vec<u16> {
+        let mut words = Vec::new();
+        for (pc, instruction) in &self.program {
+            words.resize(*pc as usize, 0);
+            words.extend(instruction.to_binary());
+        }
+        words
+    }
+
+    // directive arguments that change the layout have to be known in the first pass
+    fn parse_constant(&self, tokens: &[Token], min: i32, max: i32) -> Result<i32, Error> {
+        let expr = Expr::parse_tokens(tokens).map_err(|error| error.at(tokens))?;
+        let value = self.evaluate(&expr, false).ok_or_else(|| {
+            let message = format!("Expected a constant expression, found {}", token_text(tokens));
+            Error::new(Code::InvalidExpression, message).at(tokens)
+        })?;
+        let value = value.map_err(|error| error.at(tokens))?;
+        if value < min || value > max {
+            let message = format!("Value {} out of range [{}, {}]", token_text(tokens), min, max);
+            return Err(Error::new(Code::OutOfRange, message).at(tokens));
+        }
+        Ok(value)
+    }
+}
+
+// how far a section is moved to see whether a value depends on it, odd so that masks and shifts notice
+const RELOCATION_PROBE: i32 = 0x10001;
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+// e.g. `bp-4..bp-3`
+fn slot_range(offset: i32, size: i32) -> String {
+    let relative = |offset: i32| match offset {
+        0 => "bp".to_string(),
+        offset => format!("bp{:+}", offset),
+    };
+    format!("{}..{}", relative(offset), relative(offset + size - 1))
+}
+
+fn extern_error(name: &str) -> Error {
+    let message = format!("Symbol {} is declared .extern, assemble with -c and link the object files", name);
+    Error::new(Code::UndefinedSymbol, message)
+}
+
+fn check_data_value(value: i32, size: usize) -> Result<u16, Error> {
+    let (min, max) = if size == 2 { (-0x8000, 0xffff) } else { (-0x80, 0xff) };
+    if value < min || value > max {
+        return Err(Error::new(Code::OutOfRange, format!("Value {} out of range [{}, {}]", value, min, max)));
     }
+    Ok(value as u16)
 }
 
+// a short instruction followed by a wide one sees pc one word further ahead,
+// unless .org leaves a gap between them
+fn next_wide(wide: &[bool], pcs: &[i32], i: usize) -> i32 {
+    let adjacent = pcs.get(i + 1) == Some(&(pcs[i] + 1 + wide[i] as i32));
+    (adjacent && wide.get(i + 1) == Some(&true)) as i32
+}
+
+// where a symbol is used in a line, for errors found after the line was parsed
+fn find_symbol(text: &str, name: &str) -> Option<Range<usize>> {
+    let tokens = tokenize(text).ok()?;
+    tokens.into_iter().find(|token| token.ident() == Some(name)).map(|token| token.span)
+}
 
-fn parse_instruction(line: &str) -> Result<Instruction, String> {
-    let line = line.replace(",", " ");
-    let line = line.replace("[", " [ ");
-    let line = line.replace("]", " ] ");
-    let line = line.replace("+", " + ");
-    let line = line.to_lowercase();
+fn parse_path(tokens: &[Token]) -> Result<String, Error> {
+    match tokens {
+        [Token { kind: TokenKind::Str(bytes), .. }] => String::from_utf8(bytes.clone())
+            .map_err(|_| Error::new(Code::File, format!("Invalid file name {}", tokens[0].text)).at(tokens)),
+        _ => {
+            let message = format!("Expected a file name in quotes, found '{}'", token_text(tokens));
+            Err(Error::new(Code::InvalidOperands, message).at(tokens))
+        }
+    }
+}
 
-    let normalized_instruction = line.split_whitespace().collect::<Vec<_>>();
+fn file_error(path: &Path, err: std::io::Error) -> Error {
+    Error::new(Code::File, format!("Cannot read {}: {}", path.display(), err))
+}
 
-    let opcode = normalized_instruction[0];
-    let operands = &normalized_instruction[1..];
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
 
-    match opcode {
-        "add" | "sub" | "and" | "or" | "xor" | "mov" | "shl" | "shr" => parse_dp(opcode, operands),
-        "lod" | "lodb" | "sav" | "savb" => parse_mem(opcode, operands),
-        "push" | "pushb" => parse_push(operands, opcode == "pushb"),
+// a constant given as NAME or NAME=value, like -D on the command line; NAME alone is 1
+pub fn parse_define(define: &str) -> Result<(String, Expr), String> {
+    let (name, value) = define.split_once('=').unwrap_or((define, "1"));
+    if !is_identifier(name) {
+        return Err(format!("Invalid constant name {}", name));
+    }
+    let tokens = tokenize(value).map_err(|error| error.message)?;
+    let value = Expr::parse_tokens(&tokens).map_err(|error| format!("Invalid value for {}: {}", name, error.message))?;
+    Ok((name.to_string(), value))
+}
+
+// the tokens of a line without its trailing comment
+fn tokenize_line(line: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = tokenize(line)?;
+    if tokens.last().is_some_and(|token| matches!(token.kind, TokenKind::Comment(_))) {
+        tokens.pop();
+    }
+    Ok(tokens)
+}
+
+fn expect_no_operands(directive: &str, args: &[Token]) -> Result<(), Error> {
+    match args.first() {
+        Some(token) => Err(Error::new(Code::Syntax, format!("Unexpected token '{}' after {}", token.text, directive)).at(args)),
+        None => Ok(()),
+    }
+}
+
+fn directive_name(line: &str) -> String {
+    line.split_whitespace().next().unwrap_or("").to_lowercase()
+}
+
+// an undefined symbol of the program, forward references to numeric labels are the only ones with a digit
+fn undefined_symbol(kind: &str, name: &str) -> Error {
+    let message = match name.split_once('.').filter(|(number, _)| number.chars().all(|c| c.is_ascii_digit())) {
+        Some((number, _)) => format!("Numeric label {} is not defined below {}f", number, number),
+        None => format!("{} {} not found", kind, name),
+    };
+    Error::new(Code::UndefinedSymbol, message)
+}
+
+// splits `label: rest` only when the text before ':' is a valid label name, local and numeric labels included
+pub fn split_label(line: &str) -> (Option<&str>, &str) {
+    match line.split_once(':') {
+        Some((label, rest)) if is_label(label.trim()) => (Some(label.trim()), rest),
+        _ => (None, line),
+    }
+}
+
+fn is_label(name: &str) -> bool {
+    let local = name.strip_prefix('.').is_some_and(is_identifier);
+    let numeric = !name.is_empty() && name.chars().all(|c| c.is_ascii_digit());
+    is_identifier(name) || local || numeric
+}
+
+pub fn is_identifier(token: &str) -> bool {
+    let mut chars = token.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+        _ => false,
+    }
+}
+
+// folds an immediate in the first pass, None when it depends on symbols that are not known yet
+type Fold<'a> = &'a dyn Fn(&Expr) -> Option<Result<i32, Error>>;
+
+type Operand<'a> = &'a [Token];
+
+// most mnemonics are a single instruction, pseudo-instructions expand to several
+fn parse_instruction(opcode: &str, operands: &[Operand], fold: Fold) -> Result<Vec<Instruction>, Error> {
+    let opcode = opcode.to_lowercase();
+    let opcode = opcode.as_str();
+
+    // a `.w` suffix forces the wide encoding, e.g. of an immediate that would fit in 3 bits
+    if let Some(opcode) = opcode.strip_suffix(".w") {
+        if is_pseudo(opcode, operands) {
+            return Err(Error::new(Code::InvalidOperands, format!("Pseudo-instruction {} has no wide form", opcode)));
+        }
+        let instructions = parse_instruction(opcode, operands, fold)?;
+        return instructions.into_iter().map(|instruction| widen(instruction, opcode)).collect();
+    }
+
+    let instruction = match opcode {
+        "add" | "sub" | "and" | "or" | "xor" | "mov" | "shl" | "shr" => parse_dp(opcode, operands, fold),
+        "lod" | "lodb" | "sav" | "savb" => parse_mem(opcode, operands, fold),
+        "push" | "pushb" => parse_push(operands, opcode == "pushb", fold),
         "pop" | "popb" => parse_pop(operands, opcode == "popb"),
-        "inc" | "dec" | "not" | "cmp" | "tst" | "ret" | "nop" => parse_alias(opcode, operands),
-        _ => match opcode.chars().collect::<Vec<_>>()[0] {
-            'j' => parse_branch(opcode, operands),
-            _ => Err(format!("Invalid opcode {}", opcode)),
+        "inc" | "dec" | "not" | "cmp" | "tst" | "ret" | "nop" => parse_alias(opcode, operands, fold),
+        _ if is_pseudo(opcode, operands) => return parse_pseudo(opcode, operands, fold),
+        _ => match opcode.chars().next() {
+            Some('j') => parse_branch(opcode, operands),
+            _ => Err(Error::new(Code::UnknownInstruction, format!("Invalid opcode {}", opcode))),
+        },
+    };
+    Ok(vec![instruction?])
+}
+
+fn widen(instruction: Instruction, opcode: &str) -> Result<Instruction, Error> {
+    match instruction {
+        // nop, which never branches
+        Instruction::Isa(Isa::Branch { cond, offset: Offset::SignImm9(offset) }) => {
+            Ok(Instruction::Isa(Isa::Branch { cond, offset: Offset::wide(offset as i32) }))
+        }
+        Instruction::Isa(instruction) => match instruction.src2() {
+            Some(Src2::Reg(_)) => Err(Error::new(Code::InvalidOperands, format!("{} has no immediate to widen", opcode))),
+            Some(src2) => Ok(Instruction::Isa(instruction.with_src2(Src2::WideImm16(src2.imm().unwrap())))),
+            None => Ok(Instruction::Isa(instruction)),
         },
+        Instruction::BranchLabel { cond, label, .. } => Ok(Instruction::BranchLabel { cond, label, wide: true }),
+        instruction => Ok(instruction),
+    }
+}
+
+fn is_mnemonic(opcode: &str) -> bool {
+    matches!(
+        opcode,
+        "lodb" | "savb" | "pushb" | "popb" | "inc" | "dec" | "not" | "cmp" | "tst" | "ret" | "nop"
+    ) || AluOp::from_mnemonic(opcode).is_some()
+        || MemOp::MNEMONICS.contains(&opcode)
+        || is_pseudo(opcode, &[])
+        || (opcode.starts_with('j') && parse_cond(opcode).is_ok())
+}
+
+// `jmp reg` is the only pseudo-instruction that shares its mnemonic with a real one
+fn is_pseudo(opcode: &str, operands: &[Operand]) -> bool {
+    let register = matches!(operands, [[Token { kind: TokenKind::Register(_), .. }]]);
+    matches!(opcode, "call" | "jsr" | "bsr" | "la" | "neg" | "swap" | "clr") || (opcode == "jmp" && register)
+}
+
+// splits operands on the commas that are not nested inside parentheses or brackets
+pub fn split_operands(tokens: &[Token]) -> Vec<&[Token]> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Punct("(" | "[") => depth += 1,
+            TokenKind::Punct(")" | "]") => depth -= 1,
+            TokenKind::Punct(",") if depth == 0 => {
+                result.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
     }
+    result.push(&tokens[start..]);
+    result
 }
 
-fn parse_dp(opcode: &str, operands: &[&str]) -> Result<Instruction, String> {
-    let cmd = parse_cmd(opcode).unwrap();
-    if operands.len() == 3 && cmd != 0b101 {
+fn parse_dp(opcode: &str, operands: &[Operand], fold: Fold) -> Result<Instruction, Error> {
+    let op = parse_cmd(opcode).unwrap();
+    if operands.len() == 3 && op != AluOp::Mov {
         let td = parse_register(operands[0])?;
         let tn = parse_register(operands[1])?;
-        let src2 = parse_register_or_imm(operands[2])?;
-        Ok(Instruction::Dp { cmd, td, tn, src2 })
+        let src2 = parse_register_or_imm(operands[2], fold)?;
+        Ok(src2.instruction(|src2| Isa::Dp { op, td, tn, src2 }))
     }
     // mov
-    else if operands.len() == 2 && cmd == 0b101 {
+    else if operands.len() == 2 && op == AluOp::Mov {
         let td = parse_register(operands[0])?;
-        let src2 = parse_register_or_imm(operands[1])?;
-        Ok(Instruction::Dp {
-            cmd,
+        let src2 = parse_register_or_imm(operands[1], fold)?;
+        Ok(src2.instruction(|src2| Isa::Dp {
+            op,
             td,
-            tn: 0b000,
+            tn: Register::T0,
             src2,
-        })
+        }))
     }
     // Td = Tn
     else if operands.len() == 2 {
         let td = parse_register(operands[0])?;
         let tn = td;
-        let src2 = parse_register_or_imm(operands[1])?;
-        Ok(Instruction::Dp { cmd, td, tn, src2 })
+        let src2 = parse_register_or_imm(operands[1], fold)?;
+        Ok(src2.instruction(|src2| Isa::Dp { op, td, tn, src2 }))
     } else {
-        Err(format!("Invalid operands for {}", opcode))
+        Err(invalid_operands(opcode))
     }
 }
 
-fn parse_mem(opcode: &str, operands: &[&str]) -> Result<Instruction, String> {
-    let bsl = match opcode {
-        "sav" => 0b000,
-        "savb" => 0b100,
-        "lod" => 0b001,
-        "lodb" => 0b101,
+fn parse_mem(opcode: &str, operands: &[Operand], fold: Fold) -> Result<Instruction, Error> {
+    let (op, byte) = match opcode {
+        "sav" => (MemOp::Sav, false),
+        "savb" => (MemOp::Sav, true),
+        "lod" => (MemOp::Lod, false),
+        "lodb" => (MemOp::Lod, true),
         _ => panic!("Parsing non-existant MEM opcode {}", opcode),
     };
 
-    // sav td [ tn ]
-    if operands.len() == 4 {
+    // sav td, [tn] / sav td, [tn + src2]
+    if operands.len() == 2 {
         let td = parse_register(operands[0])?;
-        match_token(operands[1], "[")?;
-        let tn = parse_register(operands[2])?;
-        match_token(operands[3], "]")?;
-
-        Ok(Instruction::Mem {
-            bsl,
-            td,
-            tn,
-            src2: Src2::ZeroImm3(0),
-        })
+        let (tn, src2) = parse_address(operands[1], fold)?;
+        Ok(src2.instruction(|src2| Isa::Mem { op, byte, td, tn, src2 }))
+    } else {
+        Err(invalid_operands(opcode))
     }
-    // sav td [ tn + src2 ]
-    else if operands.len() == 6 {
-        let td = parse_register(operands[0])?;
-        match_token(operands[1], "[")?;
-        let tn = parse_register(operands[2])?;
-        match_token(operands[3], "+")?;
-        let src2 = parse_register_or_imm(operands[4])?;
-        match_token(operands[5], "]")?;
+}
 
-        Ok(Instruction::Mem { bsl, td, tn, src2 })
-    } else {
-        Err(format!("Invalid operands for {}", opcode))
+fn parse_address(operand: Operand, fold: Fold) -> Result<(Register, Src2Operand), Error> {
+    let inner = match operand {
+        [open, inner @ .., close] if open.is_punct("[") && close.is_punct("]") => inner,
+        _ => {
+            let message = format!("Expected '[tn + src2]', found '{}'", token_text(operand));
+            return Err(Error::new(Code::InvalidOperands, message).at(operand));
+        }
+    };
+
+    let tn = parse_register(inner.get(..1).unwrap_or_default())?;
+    match &inner[1..] {
+        [] => Ok((tn, Src2Operand::Known(Src2::ZeroImm3(0)))),
+        [plus, src2 @ ..] if plus.is_punct("+") => Ok((tn, parse_register_or_imm(src2, fold)?)),
+        // [tn - !imm]
+        [minus, bang, imm @ ..] if minus.is_punct("-") && bang.is_punct("!") => {
+            let expr = Expr::parse_tokens(imm).map_err(|error| error.at(imm))?;
+            let expr = Expr::Unary(UnaryOp::Neg, Box::new(expr));
+            Ok((tn, parse_imm(expr, fold).map_err(|error| error.at(imm))?))
+        }
+        [minus, rest @ ..] if minus.is_punct("-") => {
+            let message = format!("Expected an immediate after '-', found '{}'", token_text(rest));
+            Err(Error::new(Code::InvalidOperands, message).at(rest))
+        }
+        rest => {
+            let message = format!("Expected '+' or ']', found '{}'", token_text(rest));
+            Err(Error::new(Code::InvalidOperands, message).at(rest))
+        }
     }
 }
 
-fn parse_push(operands: &[&str], byte: bool) -> Result<Instruction, String> {
+fn parse_push(operands: &[Operand], byte: bool, fold: Fold) -> Result<Instruction, Error> {
     if operands.len() == 1 {
-        let bsl = if byte { 0b110 } else { 0b010 };
-        let write_data = parse_register_or_imm(operands[0])?;
+        let op = MemOp::Push;
+        let write_data = parse_register_or_imm(operands[0], fold)?;
 
         match write_data {
-            Src2::Reg(r) => Ok(Instruction::Mem {
-                bsl,
+            Src2Operand::Known(Src2::Reg(r)) => Ok(Instruction::Isa(Isa::Mem {
+                op,
+                byte,
                 td: r,
-                tn: 0b101,
-                src2: Src2::Reg(0b000), // don't care when pushing a reg
-            }),
-            _ => Ok(Instruction::Mem {
-                bsl,
-                td: 0b000, // don't care when pushing an immediate
-                tn: 0b101,
-                src2: write_data,
-            }),
+                tn: Register::Sp,
+                src2: Src2::Reg(Register::T0), // don't care when pushing a reg
+            })),
+            _ => Ok(write_data.instruction(|src2| Isa::Mem {
+                op,
+                byte,
+                td: Register::T0, // don't care when pushing an immediate
+                tn: Register::Sp,
+                src2,
+            })),
         }
     } else {
-        Err("Invalid operands for push".into())
+        Err(invalid_operands("push"))
     }
 }
 
-fn parse_pop(operands: &[&str], byte: bool) -> Result<Instruction, String> {
+fn parse_pop(operands: &[Operand], byte: bool) -> Result<Instruction, Error> {
     if operands.len() == 1 {
-        let bsl = if byte { 0b111 } else { 0b011 };
         let td = parse_register(operands[0])?;
-        Ok(Instruction::Mem {
-            bsl,
+        Ok(Instruction::Isa(Isa::Mem {
+            op: MemOp::Pop,
+            byte,
             td,
-            tn: 0b101,
-            src2: Src2::Reg(0b000), // don't care when popping
-        })
+            tn: Register::Sp,
+            src2: Src2::Reg(Register::T0), // don't care when popping
+        }))
     } else {
-        Err("Invalid operands for pop".into())
+        Err(invalid_operands("pop"))
     }
 }
 
-fn parse_alias(opcode: &str, operands: &[&str]) -> Result<Instruction, String> {
+fn parse_alias(opcode: &str, operands: &[Operand], fold: Fold) -> Result<Instruction, Error> {
+    let imm = |value| [Token::synthetic(TokenKind::Punct("!")), Token::synthetic(TokenKind::Number(value))];
+    let (one, minus_one) = (imm(1), imm(-1));
+    let reg_in = [Token::synthetic(TokenKind::Register(Register::In))];
+
     let mut vec = operands.to_vec();
     match opcode {
         "inc" => {
-            vec.push("!1");
-            if let Ok(instr) = parse_dp("add", &vec[..]) {
+            vec.push(&one);
+            if let Ok(instr) = parse_dp("add", &vec[..], fold) {
                 Ok(instr)
             } else {
-                Err("Invalid arguments for inc".into())
+                Err(Error::new(Code::InvalidOperands, "Invalid arguments for inc"))
             }
         }
         "dec" => {
-            vec.push("!1");
-            if let Ok(instr) = parse_dp("sub", &vec[..]) {
+            vec.push(&one);
+            if let Ok(instr) = parse_dp("sub", &vec[..], fold) {
                 Ok(instr)
             } else {
-                Err("Invalid arguments for dec".into())
+                Err(Error::new(Code::InvalidOperands, "Invalid arguments for dec"))
             }
         }
         "not" => {
-            vec.push("!-1");
-            if let Ok(instr) = parse_dp("xor", &vec[..]) {
+            vec.push(&minus_one);
+            if let Ok(instr) = parse_dp("xor", &vec[..], fold) {
                 Ok(instr)
             } else {
-                Err("Invalid arguments for not".into())
+                Err(Error::new(Code::InvalidOperands, "Invalid arguments for not"))
             }
         }
         "cmp" => {
-            vec.insert(0, "in");
-            if let Ok(instr) = parse_dp("sub", &vec[..]) {
+            vec.insert(0, &reg_in);
+            if let Ok(instr) = parse_dp("sub", &vec[..], fold) {
                 Ok(instr)
             } else {
-                Err("Invalid arguments for cmp".into())
+                Err(Error::new(Code::InvalidOperands, "Invalid arguments for cmp"))
             }
         }
         "tst" => {
-            vec.insert(0, "in");
-            if let Ok(instr) = parse_dp("and", &vec[..]) {
+            vec.insert(0, &reg_in);
+            if let Ok(instr) = parse_dp("and", &vec[..], fold) {
                 Ok(instr)
             } else {
-                Err("Invalid arguments for tst".into())
+                Err(Error::new(Code::InvalidOperands, "Invalid arguments for tst"))
             }
         }
         "ret" => {
             if vec.is_empty() {
-                Ok(Instruction::Mem {
-                    bsl: 0b011,
-                    td: 0b110,
-                    tn: 0b101,
-                    src2: Src2::Reg(0b000),
-                })
+                Ok(Instruction::Isa(Isa::Mem {
+                    op: MemOp::Pop,
+                    byte: false,
+                    td: Register::Pc,
+                    tn: Register::Sp,
+                    src2: Src2::Reg(Register::T0),
+                }))
             } else {
-                Err(format!("Unexpected token '{}' after ret", vec[0]))
+                let message = format!("Unexpected token '{}' after ret", token_text(vec[0]));
+                Err(Error::new(Code::InvalidOperands, message).at(vec[0]))
             }
         }
         "nop" => {
             if vec.is_empty() {
-                Ok(Instruction::BranchOffset {
-                    cond: 0b1111,
+                Ok(Instruction::Isa(Isa::Branch {
+                    cond: Condition::Nv,
                     offset: Offset::SignImm9(0),
-                })
+                }))
             } else {
-                Err(format!("Unexpected token '{}' after nop", vec[0]))
+                let message = format!("Unexpected token '{}' after nop", token_text(vec[0]));
+                Err(Error::new(Code::InvalidOperands, message).at(vec[0]))
             }
         }
-        _ => Err(format!("Parsing non-existant Alias {}", opcode)),
+        _ => panic!("Parsing non-existant Alias {}", opcode),
     }
 }
 
-fn parse_branch(opcode: &str, operands: &[&str]) -> Result<Instruction, String> {
-    if operands.len() == 1 {
-        let label = operands[0].to_string();
-        Ok(Instruction::BranchLabel {
-            cond: parse_cond(opcode)?,
-            label,
-        })
-    } else {
-        Err(format!("Invalid operands for {}", opcode))
-    }
-}
-
-fn parse_register(token: &str) -> Result<u8, String> {
-    match token {
-        "t0" => Ok(0b000),
-        "t1" => Ok(0b001),
-        "t2" => Ok(0b010),
-        "t3" => Ok(0b011),
-        "bp" => Ok(0b100),
-        "sp" => Ok(0b101),
-        "pc" => Ok(0b110),
-        "in" => Ok(0b111),
-        _ => Err(format!("Invalid register {}", token)),
-    }
-}
-
-fn parse_cmd(token: &str) -> Result<u8, String> {
-    match token {
-        "add" => Ok(0b000),
-        "sub" => Ok(0b001),
-        "and" => Ok(0b010),
-        "or" => Ok(0b011),
-        "xor" => Ok(0b100),
-        "mov" => Ok(0b101),
-        "shl" => Ok(0b110),
-        "shr" => Ok(0b111),
-        _ => Err(format!("Parsing non-existant DP cmd: {}", token)),
-    }
-}
-
-fn parse_register_or_imm(token: &str) -> Result<Src2, String> {
-    if let Ok(reg) = parse_register(token) {
-        Ok(Src2::Reg(reg))
-    } else if let Some(imm_token) = token.strip_prefix('!') {
-        // hex imm
-        let imm = if imm_token.starts_with("0x") {
-            i16::from_str_radix(imm_token.strip_prefix("0x").unwrap(), 16)
-                .map_err(|_| format!("Invalid hexadecimal immediate: {}", imm_token))?
-        }
-        // decimal imm
-        else {
-            imm_token
-                .parse::<i16>()
-                .map_err(|_| format!("Invalid decimal immediate: {}", imm_token))?
-        };
+fn parse_pseudo(opcode: &str, operands: &[Operand], fold: Fold) -> Result<Vec<Instruction>, Error> {
+    let imm = |value| [Token::synthetic(TokenKind::Punct("!")), Token::synthetic(TokenKind::Number(value))];
+    let (one, minus_one) = (imm(1), imm(-1));
+    let pc = [Token::synthetic(TokenKind::Register(Register::Pc))];
 
-        if imm >= 0 && imm <= 7 {
-            Ok(Src2::ZeroImm3(imm as u8))
-        } else if imm >= -8 && imm < 0 {
-            Ok(Src2::OneImm3(imm as i8))
-        } else {
-            Ok(Src2::WideImm16(imm))
+    match (opcode, operands) {
+        // mov pc, reg
+        ("jmp", [target]) => Ok(vec![parse_dp("mov", &[&pc, target], fold)?]),
+        // the pushed pc is the address after the jump, wide or not, since pc reads ahead of the next instruction
+        ("call" | "jsr" | "bsr", [target]) => {
+            let jump = match target {
+                [Token { kind: TokenKind::Register(_), .. }] => parse_dp("mov", &[&pc, target], fold)?,
+                _ => parse_branch("jmp", operands)?,
+            };
+            Ok(vec![parse_push(&[&pc], false, fold)?, jump])
         }
-    } else {
-        Err(format!("Invalid register or immediate: {}", token))
-    }
-}
-
-fn parse_cond(token: &str) -> Result<u8, String> {
-    let cond = &token[1..];
-    match cond {
-        "z" | "eq" => Ok(0b0000),
-        "nz" | "ne" => Ok(0b0001),
-        "lt" => Ok(0b0010),
-        "le" => Ok(0b0011),
-        "gt" => Ok(0b0100),
-        "ge" => Ok(0b0101),
-        "ult" | "cc" => Ok(0b0110),
-        "ule" => Ok(0b0111),
-        "ugt" => Ok(0b1000),
-        "uge" | "cs" => Ok(0b1001),
-        "mi" | "ns" => Ok(0b1010),
-        "pl" | "nc" => Ok(0b1011),
-        "vs" => Ok(0b1100),
-        "vc" => Ok(0b1101),
-        "al" | "mp" => Ok(0b1110),
-        "nv" => Ok(0b1111),
-        _ => Err(format!("Invalid conditional for JMP instruction: {}", cond)),
-    }
-}
-
-fn match_token(token: &str, pat: &str) -> Result<(), String> {
-    if token == pat {
-        Ok(())
-    } else {
-        Err(format!("Expected '{}', found '{}'", pat, token))
+        // mov td, !label
+        ("la", [td, address]) => {
+            let mut imm = vec![Token::synthetic(TokenKind::Punct("!"))];
+            imm.extend_from_slice(address);
+            Ok(vec![parse_dp("mov", &[td, &imm], fold)?])
+        }
+        // xor td, tn, !-1 / add td, td, !1
+        ("neg", [td, rest @ ..]) if rest.len() <= 1 => {
+            let tn = rest.first().unwrap_or(td);
+            Ok(vec![parse_dp("xor", &[td, tn, &minus_one], fold)?, parse_dp("add", &[td, td, &one], fold)?])
+        }
+        ("swap", [a, b]) => {
+            if parse_register(a)? == parse_register(b)? {
+                return Err(Error::new(Code::InvalidOperands, "swap needs two different registers").at(b));
+            }
+            Ok(vec![parse_dp("xor", &[a, a, b], fold)?, parse_dp("xor", &[b, b, a], fold)?, parse_dp("xor", &[a, a, b], fold)?])
+        }
+        ("clr", [td]) => Ok(vec![parse_dp("mov", &[td, &imm(0)], fold)?]),
+        _ => Err(invalid_operands(opcode)),
+    }
+}
+
+fn parse_branch(opcode: &str, operands: &[Operand]) -> Result<Instruction, Error> {
+    match operands {
+        [[label]] => {
+            let label = label.ident().ok_or_else(|| {
+                Error::new(Code::InvalidOperands, format!("Invalid label {}", label.text)).at(std::slice::from_ref(label))
+            })?;
+            Ok(Instruction::BranchLabel {
+                cond: parse_cond(opcode)?,
+                label: label.to_string(),
+                wide: false,
+            })
+        }
+        _ => Err(invalid_operands(opcode)),
+    }
+}
+
+fn invalid_operands(opcode: &str) -> Error {
+    Error::new(Code::InvalidOperands, format!("Invalid operands for {}", opcode))
+}
+
+fn parse_register(operand: Operand) -> Result<Register, Error> {
+    match operand {
+        [Token { kind: TokenKind::Register(r), .. }] => Ok(*r),
+        _ => Err(Error::new(Code::InvalidRegister, format!("Invalid register {}", token_text(operand))).at(operand)),
+    }
+}
+
+fn parse_cmd(token: &str) -> Result<AluOp, String> {
+    match AluOp::from_mnemonic(token) {
+        Some(op) => Ok(op),
+        None => Err(format!("Parsing non-existant DP cmd: {}", token)),
+    }
+}
+
+// the last operand of a dp or mem instruction, an expression while it depends on labels
+enum Src2Operand {
+    Known(Src2),
+    Expr(Expr),
+}
+
+impl Src2Operand {
+    // an expression gets a wide immediate, which the second pass fills in
+    fn instruction(self, instruction: impl FnOnce(Src2) -> Isa) -> Instruction {
+        match self {
+            Src2Operand::Known(src2) => Instruction::Isa(instruction(src2)),
+            Src2Operand::Expr(expr) => Instruction::Expr(instruction(Src2::WideImm16(0)), expr),
+        }
+    }
+}
+
+fn parse_register_or_imm(operand: Operand, fold: Fold) -> Result<Src2Operand, Error> {
+    match operand {
+        [Token { kind: TokenKind::Register(r), .. }] => Ok(Src2Operand::Known(Src2::Reg(*r))),
+        [bang, imm @ ..] if bang.is_punct("!") => {
+            let expr = Expr::parse_tokens(imm).map_err(|error| error.at(imm))?;
+            parse_imm(expr, fold).map_err(|error| error.at(imm))
+        }
+        _ => {
+            let message = format!("Invalid register or immediate: {}", token_text(operand));
+            Err(Error::new(Code::InvalidOperands, message).at(operand))
+        }
+    }
+}
+
+fn parse_imm(expr: Expr, fold: Fold) -> Result<Src2Operand, Error> {
+    let imm = match fold(&expr) {
+        Some(imm) => imm?,
+        // resolved once every label has its address
+        None => return Ok(Src2Operand::Expr(expr)),
+    };
+    imm_src2(imm).map(Src2Operand::Known).map_err(|err| Error::new(Code::OutOfRange, err))
+}
+
+fn parse_cond(token: &str) -> Result<Condition, Error> {
+    match Condition::from_mnemonic(token) {
+        Some(cond) => Ok(cond),
+        None => Err(Error::new(Code::UnknownInstruction, format!("Invalid conditional for JMP instruction: {}", &token[1..]))),
     }
 }
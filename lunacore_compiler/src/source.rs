@@ -0,0 +1,74 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// where a line of assembly came from, including the macro expansion that produced it
+// and the .include directive that brought its file in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub expansion: Option<Rc<Expansion>>,
+    pub included_from: Option<Rc<Location>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub name: String,
+    pub definition: Location,
+    pub call: Location,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub text: String,
+    pub location: Location,
+}
+
+impl Location {
+    // where the line came from when it is not simply a line of the input file
+    pub fn notes(&self) -> Vec<String> {
+        let mut notes = Vec::new();
+
+        let mut expansion = &self.expansion;
+        while let Some(exp) = expansion {
+            notes.push(format!(
+                "in expansion of macro {} (defined at {}:{}), called at {}:{}",
+                exp.name, exp.definition.file, exp.definition.line, exp.call.file, exp.call.line
+            ));
+            expansion = &exp.call.expansion;
+        }
+
+        let mut included_from = &self.included_from;
+        while let Some(include) = included_from {
+            notes.push(format!("included from {}:{}", include.file, include.line));
+            included_from = &include.included_from;
+        }
+        notes
+    }
+
+    // number of macro expansions this line is nested in
+    pub fn expansion_depth(&self) -> usize {
+        match &self.expansion {
+            Some(exp) => 1 + exp.call.expansion_depth(),
+            None => 0,
+        }
+    }
+}
+
+pub fn source_lines(input: &str, filename: &str, included_from: Option<&Location>) -> VecDeque<SourceLine> {
+    let file: Rc<str> = Rc::from(filename);
+    let included_from = included_from.map(|location| Rc::new(location.clone()));
+    input
+        .lines()
+        .enumerate()
+        .map(|(i, text)| SourceLine {
+            text: text.to_string(),
+            location: Location {
+                file: file.clone(),
+                line: i + 1,
+                expansion: None,
+                included_from: included_from.clone(),
+            },
+        })
+        .collect()
+}
@@ -0,0 +1,122 @@
+use compiler::diagnostic::Code;
+use compiler::disassembler::disassemble;
+use compiler::layout::Layout;
+use compiler::{assemble, Options};
+use lunacore_isa::container::{Container, SectionKind};
+
+#[test]
+fn assemble_inline_source() {
+    let source = "
+        .data
+        count:  .byte 3
+        .text
+        _start: mov t0, !1
+                lod t1, [t0 + !count]
+        loop:   jmp loop
+        ";
+    let program = assemble(source, &Options::default()).unwrap();
+
+    assert_eq!(program.words, [0x1a01, 0x5240, 0x9dfe]);
+    assert_eq!(program.data, [3]);
+    assert_eq!(program.entry, 0);
+    assert!(program.warnings.is_empty());
+
+    let loop_label = program.symbol("loop").unwrap();
+    assert_eq!((loop_label.section, loop_label.address), (SectionKind::Text, 2));
+    assert_eq!(program.symbol("count").unwrap().section, SectionKind::Data);
+    let names = program.symbols.iter().map(|symbol| symbol.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["_start", "loop", "count"]);
+    assert_eq!(program.symbol_table(), "0000 text _start\n0002 text loop\n0000 data count\n");
+
+    let kinds = program.sections.iter().map(|section| section.kind).collect::<Vec<_>>();
+    assert_eq!(kinds, [SectionKind::Text, SectionKind::Data]);
+    assert_eq!(program.container().sections, program.sections);
+}
+
+#[test]
+fn line_map() {
+    let program = assemble("start:\n    nop\n    mov t0, !100\n    ret\n", &Options::default()).unwrap();
+
+    let lines = program.lines.iter().map(|line| (line.address, line.words, line.source.location.line)).collect::<Vec<_>>();
+    assert_eq!(lines, [(0, 1, 2), (1, 2, 3), (3, 1, 4)]);
+    assert_eq!(program.line_at(2).unwrap().source.text, "    mov t0, !100");
+    assert!(program.line_at(4).is_none());
+}
+
+#[test]
+fn entry_point() {
+    let program = assemble("f: ret\n_start: call f\n", &Options::default()).unwrap();
+    assert_eq!(program.entry, 1);
+    assert_eq!(program.container().entry, 1);
+}
+
+#[test]
+fn procedure_extent() {
+    let program = assemble("_start: call f\njmp _start\n.proc f\nmov t0, !1\n.endproc\n", &Options::default()).unwrap();
+    let f = program.symbol("f").unwrap();
+    assert_eq!((f.address, f.size), (3, 6));
+    assert_eq!(program.symbol("_start").unwrap().size, 0);
+    assert_eq!(program.symbol_table(), "0000 text _start\n0003 text f 0006\n");
+    assert!(program.debug_info().contains("\nproc 0003 0009 f\n"));
+}
+
+#[test]
+fn layout() {
+    let options = Options {
+        layout: Layout::parse("text 0x40\nrodata 0x800\nbss 0x1000").unwrap(),
+        ..Options::default()
+    };
+    let program = assemble(".rodata\ntable: .byte 1, 2\n.bss\nbuf: .space 16\n.text\nla t0, table\nret\n", &options).unwrap();
+
+    // .bss is only zeroed memory, so the executable does not store it
+    let sections = program.sections.iter().map(|section| (section.kind, section.address, section.bytes.len())).collect::<Vec<_>>();
+    assert_eq!(sections, [(SectionKind::Text, 0x40, 6), (SectionKind::Data, 0x800, 2)]);
+    assert_eq!(program.entry, 0x40);
+    assert_eq!(program.words.len(), 0x43);
+    assert_eq!(program.data.len(), 0x802);
+    assert_eq!(program.symbol("buf").unwrap().address, 0x1000);
+}
+
+#[test]
+fn block_labels() {
+    let program = assemble("_start: .while t0 <u t1\n.if t0 == !0\nnop\n.else\ninc t0\n.endif\n.endw\n", &Options::default()).unwrap();
+    // the labels the blocks branch to are not symbols, nobody could write or break on them
+    assert_eq!(program.symbol_table(), "0000 text _start\n");
+    assert!(!program.debug_info().contains('@'));
+    assert!(!program.listing().split("Symbols:").nth(1).unwrap().contains('@'));
+
+    let options = Options { relocatable: true, ..Options::default() };
+    let object = assemble(".global f\nf:\n.if t0 == t1\nnop\n.endif\nret\n", &options).unwrap().object();
+    assert!(!object.to_string().contains('@'));
+}
+
+#[test]
+fn initial_sp() {
+    let options = Options {
+        layout: Layout::parse("stack 0xff00").unwrap(),
+        ..Options::default()
+    };
+    let program = assemble("push t0\n", &options).unwrap();
+    assert_eq!(program.sp, 0xff00);
+    let container = Container::parse(&program.container().to_bytes()).unwrap();
+    assert_eq!(container.sp, 0xff00);
+    // the source of the disassembly cannot hold it, so it says where it goes
+    assert!(disassemble(&container).contains("`stack 0xff00` layout line"));
+    assert_eq!(assemble("push t0\n", &Options::default()).unwrap().container().sp, 0);
+}
+
+#[test]
+fn diagnostics() {
+    let options = Options {
+        filename: "prog.luna".to_string(),
+        ..Options::default()
+    };
+    let errors = assemble("shl t0, t0, !20\nmov t9, !1\njmp nowhere\n", &options).unwrap_err();
+
+    let found = errors.iter().map(|error| (error.is_error(), error.code, error.location.line)).collect::<Vec<_>>();
+    assert_eq!(found, [(true, Code::InvalidRegister, 2), (true, Code::UndefinedSymbol, 3), (false, Code::OutOfRange, 1)]);
+    assert!(errors[0].to_string().contains("--> prog.luna:2:5"));
+
+    let program = assemble("shl t0, t0, !20\n", &options).unwrap();
+    assert_eq!(program.warnings.len(), 1);
+}
@@ -0,0 +1,126 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+// runs the compiler with `stdin` as its input
+fn compiler(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_compiler"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("lunacore_cli_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn stdin_to_stdout() {
+    let output = compiler(&["--emit", "sym", "-"], "_start: nop\nloop: jmp loop\n");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "0000 text _start\n0001 text loop\n");
+    assert!(output.stderr.starts_with(b"Symbol table generated"));
+
+    let output = compiler(&["-q", "-"], "mov t0, !1\n");
+    assert!(output.status.success());
+    assert_eq!(&output.stdout[..4], b"LUNA");
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn output_file() {
+    let dir = temp_dir("output");
+    let input = dir.join("prog.luna");
+    fs::write(&input, "mov t0, !1\nret\n").unwrap();
+    let output_path = dir.join("out.hex");
+
+    let output = compiler(&["-q", "--emit", "hex", "-o", output_path.to_str().unwrap(), input.to_str().unwrap()], "");
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(&output_path).unwrap(), ":040000001A0147A8F2\n:00000001FF\n");
+    assert!(!dir.join("prog.lunaexe").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sidecar_files() {
+    let dir = temp_dir("sidecar");
+    let input = dir.join("prog.luna");
+    fs::write(&input, "_start: mov t0, !1\nret\n").unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+    let output_path = dir.join("out").join("sort.lunaexe");
+
+    let output = compiler(&["-q", "-g", "-l", "-o", output_path.to_str().unwrap(), input.to_str().unwrap()], "");
+    assert!(output.status.success());
+    // named after the executable, which is where the emulator looks for the debug info
+    assert!(dir.join("out").join("sort.lunadbg").exists());
+    assert!(dir.join("out").join("sort.lunalst").exists());
+    assert!(!dir.join("prog.lunadbg").exists());
+    assert!(!dir.join("prog.lunalst").exists());
+
+    assert_eq!(compiler(&["-g", "-o", "-", input.to_str().unwrap()], "").status.code(), Some(2));
+    assert!(compiler(&["-q", "-g", "-o", output_path.to_str().unwrap(), "-"], "nop\n").status.success());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn exit_status() {
+    let dir = temp_dir("exit_status");
+    let input = dir.join("bad.luna");
+    fs::write(&input, "mov t9, t0\n").unwrap();
+
+    let output = compiler(&[input.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error[E0005]: Invalid register t9"));
+    assert!(stderr.ends_with("1 error(s), no file was generated\n"));
+    assert!(!dir.join("bad.lunaexe").exists());
+
+    assert_eq!(compiler(&["-"], "mov t9, t0\n").status.code(), Some(1));
+    assert_eq!(compiler(&[dir.join("missing.luna").to_str().unwrap()], "").status.code(), Some(1));
+    assert_eq!(compiler(&["--emit", "elf", "-"], "").status.code(), Some(2));
+    assert_eq!(compiler(&["-x", "-"], "").status.code(), Some(2));
+    assert_eq!(compiler(&["-l", "-"], "").status.code(), Some(2));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn raw_entry_point() {
+    let dir = temp_dir("raw");
+    let input = dir.join("prog.luna");
+    fs::write(&input, "f: mov t0, !1\nret\n_start: call f\n").unwrap();
+    let output_path = dir.join("prog.bin");
+
+    // bare words would start in f, the container keeps _start
+    let output = compiler(&["--raw", "-o", output_path.to_str().unwrap(), input.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("the entry point is 0x0002"));
+    assert!(!output_path.exists());
+    assert!(compiler(&["-q", "-o", output_path.to_str().unwrap(), input.to_str().unwrap()], "").status.success());
+
+    fs::write(&input, "_start: call f\nf: ret\n").unwrap();
+    assert!(compiler(&["-q", "--raw", "-o", output_path.to_str().unwrap(), input.to_str().unwrap()], "").status.success());
+
+    // the linker takes _start from whichever module defines it
+    let objects = [("lib", ".global f\nf: ret\n"), ("main", ".global _start\n.extern f\n_start: call f\n")].map(|(name, source)| {
+        let object = dir.join(format!("{}.lunaobj", name));
+        let output = compiler(&["-q", "-c", "-o", object.to_str().unwrap(), "-"], source);
+        assert!(output.status.success());
+        object.to_str().unwrap().to_string()
+    });
+    let linker = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_linker")).args(args).args(&objects).output().unwrap();
+    let output = linker(&["--raw", "-o", output_path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("_start is at 0x0001"));
+    assert!(linker(&["-o", output_path.to_str().unwrap()]).status.success());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,1051 @@
+use compiler::compiler::compile;
+use compiler::diagnostic::*;
+use compiler::expr::Expr;
+use compiler::instructions::*;
+use compiler::layout::Layout;
+use compiler::parser::*;
+use lunacore_isa::{AluOp, Condition, Instruction as Isa, MemOp, Offset, Register, Src2};
+use std::env;
+use std::fs;
+
+// the rendered diagnostics of a failed parse
+fn errors(result: Result<(), Vec<Diagnostic>>) -> Vec<String> {
+    result.unwrap_err().iter().map(Diagnostic::to_string).collect()
+}
+
+#[test]
+fn parser_test() {
+    let program = "
+                _start: \n
+                        mov t0, !5 \n
+                        mov t1, !0x1000\n
+                        xor t2, t2\n
+                        push pc \n
+                        JMP loop \n
+                        Jmp _end \n
+                \n
+                loop:   cmp t0, !0 \n
+                        jeq endloop \n
+                        savB T0, [T1+T2] \n
+                        dec t0 \n 
+                        inc t2 \n
+                        jmp loop \n
+                endloop:\n
+                        ret \n
+                _end: \n
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+
+    println!("Parsed Program: {:#?}", parser.program);
+    println!("Labels: {:#?}", parser.label_map);
+
+    assert_eq!(parser.label_map.len(), 4);
+    assert_eq!(parser.label_map.get("_start"), Some(&0));
+    assert_eq!(parser.label_map.get("loop"), Some(&7));
+    assert_eq!(parser.label_map.get("endloop"), Some(&13));
+    assert_eq!(parser.label_map.get("_end"), Some(&14));
+
+    assert_eq!(
+        parser.program[4],
+        (
+            5,
+            Instruction::Isa(Isa::Branch { cond: Condition::Mp, offset: Offset::SignImm9(0) })
+        )
+    );
+    assert_eq!(
+        parser.program[11],
+        (
+            12,
+            Instruction::Isa(Isa::Branch { cond: Condition::Mp, offset: Offset::SignImm9(-7) })
+        )
+    );
+}
+
+#[test]
+fn parse_and_compile() {
+    let program = "
+            main:
+                push !104
+                push pc
+                jmp natural_sum
+                mov t3, t0
+                jmp end
+
+            natural_sum:
+                mov t0, !1
+                mov t1, !1
+                lod t2, [sp + !0x02]
+            while:
+                cmp t1, t2
+                jge endwhile
+                add t1, t1, !1 
+                nop
+                add t0, t0, t1
+                jmp while
+
+            endwhile:
+                ret
+
+            end:
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+
+    println!("Parsed Program: {:#?}", parser.program);
+    println!("Labels: {:#?}", parser.label_map);
+
+    let binary = compile(&parser.get_program());
+    let expected: Vec<u16> = vec![
+        // main:
+        0b0111010000101000, 104,
+        0b0100010110101000,
+        0b1001110000000001,
+        0b0000101011000000,
+        0b1001110000001001,
+        // natural_sum:
+        0b0001101000000001,
+        0b0001101001000001,
+        0b0101001010101010,
+        // while:
+        0b0000001111001010,
+        0b1000101000000011,
+        0b0001000001001001,
+        0b1001111000000000,
+        0b0000000000000001,
+        0b1001110111111001,
+        // endwhile:
+        0b0100011110101000,
+        // end:
+    ];
+
+    assert_eq!(binary, expected);
+}
+
+#[test]
+fn branch_relaxation() {
+    // 300 words between the branches and their targets, too far for a 9-bit offset
+    let padding = "mov t0, !0x1234\n".repeat(150);
+    let program = format!("start: jmp far\njmp start\n{}far: jmp start\njeq near\nnear: nop", padding);
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(&program, "test"), Ok(()));
+
+    // only the branches across the padding are widened
+    assert_eq!(parser.label_map.get("far"), Some(&303));
+    assert_eq!(
+        parser.program[0].1,
+        Instruction::Isa(Isa::Branch { cond: Condition::Mp, offset: Offset::WideImm16(300) })
+    );
+    assert_eq!(
+        parser.program[1].1,
+        Instruction::Isa(Isa::Branch { cond: Condition::Mp, offset: Offset::SignImm9(-5) })
+    );
+    assert_eq!(
+        parser.program[152],
+        (303, Instruction::Isa(Isa::Branch { cond: Condition::Mp, offset: Offset::wide(-306) }))
+    );
+    assert_eq!(
+        parser.program[153],
+        (305, Instruction::Isa(Isa::Branch { cond: Condition::Z, offset: Offset::SignImm9(-1) }))
+    );
+}
+
+#[test]
+fn parse_data_directives() {
+    let program = "
+            .data
+            table:  .word 0x1234, -1, end
+            msg:    .asciz \"hi:\\n\"
+                    .align 2
+            bytes:  .byte 1, 255, -128
+                    .space 3, 0x7f
+            .text
+            main:
+                    mov t0, !1
+            end:
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+
+    assert_eq!(parser.label_map.get("main"), Some(&0));
+    assert_eq!(parser.label_map.get("end"), Some(&1));
+    assert_eq!(parser.data_label_map.get("table"), Some(&0));
+    assert_eq!(parser.data_label_map.get("msg"), Some(&6));
+    assert_eq!(parser.data_label_map.get("bytes"), Some(&12));
+
+    let expected: Vec<u8> = vec![
+        // table:
+        0x34, 0x12, 0xff, 0xff, 0x01, 0x00,
+        // msg:
+        b'h', b'i', b':', b'\n', 0x00,
+        // .align 2
+        0x00,
+        // bytes:
+        0x01, 0xff, 0x80,
+        0x7f, 0x7f, 0x7f,
+    ];
+    assert_eq!(parser.data, expected);
+}
+
+#[test]
+fn data_directive_errors() {
+    let mut parser = Parser::new();
+    assert!(parser.parse_program(".data\n.byte 1\n.word 2", "test").is_err());
+    assert!(parser.parse_program(".data\n.byte 256", "test").is_err());
+    assert!(parser.parse_program(".byte 2", "test").is_err());
+    // raw words in .text have to be known constants
+    assert!(parser.parse_program(".word later\nlater: nop", "test").is_err());
+    assert!(parser.parse_program(".data\nmov t0, !1", "test").is_err());
+    assert!(parser.parse_program(".data\n.word missing", "test").is_err());
+    assert!(parser.parse_program(".data\nx: .byte 1\n.text\nx: nop", "test").is_err());
+}
+
+#[test]
+fn parse_immediates() {
+    let program = "
+            mov t0, !0xffff
+            mov t0, !0x8000
+            mov t0, !-0x10
+            mov t0, !0b101
+            mov t0, !0o17
+            mov t0, !'\\n'
+            mov t0, !-32768
+            ";
+
+    let mut parser = Parser::new();
+    parser.parse_program(program, "test").unwrap();
+    let src2 = parser
+        .get_program()
+        .into_iter()
+        .map(|instruction| match instruction {
+            Instruction::Isa(Isa::Dp { src2, .. }) => src2,
+            _ => unreachable!(),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        src2,
+        vec![
+            Src2::WideImm16(0xffff),
+            Src2::WideImm16(0x8000),
+            Src2::WideImm16(0xfff0),
+            Src2::ZeroImm3(5),
+            Src2::WideImm16(15),
+            Src2::WideImm16(10),
+            Src2::WideImm16(0x8000),
+        ]
+    );
+
+    // the 16-bit field takes signed and unsigned values
+    let diagnostics = parser.parse_program("mov t0, !0x10000\nmov t0, !-32769\n.equ N 0x10000\nmov t0, !N", "test").unwrap_err();
+    let messages = diagnostics.iter().map(|d| (d.code, d.message.as_str())).collect::<Vec<_>>();
+    assert_eq!(
+        messages,
+        vec![
+            (Code::OutOfRange, "Immediate 65536 out of range [-32768, 65535]"),
+            (Code::OutOfRange, "Immediate -32769 out of range [-32768, 65535]"),
+            (Code::OutOfRange, "Immediate 65536 out of range [-32768, 65535]"),
+        ]
+    );
+    assert!(parser.parse_program("mov t0, !0b102", "test").is_err());
+    assert!(parser.parse_program("mov t0, !0o8", "test").is_err());
+    assert!(parser.parse_program("mov t0, !0x1_0", "test").is_err());
+}
+
+#[test]
+fn parse_constant_expressions() {
+    let program = "
+            .equ BUF_SIZE 10
+            .equ MASK, (1 << 4) - 1
+            .data
+            start:  .space BUF_SIZE
+            end:    .word end - start, lo(0x1234) | hi(0x1234) << 8
+            .text
+            main:
+                    mov t0, !(BUF_SIZE*2 - 1)
+                    and t2, t2, !MASK >> 2
+                    sav t0, [bp - !2]
+                    lod t1, [bp + !end - start]
+                    mov t3, !later - main
+                    push !LATE
+            later:
+            .equ LATE 3
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+
+    let src2 = |i: usize| match &parser.program[i].1 {
+        Instruction::Isa(instruction) => instruction.src2().unwrap(),
+        _ => panic!(),
+    };
+    assert_eq!(src2(0), Src2::WideImm16(19));
+    assert_eq!(src2(1), Src2::ZeroImm3(3));
+    assert_eq!(src2(2), Src2::OneImm3(-2));
+    assert_eq!(src2(3), Src2::WideImm16(10));
+    // label and forward references keep the wide encoding
+    assert_eq!(src2(4), Src2::WideImm16(10));
+    assert_eq!(src2(5), Src2::WideImm16(3));
+
+    assert_eq!(parser.data[10..], [10, 0, 0x34, 0x12]);
+}
+
+#[test]
+fn constant_expression_errors() {
+    let mut parser = Parser::new();
+    assert!(parser.parse_program("mov t0, !missing", "test").is_err());
+    assert!(parser.parse_program("mov t0, !(1 << 16)", "test").is_err());
+    assert!(parser.parse_program(".equ A B\n.equ B A\nmov t0, !A", "test").is_err());
+    assert!(parser.parse_program(".equ A 1\n.equ A 2", "test").is_err());
+    assert!(parser.parse_program(".equ A 1\nA: nop", "test").is_err());
+    assert!(parser.parse_program(".data\n.space later\nlater:", "test").is_err());
+}
+
+#[test]
+fn parse_wide_forms() {
+    let program = "
+            start:  mov.w t0, !3
+                    PUSH.W !-1
+                    jmp.w start
+                    jz start
+                    .word 0xc000, -1
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(
+        compile(&parser.get_program()),
+        vec![0x3a00, 0x0003, 0x7428, 0xffff, 0xbc00, 0xfff9, 0x81f8, 0xc000, 0xffff]
+    );
+
+    assert!(parser.parse_program("mov.w t0, t1", "test").is_err());
+    assert!(parser.parse_program("call.w start\nstart: nop", "test").is_err());
+    assert!(parser.parse_program(".word 0x10000", "test").is_err());
+}
+
+#[test]
+fn parse_pseudo_instructions() {
+    let program = "
+            .data
+            table: .word 1
+            .text
+            main:   call f
+                    jsr t1
+                    jmp t1
+                    la t0, table
+                    neg t0
+                    neg t1, t2
+                    swap t0, t1
+                    clr t3
+            f:      ret
+            ";
+
+    let mut parser = Parser::new();
+    parser.parse_program(program, "test").unwrap();
+    assert_eq!(
+        parser.get_program(),
+        vec![
+            Instruction::Isa(Isa::Mem { op: MemOp::Push, byte: false, td: Register::Pc, tn: Register::Sp, src2: Src2::Reg(Register::T0) }),
+            Instruction::Isa(Isa::Branch { cond: Condition::Mp, offset: Offset::SignImm9(11) }),
+            Instruction::Isa(Isa::Mem { op: MemOp::Push, byte: false, td: Register::Pc, tn: Register::Sp, src2: Src2::Reg(Register::T0) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Mov, td: Register::Pc, tn: Register::T0, src2: Src2::Reg(Register::T1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Mov, td: Register::Pc, tn: Register::T0, src2: Src2::Reg(Register::T1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Mov, td: Register::T0, tn: Register::T0, src2: Src2::ZeroImm3(0) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Xor, td: Register::T0, tn: Register::T0, src2: Src2::OneImm3(-1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Add, td: Register::T0, tn: Register::T0, src2: Src2::ZeroImm3(1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Xor, td: Register::T1, tn: Register::T2, src2: Src2::OneImm3(-1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Add, td: Register::T1, tn: Register::T1, src2: Src2::ZeroImm3(1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Xor, td: Register::T0, tn: Register::T0, src2: Src2::Reg(Register::T1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Xor, td: Register::T1, tn: Register::T1, src2: Src2::Reg(Register::T0) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Xor, td: Register::T0, tn: Register::T0, src2: Src2::Reg(Register::T1) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Mov, td: Register::T3, tn: Register::T0, src2: Src2::ZeroImm3(0) }),
+            Instruction::Isa(Isa::Mem { op: MemOp::Pop, byte: false, td: Register::Pc, tn: Register::Sp, src2: Src2::Reg(Register::T0) }),
+        ]
+    );
+    assert_eq!(parser.label_map.get("f"), Some(&14));
+
+    assert!(parser.parse_program("swap t0, t0", "test").is_err());
+    assert!(parser.parse_program("la t0", "test").is_err());
+    assert!(parser.parse_program("call f, g\nf: nop\ng: nop", "test").is_err());
+    assert!(parser.parse_program("neg t0, t1, t2", "test").is_err());
+    assert!(parser.parse_program("jnz t0", "test").is_err());
+    assert!(parser.parse_program(".macro call f\n.endm", "test").is_err());
+}
+
+#[test]
+fn relocation_errors() {
+    let mut parser = Parser::new();
+    // externs only exist in object files
+    assert_eq!(
+        parser.parse_program(".extern f\ncall f", "test").unwrap_err()[0].message,
+        "Symbol f is declared .extern, assemble with -c and link the object files"
+    );
+
+    parser.relocatable = true;
+    let errors = |parser: &mut Parser, program| {
+        parser.parse_program(program, "test").unwrap_err().into_iter().map(|d| d.message).collect::<Vec<_>>()
+    };
+    assert_eq!(
+        errors(&mut parser, ".global f, g\n.extern h\nf: nop\nh: nop"),
+        vec!["Global symbol g is not defined", "Extern symbol h is also defined here"]
+    );
+    assert_eq!(
+        errors(&mut parser, ".data\nx: .byte 1\n.byte x\n.text\nmov t0, !x * 2"),
+        vec![
+            "Expression cannot be relocated, it has to be an address plus or minus a constant",
+            "Only .word values can hold a relocatable address",
+        ]
+    );
+    assert!(parser.parse_program(".global 1", "test").is_err());
+    assert!(parser.parse_program(".extern", "test").is_err());
+
+    // differences between labels of the same section do not move
+    parser.parse_program(".data\na: .space 3\nb:\n.text\nmov t0, !b - a\nf: la t1, f + 1", "test").unwrap();
+    assert_eq!(parser.relocations.len(), 1);
+    assert_eq!(parser.relocations[0].addend, 3);
+}
+
+#[test]
+fn parse_conditionals() {
+    let program = "
+            .equ LAYOUT 2
+            .ifndef DEBUG
+            .equ DEBUG 0
+            .endif
+
+            .if DEBUG
+                mov t0, !1
+            .elif LAYOUT == 2 && defined_later
+                mov t0, !2
+            .elif LAYOUT == 2
+                mov t0, !3
+            .else
+                mov t0, !4
+            .endif
+
+            .if 0
+                this line is not assembled
+                .if undefined
+                .else
+                    neither is this one
+                .endif
+            .endif
+
+            .macro check reg
+            .ifdef DEBUG_CHECKS
+                cmp \\reg, !0
+            .endif
+            .endm
+            check t1
+            ";
+
+    let mut parser = Parser::new();
+    assert!(parser.parse_program(program, "test").is_err());
+
+    parser.defines = vec![parse_define("defined_later=0").unwrap()];
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), vec![0b0001101000000011]);
+
+    // only the condition of the branch that is taken has to be known
+    parser.defines = vec![parse_define("DEBUG").unwrap(), parse_define("DEBUG_CHECKS").unwrap()];
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), vec![0b0001101000000001, 0b0001001111001000]);
+
+    parser.defines = vec![parse_define("DEBUG=1 - 1").unwrap(), parse_define("defined_later").unwrap()];
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), vec![0b0001101000000010]);
+
+    assert!(parse_define("1x=2").is_err());
+    assert!(parse_define("X=(1").is_err());
+}
+
+#[test]
+fn conditional_errors() {
+    let mut parser = Parser::new();
+    assert_eq!(
+        errors(parser.parse_program("start:\n.if start\n.endif", "test")),
+        vec![
+            "error[E0013]: Condition has to be known where it is written, it cannot use code labels or later constants\n --> test:2:5\n  |\n2 | .if start\n  |     ^^^^^"
+        ]
+    );
+    assert_eq!(
+        errors(parser.parse_program(".if 1\nnop", "test")),
+        vec!["error[E0013]: Missing .endif\n --> test:1:1\n  |\n1 | .if 1\n  | ^^^^^"]
+    );
+
+    assert!(parser.parse_program(".endif", "test").is_err());
+    assert!(parser.parse_program(".else\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if 1\n.else\n.else\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if 1\n.else\n.elif 1\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if\n.endif", "test").is_err());
+    assert!(parser.parse_program(".ifdef 1\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if 1\n.endif 1", "test").is_err());
+    // the condition of a taken branch is the only one evaluated
+    assert_eq!(parser.parse_program(".if 1\n.elif missing\n.endif", "test"), Ok(()));
+    assert!(parser.parse_program(".if 0\n.elif missing\n.endif", "test").is_err());
+}
+
+#[test]
+fn parse_local_labels() {
+    let program = "
+            sort:   mov t0, !3
+            .loop:  dec t0
+                    jnz .loop
+                    jmp 1f
+            1:      ret
+            search:
+            .loop:  jmp .loop
+                    la t1, sort.loop
+            1:      jmp 1b
+                    jmp 1b
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(parser.label_map.get("sort.loop"), Some(&1));
+    assert_eq!(parser.label_map.get("search.loop"), Some(&5));
+    assert_eq!(parser.label_map.get(".loop"), None);
+
+    let binary = compile(&parser.get_program());
+    let expected: Vec<u16> = vec![
+        0b0001101000000011,
+        0b0001001000000001,
+        0b1000001111111101,
+        0b1001110111111111,
+        0b0100011110101000,
+        0b1001110111111101,
+        0b0011101001000000, 1,
+        0b1001110111111110,
+        0b1001110111111101,
+    ];
+    assert_eq!(binary, expected);
+
+    // labels of a macro body are renamed, numeric ones can repeat anyway
+    let program = ".macro spin\n.wait: jnz .wait\n1: jz 1b\n.endm\nmain: spin\nspin";
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(parser.label_map.get("spin.2.wait"), Some(&2));
+}
+
+#[test]
+fn local_label_errors() {
+    let mut parser = Parser::new();
+    assert_eq!(
+        errors(parser.parse_program("    jmp 1f\n1b: nop\n", "test")),
+        vec![
+            "error[E0002]: Invalid opcode 1b\n --> test:2:1\n  |\n2 | 1b: nop\n  | ^^^^^^^",
+            "error[E0007]: Numeric label 1 is not defined below 1f\n --> test:1:5\n  |\n1 |     jmp 1f\n  |     ^^^^^^",
+        ]
+    );
+    assert_eq!(
+        errors(parser.parse_program("    jmp .loop\n", "test")),
+        vec!["error[E0007]: Local label .loop has no label above it\n --> test:1:9\n  |\n1 |     jmp .loop\n  |         ^^^^^"]
+    );
+
+    assert!(parser.parse_program(".loop: nop", "test").is_err());
+    assert!(parser.parse_program("jmp 1b\n1: nop", "test").is_err());
+    assert!(parser.parse_program("f:\n.x: nop\n.x: nop", "test").is_err());
+    assert_eq!(parser.parse_program("f:\n.x: nop\ng:\n.x: nop\n.word 0b1", "test"), Ok(()));
+}
+
+#[test]
+fn parse_sections() {
+    let program = |org: u16| {
+        format!(
+            "
+                .section .rodata
+                msg:    .asciz \"hi\"
+                .data
+                count:  .word 3
+                .bss
+                buf:    .space 4
+                .text
+                start:  la t0, msg
+                        jmp end
+                        .org 0x{:x}
+                end:    ret
+                .rodata
+                        .byte 7
+                ",
+            org
+        )
+    };
+
+    // the data memory sections follow each other from address 0
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(&program(0x10), "test"), Ok(()));
+    assert_eq!(parser.data_label_map.get("count"), Some(&0));
+    assert_eq!(parser.data_label_map.get("msg"), Some(&2));
+    assert_eq!(parser.data_label_map.get("buf"), Some(&6));
+    assert_eq!(parser.data, [3, 0, b'h', b'i', 0, 7]);
+    assert_eq!(parser.label_map.get("end"), Some(&0x10));
+    let mut expected = vec![0x3a00, 2, 0b1001110000001100];
+    expected.resize(0x10, 0);
+    expected.push(0b0100011110101000);
+    assert_eq!(parser.words(), expected);
+
+    parser.layout = Layout::parse("text 0x100
+data 0x1000 0x100
+bss 0x2000").unwrap();
+    assert_eq!(parser.parse_program(&program(0x110), "test"), Ok(()));
+    assert_eq!(parser.label_map.get("start"), Some(&0x100));
+    assert_eq!(parser.data_label_map.get("count"), Some(&0x1000));
+    assert_eq!(parser.data_label_map.get("msg"), Some(&0x1002));
+    assert_eq!(parser.data_label_map.get("buf"), Some(&0x2000));
+    assert_eq!(parser.data.len(), 0x1006);
+    let placements = parser.placements.iter().map(|p| (p.section, p.address, p.size)).collect::<Vec<_>>();
+    assert_eq!(
+        placements,
+        vec![
+            (Section::Text, 0x100, 0x11),
+            (Section::Data, 0x1000, 2),
+            (Section::Rodata, 0x1002, 4),
+            (Section::Bss, 0x2000, 4),
+        ]
+    );
+
+    // a .org in data memory pads the section
+    assert_eq!(parser.parse_program(".data\n.byte 1\n.org 0x1004\nx: .byte 2", "test"), Ok(()));
+    assert_eq!(parser.data_label_map.get("x"), Some(&0x1004));
+    assert_eq!(parser.data[0x1000..], [1, 0, 0, 0, 2]);
+}
+
+#[test]
+fn section_errors() {
+    let mut parser = Parser::new();
+    parser.layout = Layout::parse("text 0 4\ndata 0x100 8\nrodata 0x102").unwrap();
+    assert_eq!(
+        errors(parser.parse_program(".data\n.word 1, 2\n.rodata\n.byte 2", "test")),
+        vec!["error[E0010]: .rodata at 0x0102-0x0102 overlaps .data at 0x0100-0x0103\n --> test:4:1\n  |\n4 | .byte 2\n  | ^^^^^^^"]
+    );
+    // only relaxed branches show whether the code before a .org fits
+    assert_eq!(parser.parse_program("jmp 1f\n.org 1\n1: nop", "test"), Ok(()));
+    assert_eq!(
+        errors(parser.parse_program("nop\nnop\n.org 1", "test")),
+        vec!["error[E0010]: .org 0x0001 is behind the end of the code before it at 0x0002\n --> test:3:1\n  |\n3 | .org 1\n  | ^^^^^^"]
+    );
+
+    assert!(parser.parse_program(".org 5", "test").is_err());
+    assert!(parser.parse_program("mov t0, !0x1234\nmov t0, !0x1234\nnop", "test").is_err());
+    assert!(parser.parse_program(".data\n.space 9", "test").is_err());
+    assert!(parser.parse_program(".data\n.byte 1\n.org 0x100", "test").is_err());
+    assert!(parser.parse_program(".bss\n.byte 1", "test").is_err());
+    assert!(parser.parse_program(".bss\n.space 1, 0xff", "test").is_err());
+    assert!(parser.parse_program(".section .heap", "test").is_err());
+    assert!(parser.parse_program(".section", "test").is_err());
+    assert!(parser.parse_program(".word 1\n.bss\n.org 0", "test").is_err());
+    assert!(parser.parse_program(".rodata 1", "test").is_err());
+
+    // object files leave the placement to the linker
+    parser.relocatable = true;
+    assert!(parser.parse_program(".org 0", "test").is_err());
+}
+
+#[test]
+fn parse_frames() {
+    let program = "
+            strcpy: .frame
+                    .arg dst
+                    .arg src
+                    .local c, 1
+                    .local saved
+                    .local buf, 3
+                    .endframe
+                    sub sp, !.frame_size
+            loop:   lod t0, [bp + src]
+                    lodb t1, [t0]
+                    savb t1, [bp + c]
+                    add t2, bp, !strcpy.buf
+                    sav t2, [bp + buf + 1]
+            copy:   .frame
+                    .arg src, 2, 8
+                    .local end, 2, -8
+                    .endframe
+                    lod t0, [bp + src]
+                    sav t0, [bp + end]
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    let slots = ["dst", "src", "c", "saved", "buf", "frame_size"].map(|name| parser.evaluate(&Expr::Symbol(format!("strcpy.{}", name), 0..0), false));
+    assert_eq!(slots, [4, 6, -1, -4, -8, 8].map(|offset| Some(Ok(offset))));
+
+    // the slots are only constants, the same as writing the offsets
+    let offsets = "
+                    sub sp, !8
+                    lod t0, [bp + !6]
+                    lodb t1, [t0]
+                    savb t1, [bp + !-1]
+                    add t2, bp, !-8
+                    sav t2, [bp + !-8 + 1]
+                    lod t0, [bp + !8]
+                    sav t0, [bp + !-8]
+            ";
+    let binary = compile(&parser.get_program());
+    assert_eq!(parser.parse_program(offsets, "test"), Ok(()));
+    assert_eq!(binary, compile(&parser.get_program()));
+}
+
+#[test]
+fn frame_errors() {
+    let mut parser = Parser::new();
+    assert_eq!(
+        errors(parser.parse_program("f: .frame\n.local i\n.local j, 2, -2\n.endframe", "test")),
+        vec!["error[E0014]: Local j at bp-2..bp-1 overlaps i at bp-2..bp-1\n --> test:3:8\n  |\n3 | .local j, 2, -2\n  |        ^^^^^^^^"]
+    );
+    assert_eq!(
+        errors(parser.parse_program("f: .frame\n.arg n, 2, 5\n.endframe", "test")),
+        vec!["error[E0014]: Argument n at bp+5..bp+6 is a word at an odd offset\n --> test:2:6\n  |\n2 | .arg n, 2, 5\n  |      ^^^^^^^"]
+    );
+    assert_eq!(
+        errors(parser.parse_program("f: .frame\n.arg n", "test")),
+        vec!["error[E0014]: Missing .endframe for the frame of f\n --> test:1:1\n  |\n1 | f: .frame\n  | ^^^^^^^^^"]
+    );
+
+    assert!(parser.parse_program(".frame\n.endframe", "test").is_err());
+    assert!(parser.parse_program("f: .local i", "test").is_err());
+    assert!(parser.parse_program("f: .endframe", "test").is_err());
+    assert!(parser.parse_program("f: .frame\n.arg n, 2, 2\n.endframe", "test").is_err());
+    assert!(parser.parse_program("f: .frame\n.local i, 2, 0\n.endframe", "test").is_err());
+    assert!(parser.parse_program("f: .frame\n.local i\n.local i\n.endframe", "test").is_err());
+    assert!(parser.parse_program("f: .frame\n.local t0\n.endframe", "test").is_err());
+    assert!(parser.parse_program("f: .frame\n.local i, 0\n.endframe", "test").is_err());
+    assert!(parser.parse_program("f: .frame\n.endframe\n.frame\n.endframe", "test").is_err());
+    // slots are only known inside [bp + ...]
+    assert!(parser.parse_program("f: .frame\n.local i\n.endframe\nmov t0, i", "test").is_err());
+
+    // the frame of f ends with it, g does not borrow its slots
+    let program = "f: .frame\n.local x\n.endframe\nlod t0, [bp + x]\n.loop: ret\ng: lod t1, [bp + x]\nret";
+    assert_eq!(
+        errors(parser.parse_program(program, "test")),
+        vec!["error[E0014]: x is not an argument or local, no .frame is active here\n --> test:6:18\n  |\n6 | g: lod t1, [bp + x]\n  |                  ^"]
+    );
+    assert_eq!(
+        errors(parser.parse_program("f: .frame\n.local x\n.endframe\nlod t0, [bp + y]", "test")),
+        vec!["error[E0014]: y is not an argument or local of the frame of f\n --> test:4:15\n  |\n4 | lod t0, [bp + y]\n  |               ^"]
+    );
+    assert!(parser.parse_program("f: .frame\n.local x\n.endframe\nret\n.proc g\nlod t1, [bp + x]\n.endproc", "test").is_err());
+    // a loop label before the ret keeps the frame
+    assert_eq!(parser.parse_program("f: .frame\n.local x\n.endframe\nloop: lod t0, [bp + x]\nret", "test"), Ok(()));
+}
+
+#[test]
+fn parse_procedures() {
+    let program = "
+            .proc triple
+                    .frame
+                    .arg n
+                    .endframe
+                    lod t2, [bp + n]
+                    jz .zero
+                    add t0, t2, t2
+                    return
+            .zero:  mov t0, !0
+            .endproc
+            ";
+    // the same, written out: only t2 is saved, and the frame has no locals to reserve
+    let written = "
+            triple: push bp
+                    mov bp, sp
+                    push t2
+                    lod t2, [bp + !4]
+                    jz zero
+                    add t0, t2, t2
+                    lod t2, [bp + !-2]
+                    mov sp, bp
+                    pop bp
+                    ret
+            zero:   mov t0, !0
+                    lod t2, [bp + !-2]
+                    mov sp, bp
+                    pop bp
+                    ret
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(written, "test"), Ok(()));
+    let binary = compile(&parser.get_program());
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), binary);
+    assert_eq!(parser.procedures, vec![Procedure { name: "triple".to_string(), start: 0, end: 15 }]);
+    assert_eq!(parser.label_map.get("triple.zero"), Some(&10));
+
+    // registers used by macros count too, and the locals are reserved below bp before saving them
+    let program = ".macro clobber\nmov t3, !1\n.endm\n.proc f\n.frame\n.local buf, 3\n.endframe\nclobber\n.endproc";
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    let written = "push bp\nmov bp, sp\nsub sp, !4\npush t3\nmov t3, !1\nlod t3, [bp + !-6]\nmov sp, bp\npop bp\nret";
+    let binary = compile(&parser.get_program());
+    assert_eq!(parser.parse_program(written, "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), binary);
+}
+
+#[test]
+fn procedure_errors() {
+    let mut parser = Parser::new();
+    assert_eq!(
+        errors(parser.parse_program("f: nop\n    return", "test")),
+        vec!["error[E0015]: return outside of .proc\n --> test:2:5\n  |\n2 |     return\n  |     ^^^^^^"]
+    );
+    assert_eq!(
+        errors(parser.parse_program(".proc f\nnop", "test")),
+        vec!["error[E0015]: Missing .endproc for f\n --> test:1:1\n  |\n1 | .proc f\n  | ^^^^^^^"]
+    );
+    assert_eq!(
+        errors(parser.parse_program(".proc f\nnop\n.frame\n.endframe\n.endproc", "test")),
+        vec!["error[E0014]: .frame has to come before the first instruction of f, whose prologue reserves it\n --> test:3:1\n  |\n3 | .frame\n  | ^^^^^^"]
+    );
+
+    assert!(parser.parse_program(".endproc", "test").is_err());
+    assert!(parser.parse_program(".proc f\n.proc g\n.endproc\n.endproc", "test").is_err());
+    assert!(parser.parse_program(".proc\n.endproc", "test").is_err());
+    assert!(parser.parse_program(".proc 1\n.endproc", "test").is_err());
+    assert!(parser.parse_program(".data\n.proc f\n.endproc", "test").is_err());
+    assert!(parser.parse_program(".proc f\nreturn t0\n.endproc", "test").is_err());
+    assert!(parser.parse_program(".proc f\n.if 0\n.endproc\n.endif", "test").is_err());
+}
+
+#[test]
+fn parse_structured() {
+    let program = "
+                    .while t1 <u t2
+                    .if t0 == !0
+                    add t0, t0, !1
+                    .elseif t0 > t1
+                    sub t0, t0, !1
+                    .else
+                    nop
+                    .endif
+                    add t1, t1, !1
+                    .endw
+            ";
+    // the same, written out: every condition branches on its opposite past its body
+    let written = "
+            top:    cmp t1, t2
+                    juge end
+                    cmp t0, !0
+                    jnz next
+                    add t0, t0, !1
+                    jmp done
+            next:   cmp t0, t1
+                    jle other
+                    sub t0, t0, !1
+                    jmp done
+            other:  nop
+            done:   add t1, t1, !1
+                    jmp top
+            end:
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(written, "test"), Ok(()));
+    let binary = compile(&parser.get_program());
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), binary);
+
+    // assembly time conditions still select the code, also inside a run time block
+    let program = ".equ DEBUG 0\n.if t0 >= t1\n.if DEBUG\nnop\n.else\nmov t0, t1\n.endif\n.endif";
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    let binary = compile(&parser.get_program());
+    assert_eq!(parser.parse_program("cmp t0, t1\njlt end\nmov t0, t1\nend:", "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), binary);
+
+    // loops in a .proc come after its prologue, frame slots can be compared
+    let program = ".proc f\n.frame\n.local i\n.endframe\n.while t0 <=u [bp + i]\nnop\n.endw\n.endproc";
+    assert!(parser.parse_program(program, "test").is_err());
+    let program = ".proc f\n.frame\n.local i\n.endframe\nlod t1, [bp + i]\n.while t0 <=u t1\nnop\n.endw\n.endproc";
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+    let binary = compile(&parser.get_program());
+    let written = "push bp\nmov bp, sp\nsub sp, !2\nlod t1, [bp + !-2]\ntop: cmp t0, t1\njugt end\nnop\njmp top\nend: mov sp, bp\npop bp\nret";
+    assert_eq!(parser.parse_program(written, "test"), Ok(()));
+    assert_eq!(compile(&parser.get_program()), binary);
+}
+
+#[test]
+fn structured_errors() {
+    let mut parser = Parser::new();
+    assert_eq!(
+        errors(parser.parse_program(".while t0 < t1\nnop\n.endif", "test")),
+        vec![
+            "error[E0013]: Missing .endw before .endif\n --> test:3:1\n  |\n3 | .endif\n  | ^^^^^^",
+            "error[E0013]: Missing .endw\n --> test:1:1\n  |\n1 | .while t0 < t1\n  | ^^^^^^^^^^^^^^",
+        ]
+    );
+    assert_eq!(
+        errors(parser.parse_program(".if t0 = t1\n.endif", "test")),
+        vec!["error[E0004]: Expected a comparison like `t0 < t1` or `t0 == !0`, found 't0 = t1'\n --> test:1:5\n  |\n1 | .if t0 = t1\n  |     ^^^^^^^"]
+    );
+    assert_eq!(
+        errors(parser.parse_program("nop\n.if t0 != t1\nnop", "test")),
+        vec!["error[E0013]: Missing .endif\n --> test:2:1\n  |\n2 | .if t0 != t1\n  | ^^^^^^^^^^^^"]
+    );
+
+    assert!(parser.parse_program(".endw", "test").is_err());
+    assert!(parser.parse_program(".if t0 ==u t1\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if t0 < u t1\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if !1 < t0\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if t0 < t1\n.else\n.else\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if t0 < t1\n.else\n.elseif t0 > t1\n.endif", "test").is_err());
+    assert!(parser.parse_program(".if t0 < t1\n.if 1\n.endif", "test").is_err());
+    assert!(parser.parse_program(".data\n.while t0 < t1\n.endw", "test").is_err());
+    assert!(parser.parse_program(".while t0 < t1\n.endw t0", "test").is_err());
+}
+
+#[test]
+fn parse_comments_and_literals() {
+    let program = "
+            .data
+            msg:    .ascii \"a;b // c\"    ; the comment starts after the string
+            chars:  .byte 'A', '\\n', '\\''
+            .text
+            Main:   MOV t0, !'a' - 1        ; load
+                    add t0, t0, T1          // sum
+                    jmp Main
+            ";
+
+    let mut parser = Parser::new();
+    assert_eq!(parser.parse_program(program, "test"), Ok(()));
+
+    assert_eq!(parser.data, b"a;b // cA\n'".to_vec());
+    assert_eq!(parser.label_map.get("Main"), Some(&0));
+    assert_eq!(parser.label_map.get("main"), None);
+    assert_eq!(
+        parser.get_program(),
+        vec![
+            Instruction::Isa(Isa::Dp { op: AluOp::Mov, td: Register::T0, tn: Register::T0, src2: Src2::WideImm16(96) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Add, td: Register::T0, tn: Register::T0, src2: Src2::Reg(Register::T1) }),
+            Instruction::Isa(Isa::Branch { cond: Condition::Mp, offset: Offset::SignImm9(-5) }),
+        ]
+    );
+
+    // labels are case-sensitive, registers and mnemonics are not
+    assert_eq!(
+        errors(parser.parse_program("Main: jmp main", "test")),
+        vec!["error[E0007]: Label main not found\n --> test:1:11\n  |\n1 | Main: jmp main\n  |           ^^^^"]
+    );
+    assert_eq!(
+        errors(parser.parse_program("\n  mov t0, #1", "test")),
+        vec!["error[E0001]: Unexpected character '#'\n --> test:2:11\n  |\n2 |   mov t0, #1\n  |           ^"]
+    );
+    assert!(parser.parse_program(".data\n.ascii \"abc", "test").is_err());
+    assert!(parser.parse_program(".data\n.byte 'ab'", "test").is_err());
+    assert!(parser.parse_program(".data\n.ascii abc", "test").is_err());
+}
+
+#[test]
+fn diagnostics() {
+    let program = "
+            .equ N 20
+            mov t9, t0
+            frob t0
+            shl t0, t0, !N
+            add t0, t0, !70000
+            jmp nowhere
+            ";
+
+    // every bad line is reported, not just the first one
+    let mut parser = Parser::new();
+    let diagnostics = parser.parse_program(program, "test").unwrap_err();
+    let found = diagnostics.iter().map(|d| (d.code, d.location.line, d.span.clone())).collect::<Vec<_>>();
+    assert_eq!(
+        found,
+        vec![
+            (Code::InvalidRegister, 3, Some(16..18)),
+            (Code::UnknownInstruction, 4, Some(12..19)),
+            (Code::OutOfRange, 6, Some(25..30)),
+            (Code::UndefinedSymbol, 7, Some(16..23)),
+        ]
+    );
+
+    // warnings do not stop the assembly
+    parser.parse_program("shl t0, t0, !20\nshr t1, t1, !3", "test").unwrap();
+    assert_eq!(parser.diagnostics.len(), 1);
+    assert_eq!(
+        parser.diagnostics[0].to_string(),
+        "warning[E0006]: Shift amount 20 is out of range, the hardware shifts by 4\n --> test:1:1\n  |\n1 | shl t0, t0, !20\n  | ^^^^^^^^^^^^^^^"
+    );
+}
+
+// writes `files` to a fresh directory and returns its path
+fn temp_files(name: &str, files: &[(&str, &[u8])]) -> std::path::PathBuf {
+    let dir = env::temp_dir().join(format!("lunacore_{}_{}", name, std::process::id()));
+    for (file, contents) in files {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn parse_includes() {
+    let dir = temp_files(
+        "include",
+        &[
+            ("main.luna", b".include \"defs.luna\"\n.include \"frame.luna\"\nenter !SIZE\n.data\nbin: .incbin \"blob.bin\", 1, 2"),
+            ("defs.luna", b".equ SIZE 4"),
+            ("lib/frame.luna", b".macro enter n\npush bp\nmov bp, sp\nsub sp, \\n\n.endm"),
+            ("blob.bin", &[1, 2, 3, 4]),
+        ],
+    );
+
+    let main = dir.join("main.luna");
+    let input = fs::read_to_string(&main).unwrap();
+    let mut parser = Parser::new();
+    assert!(parser.parse_program(&input, main.to_str().unwrap()).is_err());
+
+    parser.include_paths = vec![dir.join("lib")];
+    parser.parse_program(&input, main.to_str().unwrap()).unwrap();
+    assert_eq!(
+        parser.get_program(),
+        vec![
+            Instruction::Isa(Isa::Mem { op: MemOp::Push, byte: false, td: Register::Bp, tn: Register::Sp, src2: Src2::Reg(Register::T0) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Mov, td: Register::Bp, tn: Register::T0, src2: Src2::Reg(Register::Sp) }),
+            Instruction::Isa(Isa::Dp { op: AluOp::Sub, td: Register::Sp, tn: Register::Sp, src2: Src2::ZeroImm3(4) }),
+        ]
+    );
+    assert_eq!(parser.data, vec![2, 3]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn include_errors() {
+    let dir = temp_files(
+        "include_errors",
+        &[
+            ("a.luna", b".include \"b.luna\""),
+            ("b.luna", b"nop\n.include \"a.luna\""),
+            ("bad.luna", b"nop\nmov t9, t0"),
+            ("blob.bin", &[1, 2]),
+        ],
+    );
+    let file = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    let mut parser = Parser::new();
+    let diagnostics = parser.parse_program(".include \"a.luna\"", &file("main.luna")).unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, Code::File);
+    assert_eq!(diagnostics[0].message, format!("Circular include of {}", file("a.luna")));
+    assert_eq!(&*diagnostics[0].location.file, file("b.luna"));
+    assert_eq!(
+        diagnostics[0].notes,
+        vec![
+            format!("included from {}:1", file("a.luna")),
+            format!("included from {}:1", file("main.luna")),
+        ]
+    );
+
+    let diagnostics = parser.parse_program("\n.include \"bad.luna\"", &file("main.luna")).unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].code, Code::InvalidRegister);
+    assert_eq!((&*diagnostics[0].location.file, diagnostics[0].location.line), (file("bad.luna").as_str(), 2));
+    assert_eq!(diagnostics[0].span, Some(4..6));
+    assert_eq!(diagnostics[0].notes, vec![format!("included from {}:2", file("main.luna"))]);
+
+    assert!(parser.parse_program(".include \"missing.luna\"", &file("main.luna")).is_err());
+    assert!(parser.parse_program(".include missing.luna", &file("main.luna")).is_err());
+    assert!(parser.parse_program(".data\n.incbin \"blob.bin\", 1, 2", &file("main.luna")).is_err());
+    assert!(parser.parse_program(".incbin \"blob.bin\"", &file("main.luna")).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -52,21 +52,6 @@ impl fmt::Debug for RegFile {
     }
 }
 
-pub fn reg_to_string(reg: u16) -> String {
-    let result = match reg {
-        0 => "T0",
-        1 => "T1",
-        2 => "T2",
-        3 => "T3",
-        4 => "BP",
-        5 => "SP",
-        6 => "PC",
-        7 => "IN",
-        _ => panic!(),
-    };
-    result.to_string()
-}
-
 pub const MEMORY_SIZE: usize = 1 << 16; // 2^16 = 65536 for a 16-bit physical address space
 pub struct ByteRAM {
     pub data: [u8; MEMORY_SIZE],
@@ -109,9 +94,11 @@ impl ByteRAM {
         self.data[..load_size].copy_from_slice(&binary_data[..load_size]);
     }
 
+    // whitespace is ignored, so $readmemb images with one value per line load as well
     pub fn load_binary_str(&mut self, binary_string: &str) {
+        let binary_string = binary_string.split_whitespace().collect::<String>();
         assert!(
-            binary_string.len() % 8 == 0,
+            binary_string.len().is_multiple_of(8),
             "Binary string must be 8-bit aligned."
         );
 
@@ -159,9 +146,11 @@ impl WordROM {
         self.data[..load_size].copy_from_slice(&binary_data[..load_size]);
     }
 
+    // whitespace is ignored, so $readmemb images with one value per line load as well
     pub fn load_binary_str(&mut self, binary_string: &str) {
+        let binary_string = binary_string.split_whitespace().collect::<String>();
         assert!(
-            binary_string.len() % 16 == 0,
+            binary_string.len().is_multiple_of(16),
             "Binary string must be 16-bit aligned."
         );
 
@@ -252,7 +241,7 @@ impl CondUnit {
 
 // bit utils
 fn is_word_aligned(addr: u16) -> bool {
-    addr % 2 == 0
+    addr.is_multiple_of(2)
 }
 
 fn get_lsb(data: u16) -> u8 {
@@ -285,4 +274,15 @@ mod test {
     fn test_into_word() {
         assert_eq!(into_word(0x12, 0x34), 0x1234);
     }
+
+    #[test]
+    fn test_load_binary_str() {
+        let mut rom = WordROM::new();
+        rom.load_binary_str("0001101000000011\n1000001111111100\n");
+        assert_eq!(rom.read(0), [0x1a03, 0x83fc]);
+
+        let mut ram = ByteRAM::new();
+        ram.load_binary_str("00000001 11111111");
+        assert_eq!(ram.read(0, 0), 0xff01);
+    }
 }
@@ -1,5 +1,8 @@
 use crate::components::*;
+use crate::debuginfo::DebugInfo;
+use lunacore_isa::{is_wide, AluOp, Condition, Instruction, MemOp, Offset, Register, Src2};
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub regs: RegFile,
     pub imem: WordROM,
@@ -10,12 +13,15 @@ pub struct CPU {
     // datapath
     pub alu_flags: Flags,
     pub instr: [u16; 2],
+    pub decoded: Option<Instruction>,
     pub wide: bool,
     pub next_wide: bool,
     pub pc_overwritten: bool,
 
     pub run: bool,
     pub debug: bool,
+    // source locations for messages, when the program came with a .lunadbg file
+    pub debug_info: Option<DebugInfo>,
 }
 
 impl CPU {
@@ -29,132 +35,72 @@ impl CPU {
 
             alu_flags: Flags::new(),
             instr: [0, 0],
+            decoded: None,
             wide: false,
             next_wide: false,
             pc_overwritten: false,
 
             run: true,
             debug: false,
+            debug_info: None,
         }
     }
 
     pub fn debug_state(&self) {
-        println!("");
+        println!();
         println!("{:?}", self.regs);
         println!("{:?}", self.cond_unit.flags);
         println!();
     }
 
     pub fn debug_instruction(&self) {
-        let op = get_bits(self.instr[0], 15, 14);
-
-        let instr_str = match op {
-            // DP
-            0b00 => {
-                let imm = get_bits(self.instr[0], 13, 12);
-                let cmd = get_bits(self.instr[0], 11, 9);
-                let td = get_bits(self.instr[0], 8, 6);
-                let tn = get_bits(self.instr[0], 5, 3);
-                let src2 = get_bits(self.instr[0], 2, 0);
-
-
-                let src2_string = match imm {
-                    0b00 => reg_to_string(src2),
-                    0b01 => format!("!{}", src2),
-                    0b10 => format!("!{}", imm_extend(src2, 3, 1) as i16),
-                    0b11 => format!("!0x{:04x}", self.instr[1]),
-                    _ => panic!(),
-                };
+        let reg = |r: Register| r.name().to_uppercase();
 
-                match cmd {
-                    0b000 => format!("ADD  {}, {}, {}", reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b001 => format!("SUB  {}, {}, {}", reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b010 => format!("AND  {}, {}, {}", reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b011 => format!("OR   {}, {}, {} ", reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b100 => format!("XOR  {}, {}, {}", reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b101 => format!("MOV  {}, {}", reg_to_string(td), src2_string),
-                    0b110 => format!("SHL  {}, {}, {}", reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b111 => format!("SHR  {}, {}, {}", reg_to_string(td), reg_to_string(tn), src2_string),
-                    _ => panic!(),
-                }
+        let instr_str = match self.decoded {
+            Some(Instruction::Dp { op: AluOp::Mov, td, src2, .. }) => format!("MOV  {}, {}", reg(td), src2_string(src2, "!")),
+            Some(Instruction::Dp { op, td, tn, src2 }) => {
+                format!("{:<4} {}, {}, {}", op.mnemonic().to_uppercase(), reg(td), reg(tn), src2_string(src2, "!"))
             }
-            // MEM
-            0b01 => {
-                let imm = get_bits(self.instr[0], 13, 12);
-                let b = get_bit(self.instr[0], 11);
-                let sl = get_bits(self.instr[0], 10, 9);
-                let td = get_bits(self.instr[0], 8, 6);
-                let tn = get_bits(self.instr[0], 5, 3);
-                let src2 = get_bits(self.instr[0], 2, 0);
-
-                let src2_string = match imm {
-                    0b00 => reg_to_string(src2) + "]",
-                    0b01 => format!("{}]", src2),
-                    0b10 => format!("{}]", imm_extend(src2, 3, 1) as i16),
-                    0b11 => format!("0x{:04x}]", self.instr[1]),
-                    _ => panic!(),
-                };
-
-                let push_src = match imm {
-                    0b00 => reg_to_string(td) + "",
-                    0b01 => format!("{}", src2),
-                    0b10 => format!("{}", imm_extend(src2, 3, 1) as i16),
-                    0b11 => format!("0x{:04x}", self.instr[1]),
-                    _ => panic!(),
-                };
-
-                let b_str = match b {
-                    0 => " ",
-                    1 => "B",
-                    _ => panic!(),
-                };
-
-                match sl {
-                    0b00 => format!("SAV{} {}, [{} + {}", b_str, reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b01 => format!("LOD{} {}, [{} + {}", b_str, reg_to_string(td), reg_to_string(tn), src2_string),
-                    0b10 => format!("PUSH{} {}", b_str, push_src),
-                    0b11 => format!("POP{} {}             ", b_str, reg_to_string(td)),
-                    _ => panic!(),
+            Some(Instruction::Mem { op, byte, td, tn, src2 }) => {
+                let b_str = if byte { "B" } else { " " };
+                match op {
+                    MemOp::Sav | MemOp::Lod => {
+                        let name = op.mnemonic().to_uppercase();
+                        format!("{}{} {}, [{} + {}]", name, b_str, reg(td), reg(tn), src2_string(src2, ""))
+                    }
+                    // push stores td, unless src2 is an immediate
+                    MemOp::Push => match src2 {
+                        Src2::Reg(_) => format!("PUSH{} {}", b_str, reg(td)),
+                        imm => format!("PUSH{} {}", b_str, src2_string(imm, "")),
+                    },
+                    MemOp::Pop => format!("POP{} {}             ", b_str, reg(td)),
                 }
             }
-            // BRANCH
-            0b10 => {
-                let w = get_bit(self.instr[0], 13);
-                let cond = get_bits(self.instr[0], 12, 9);
-                let offset = get_bits(self.instr[0], 8, 0);
-
-                let offset_string = match w {
-                    0 => format!("{}        => [0x{:04x}]", sign_extend(offset, 9) as i16, self.regs.pc + sign_extend(offset, 9)),
-                    1 => format!("0x{:04x}    => [0x{:04x}]", self.instr[1], self.regs.pc + self.instr[1]),
-                    _ => panic!(),
+            Some(Instruction::Branch { cond, offset }) => {
+                let target = self.regs.pc.wrapping_add(offset.value());
+                let offset_string = match offset {
+                    Offset::SignImm9(offset) => format!("{}        => [0x{:04x}]", offset, target),
+                    Offset::WideImm16(offset) => format!("0x{:04x}    => [0x{:04x}]", offset, target),
                 };
-
-                let cond_str = match cond {
-                    0b0000 => "Z  ",
-                    0b0001 => "NZ ",
-                    0b0010 => "LT ",
-                    0b0011 => "LE ",
-                    0b0100 => "GT ",
-                    0b0101 => "GE ",
-                    0b0110 => "ULT",
-                    0b0111 => "ULE",
-                    0b1000 => "UGT",
-                    0b1001 => "UGE",
-                    0b1010 => "MI ",
-                    0b1011 => "PL ",
-                    0b1100 => "VS ",
-                    0b1101 => "VC ",
-                    0b1110 => "MP ",
-                    0b1111 => "NV ",
-                    _ => panic!(),
-                };
-
-                format!("J{} {}", cond_str, offset_string)
+                format!("{:<4} {}", cond.mnemonic().to_uppercase(), offset_string)
             }
-            _ => panic!(),
+            None => format!("??? 0x{:04x}", self.instr[0]),
         };
 
-        println!("PC=0x{:04x}: {}", self.pc, instr_str);
+        // instructions expanded from a pseudo-instruction also show what was written
+        let pseudo = self.debug_info.as_ref().and_then(|info| info.line_at(self.pc)?.pseudo.as_ref());
+        match pseudo {
+            Some(source) => println!("{}: {:<32} ({})", self.location(self.pc), instr_str, source),
+            None => println!("{}: {}", self.location(self.pc), instr_str),
+        }
+    }
+
+    // the source line and label of an address, or the bare address without debug info
+    pub fn location(&self, pc: u16) -> String {
+        match self.debug_info.as_ref().and_then(|info| info.describe(pc)) {
+            Some(location) => format!("{} [0x{:04x}]", location, pc),
+            None => format!("PC=0x{:04x}", pc),
+        }
     }
 
     pub fn fetch(&mut self) {
@@ -162,9 +108,11 @@ impl CPU {
     }
 
     pub fn decode(&mut self) {
+        self.decoded = Instruction::decode(&self.instr);
+
         // wide and next_wide logic
-        self.wide = self.is_wide(self.instr[0]);
-        self.next_wide = self.is_wide(self.instr[1]) && !self.wide;
+        self.wide = is_wide(self.instr[0]);
+        self.next_wide = is_wide(self.instr[1]) && !self.wide;
         if self.debug && self.wide {
             print!("W ");
         }
@@ -174,22 +122,16 @@ impl CPU {
     }
 
     pub fn execute(&mut self) {
-        let op = get_bits(self.instr[0], 15, 14);
-
-        match op {
-            0b00 => self.dp(),
-            0b01 => {
-                let sl = get_bits(self.instr[0], 10, 9);
-                match sl {
-                    0b00 => self.sav(),
-                    0b01 => self.lod(),
-                    0b10 => self.push(),
-                    0b11 => self.pop(),
-                    _ => panic!("Op=11 instructions not implemented yet!"),
-                }
-            }
-            0b10 => self.branch(),
-            _ => panic!(),
+        match self.decoded {
+            Some(Instruction::Dp { op, td, tn, src2 }) => self.dp(op, td, tn, src2),
+            Some(Instruction::Mem { op, byte, td, tn, src2 }) => match op {
+                MemOp::Sav => self.sav(byte, td, tn, src2),
+                MemOp::Lod => self.lod(byte, td, tn, src2),
+                MemOp::Push => self.push(byte, td, tn, src2),
+                MemOp::Pop => self.pop(byte, td, tn),
+            },
+            Some(Instruction::Branch { cond, offset }) => self.branch(cond, offset),
+            None => panic!("Op=11 instructions not implemented yet!"),
         }
     }
 
@@ -204,21 +146,6 @@ impl CPU {
         self.alu_flags = Flags::new();
     }
 
-    fn is_wide(&self, instr: u16) -> bool {
-        let instr_op = get_bits(instr, 15, 14);
-        match instr_op {
-            0b00..=0b01 => {
-                let next_imm = get_bits(instr, 13, 12);
-                next_imm == 0b11
-            }
-            0b10 => {
-                let w = get_bit(instr, 13);
-                w == 1
-            }
-            _ => false,
-        }
-    }
-
     fn alu(&mut self, a: u16, b: u16, aluop: u16) -> u16 {
         let result: u32 = match aluop {
             0b000 => a as u32 + b as u32,        // Addition
@@ -259,30 +186,25 @@ impl CPU {
         result as u16
     }
 
-    fn dp(&mut self) {
-        let imm = get_bits(self.instr[0], 13, 12);
-        let cmd = get_bits(self.instr[0], 11, 9);
-
-        let td = get_bits(self.instr[0], 8, 6);
-        let tn = get_bits(self.instr[0], 5, 3);
-        let src2 = get_bits(self.instr[0], 2, 0);
+    // the second operand, a register or an extended immediate
+    fn operand(&self, src2: Src2) -> u16 {
+        match src2 {
+            Src2::Reg(r) => self.regs.read(r.bits()),
+            imm => imm.imm().unwrap(),
+        }
+    }
 
-        let src_a = self.regs.read(tn);
-        let src_b = match imm {
-            0b00 => self.regs.read(src2),
-            0b01 => imm_extend(src2, 3, 0),
-            0b10 => imm_extend(src2, 3, 1),
-            0b11 => self.instr[1],
-            _ => panic!(),
-        };
+    fn dp(&mut self, op: AluOp, td: Register, tn: Register, src2: Src2) {
+        let src_a = self.regs.read(tn.bits());
+        let src_b = self.operand(src2);
 
-        let result = self.alu(src_a, src_b, cmd);
+        let result = self.alu(src_a, src_b, op.bits());
 
         // write flags and results
         self.cond_unit.write_flags(&self.alu_flags);
-        self.regs.write(td, result);
+        self.regs.write(td.bits(), result);
 
-        if td == 0b110 {
+        if td == Register::Pc {
             self.pc = result;
             self.pc_overwritten = true;
 
@@ -292,54 +214,28 @@ impl CPU {
         }
     }
 
-    fn sav(&mut self) {
-        let imm = get_bits(self.instr[0], 13, 12);
-        let b = get_bit(self.instr[0], 11);
-
-        let td = get_bits(self.instr[0], 8, 6);
-        let tn = get_bits(self.instr[0], 5, 3);
-        let src2 = get_bits(self.instr[0], 2, 0);
+    fn sav(&mut self, byte: bool, td: Register, tn: Register, src2: Src2) {
+        let src_a = self.regs.read(tn.bits());
+        let src_b = self.operand(src2);
 
-        let src_a = self.regs.read(tn);
-        let src_b = match imm {
-            0b00 => self.regs.read(src2),
-            0b01 => imm_extend(src2, 3, 0),
-            0b10 => imm_extend(src2, 3, 1),
-            0b11 => self.instr[1],
-            _ => panic!(),
-        };
-
-        let result = self.alu(src_a, src_b, 0b000);
+        let result = self.alu(src_a, src_b, AluOp::Add.bits());
         let addr = result;
 
-        let write_data = self.regs.read(td);
-        self.dmem.write(addr, write_data, b);
+        let write_data = self.regs.read(td.bits());
+        self.dmem.write(addr, write_data, byte as u16);
     }
 
-    fn lod(&mut self) {
-        let imm = get_bits(self.instr[0], 13, 12);
-        let b = get_bit(self.instr[0], 11);
-
-        let td = get_bits(self.instr[0], 8, 6);
-        let tn = get_bits(self.instr[0], 5, 3);
-        let src2 = get_bits(self.instr[0], 2, 0);
-
-        let src_a = self.regs.read(tn);
-        let src_b = match imm {
-            0b00 => self.regs.read(src2),
-            0b01 => imm_extend(src2, 3, 0),
-            0b10 => imm_extend(src2, 3, 1),
-            0b11 => self.instr[1],
-            _ => panic!(),
-        };
+    fn lod(&mut self, byte: bool, td: Register, tn: Register, src2: Src2) {
+        let src_a = self.regs.read(tn.bits());
+        let src_b = self.operand(src2);
 
-        let result = self.alu(src_a, src_b, 0b000);
+        let result = self.alu(src_a, src_b, AluOp::Add.bits());
         let addr = result;
 
-        let read_data = self.dmem.read(addr, b);
-        self.regs.write(td, read_data);
+        let read_data = self.dmem.read(addr, byte as u16);
+        self.regs.write(td.bits(), read_data);
 
-        if td == 0b110 {
+        if td == Register::Pc {
             self.pc = read_data;
             self.pc_overwritten = true;
 
@@ -349,61 +245,40 @@ impl CPU {
         }
     }
 
-    fn push(&mut self) {
-        let imm = get_bits(self.instr[0], 13, 12);
-        let b = get_bit(self.instr[0], 11);
+    fn push(&mut self, byte: bool, td: Register, tn: Register, src2: Src2) {
+        assert_eq!(tn, Register::Sp); // rn = sp
 
-        let td = get_bits(self.instr[0], 8, 6);
-        let tn = get_bits(self.instr[0], 5, 3);
-        assert_eq!(tn, 0b101); // rn = sp
-        let src2 = get_bits(self.instr[0], 2, 0);
+        let sp = self.regs.read(tn.bits());
+        let src_b: u16 = if byte { 1 } else { 2 };
 
-        let sp = self.regs.read(tn);
-        let src_b: u16 = match b {
-            0 => 2,
-            1 => 1,
-            _ => panic!(),
-        };
-
-        let result = self.alu(sp, src_b, 0b001);
+        let result = self.alu(sp, src_b, AluOp::Sub.bits());
         let addr = result;
         let new_sp = result;
 
-        let write_data = match imm {
-            0b00 => self.regs.read(td),
-            0b01 => imm_extend(src2, 3, 0),
-            0b10 => imm_extend(src2, 3, 1),
-            0b11 => self.instr[1],
-            _ => panic!(),
+        let write_data = match src2 {
+            Src2::Reg(_) => self.regs.read(td.bits()),
+            imm => self.operand(imm),
         };
-        self.dmem.write(addr, write_data, b);
-        self.regs.write(tn, new_sp);
+        self.dmem.write(addr, write_data, byte as u16);
+        self.regs.write(tn.bits(), new_sp);
     }
 
-    fn pop(&mut self) {
-        let b = get_bit(self.instr[0], 11);
+    // src2 is not used in pop
+    fn pop(&mut self, byte: bool, td: Register, tn: Register) {
+        assert_eq!(tn, Register::Sp); // rn = sp
 
-        let td = get_bits(self.instr[0], 8, 6);
-        let tn = get_bits(self.instr[0], 5, 3);
-        assert_eq!(tn, 0b101); // rn = sp
-        let _src2 = get_bits(self.instr[0], 2, 0); // src2 not used in pop
+        let sp = self.regs.read(tn.bits());
+        let src_b: u16 = if byte { 1 } else { 2 };
 
-        let sp = self.regs.read(tn);
-        let src_b: u16 = match b {
-            0 => 2,
-            1 => 1,
-            _ => panic!(),
-        };
-
-        let result = self.alu(sp, src_b, 0b000);
+        let result = self.alu(sp, src_b, AluOp::Add.bits());
         let addr = sp;
         let new_sp = result;
 
-        let read_data = self.dmem.read(addr, b);
-        self.regs.write(td, read_data);
-        self.regs.write(tn, new_sp);
+        let read_data = self.dmem.read(addr, byte as u16);
+        self.regs.write(td.bits(), read_data);
+        self.regs.write(tn.bits(), new_sp);
 
-        if td == 0b110 {
+        if td == Register::Pc {
             self.pc = read_data;
             self.pc_overwritten = true;
 
@@ -413,18 +288,10 @@ impl CPU {
         }
     }
 
-    fn branch(&mut self) {
-        let cond = get_bits(self.instr[0], 12, 9);
-        let imm9 = get_bits(self.instr[0], 8, 0);
-
-        let offset = match self.wide {
-            true => self.instr[1],
-            false => sign_extend(imm9, 9),
-        };
-
+    fn branch(&mut self, cond: Condition, offset: Offset) {
         // do jmp if check = true
-        if self.cond_unit.check(cond) {
-            let result = self.alu(self.regs.pc, offset, 0b000);
+        if self.cond_unit.check(cond.bits()) {
+            let result = self.alu(self.regs.pc, offset.value(), AluOp::Add.bits());
             self.pc = result;
             self.pc_overwritten = true;
         }
@@ -438,64 +305,22 @@ impl CPU {
     }
 }
 
-// binary utils
-fn imm_extend(data: u16, len: u16, ext_value: u16) -> u16 {
-    if ext_value == 0 {
-        data
-    } else if ext_value == 1 {
-        let bitmask: u16 = ((1 << len) - 1) ^ u16::MAX;
-        data | bitmask
-    } else {
-        panic!()
+// src2 as the debug output shows it, immediates from the second word in hex
+fn src2_string(src2: Src2, prefix: &str) -> String {
+    match src2 {
+        Src2::Reg(r) => r.name().to_uppercase(),
+        Src2::ZeroImm3(i) => format!("{}{}", prefix, i),
+        Src2::OneImm3(i) => format!("{}{}", prefix, i),
+        Src2::WideImm16(i) => format!("{}0x{:04x}", prefix, i),
     }
 }
 
-fn sign_extend(data: u16, len: u16) -> u16 {
-    let sign = data >> (len - 1);
-    imm_extend(data, len, sign)
-}
-
-fn get_bit(data: u16, pos: u16) -> u16 {
-    (data >> pos) & 1
-}
-
-fn get_bits(data: u16, end: u16, start: u16) -> u16 {
-    let shifted_data: u16 = data >> start;
-    let bitmask: u16 = (1 << (end - start + 1)) - 1;
-    shifted_data & bitmask
-}
-
 #[cfg(test)]
 #[allow(overflowing_literals)]
 #[allow(arithmetic_overflow)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_imm_extend() {
-        assert_eq!(imm_extend(0b1011, 4, 0), 0b00000000_00001011);
-        assert_eq!(imm_extend(0b000, 3, 1), 0b11111111_11111000);
-    }
-
-    #[test]
-    fn test_sign_extend() {
-        assert_eq!(sign_extend(0b011, 3), 0b00000000_00000011);
-        assert_eq!(sign_extend(0b101, 3), 0b11111111_11111101);
-    }
-
-    #[test]
-    fn test_get_bit() {
-        assert_eq!(get_bit(0b1010_1010, 3), 1);
-        assert_eq!(get_bit(0b1010_1010, 2), 0);
-        assert_eq!(get_bit(0b0000_0010, 1), 1);
-        assert_eq!(get_bit(0b1111_1110, 0), 0);
-    }
-
-    #[test]
-    fn test_get_bits() {
-        assert_eq!(get_bits(0b1111_0000, 7, 4), 0b1111);
-    }
-
     #[test]
     fn test_alu_add() {
         let mut cpu = CPU::new();
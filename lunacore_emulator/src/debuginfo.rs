@@ -0,0 +1,203 @@
+// reads the debug information (.lunadbg) written by `compiler -g`:
+//
+//   lunadbg 1
+//   file <index> <name>
+//   symbol <text|data> <address> <name>
+//   proc <start> <end> <name>
+//   line <pc> <words> <file index> <line> <column>
+//   pseudo <pc> <source>
+
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    // text symbols sorted by address
+    pub symbols: Vec<(u16, String)>,
+    pub data_symbols: Vec<(u16, String)>,
+    // the words of every .proc, the end is exclusive
+    pub procedures: Vec<(u16, u16, String)>,
+    // sorted by pc
+    pub lines: Vec<LineInfo>,
+}
+
+pub struct LineInfo {
+    pub pc: u16,
+    // 2 for wide instructions, whose second word holds the immediate
+    pub words: u16,
+    pub file: usize,
+    pub line: usize,
+    // the pseudo-instruction this instruction was expanded from
+    pub pseudo: Option<String>,
+}
+
+impl DebugInfo {
+    pub fn parse(input: &str) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo {
+            files: Vec::new(),
+            symbols: Vec::new(),
+            data_symbols: Vec::new(),
+            procedures: Vec::new(),
+            lines: Vec::new(),
+        };
+
+        let mut lines = input.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        match lines.next() {
+            Some((_, "lunadbg 1")) => (),
+            _ => return Err("Not a LunaCore debug info file".to_string()),
+        }
+
+        for (i, line) in lines {
+            let error = || format!("line {}: Invalid entry '{}'", i + 1, line.trim());
+            let hex = |field: &str| u16::from_str_radix(field, 16).map_err(|_| error());
+            let number = |field: &str| field.parse::<usize>().map_err(|_| error());
+            let (kind, rest) = line.split_once(' ').ok_or_else(error)?;
+
+            match kind {
+                "file" => {
+                    let (_, name) = rest.split_once(' ').ok_or_else(error)?;
+                    info.files.push(name.to_string());
+                }
+                "symbol" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["text", address, name] => info.symbols.push((hex(address)?, name.to_string())),
+                    ["data", address, name] => info.data_symbols.push((hex(address)?, name.to_string())),
+                    _ => return Err(error()),
+                },
+                "proc" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                    [start, end, name] => info.procedures.push((hex(start)?, hex(end)?, name.to_string())),
+                    _ => return Err(error()),
+                },
+                "line" => match rest.split_whitespace().collect::<Vec<_>>()[..] {
+                    // the column is only of use to editors
+                    [pc, words, file, line, column] => {
+                        number(column)?;
+                        info.lines.push(LineInfo {
+                            pc: hex(pc)?,
+                            words: number(words)? as u16,
+                            file: number(file)?,
+                            line: number(line)?,
+                            pseudo: None,
+                        })
+                    }
+                    _ => return Err(error()),
+                },
+                "pseudo" => {
+                    let (pc, source) = rest.split_once(' ').ok_or_else(error)?;
+                    let pc = hex(pc)?;
+                    match info.lines.iter_mut().find(|line| line.pc == pc) {
+                        Some(line) => line.pseudo = Some(source.to_string()),
+                        None => return Err(error()),
+                    }
+                }
+                _ => return Err(error()),
+            }
+        }
+
+        if info.lines.iter().any(|line| line.file >= info.files.len()) {
+            return Err("Line entry refers to a missing file".to_string());
+        }
+        info.symbols.sort();
+        info.lines.sort_by_key(|line| line.pc);
+        Ok(info)
+    }
+
+    // the instruction that covers the pc, including the immediate word of a wide instruction
+    pub fn line_at(&self, pc: u16) -> Option<&LineInfo> {
+        let i = self.lines.partition_point(|line| line.pc <= pc).checked_sub(1)?;
+        let line = &self.lines[i];
+        ((pc as u32) < line.pc as u32 + line.words as u32).then_some(line)
+    }
+
+    // the closest text symbol at or before the pc, with the distance to it
+    pub fn symbol_at(&self, pc: u16) -> Option<(&str, u16)> {
+        let i = self.symbols.partition_point(|(address, _)| *address <= pc).checked_sub(1)?;
+        let (address, name) = &self.symbols[i];
+        Some((name, pc - address))
+    }
+
+    pub fn procedure_at(&self, pc: u16) -> Option<&str> {
+        let (_, _, name) = self.procedures.iter().find(|(start, end, _)| (*start..*end).contains(&pc))?;
+        Some(name)
+    }
+
+    // e.g. "sort.luna:42 while_j", "sort.luna:44 while_j+3" or "sort.luna:44 while_j+3 in selection_sort"
+    pub fn describe(&self, pc: u16) -> Option<String> {
+        let line = self.line_at(pc)?;
+        let mut text = format!("{}:{}", self.files[line.file], line.line);
+        let symbol = self.symbol_at(pc);
+        if let Some((name, offset)) = symbol {
+            text += &match offset {
+                0 => format!(" {}", name),
+                _ => format!(" {}+{}", name, offset),
+            };
+        }
+        // labels inside a procedure do not always say which one it is, `main` and `main.loop` do but
+        // `main_loop` does not
+        let names_procedure = |procedure: &str| symbol.is_some_and(|(name, _)| name == procedure || name.starts_with(&format!("{}.", procedure)));
+        if let Some(procedure) = self.procedure_at(pc).filter(|procedure| !names_procedure(procedure)) {
+            text += &format!(" in {}", procedure);
+        }
+        if pc != line.pc {
+            text += " (wide immediate)";
+        }
+        Some(text)
+    }
+
+    // the address of a text label or of the first instruction of a `file:line`
+    pub fn resolve(&self, location: &str) -> Option<u16> {
+        if let Some((address, _)) = self.symbols.iter().find(|(_, name)| name == location) {
+            return Some(*address);
+        }
+        let (file, line) = location.rsplit_once(':')?;
+        let line = line.parse::<usize>().ok()?;
+        // a line without instructions stops at the next one that has some
+        self.lines
+            .iter()
+            .filter(|entry| entry.line >= line)
+            .filter(|entry| self.files[entry.file] == file || self.files[entry.file].ends_with(&format!("/{}", file)))
+            .min_by_key(|entry| (entry.line, entry.pc))
+            .map(|entry| entry.pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO: &str = "lunadbg 1\n\
+        file 0 src/test.luna\n\
+        symbol data 0000 count\n\
+        symbol text 0000 main\n\
+        symbol text 0003 main.next\n\
+        symbol text 0004 main_loop\n\
+        line 0000 2 0 4 9\n\
+        line 0002 1 0 5 9\n\
+        pseudo 0002 call main_loop\n\
+        line 0003 1 0 5 9\n\
+        pseudo 0003 call main_loop\n\
+        line 0004 1 0 6 9\n\
+        proc 0000 0005 main\n";
+
+    #[test]
+    fn test_describe() {
+        let info = DebugInfo::parse(INFO).unwrap();
+        assert_eq!(info.describe(0).as_deref(), Some("src/test.luna:4 main"));
+        assert_eq!(info.describe(1).as_deref(), Some("src/test.luna:4 main+1 (wide immediate)"));
+        assert_eq!(info.describe(3).as_deref(), Some("src/test.luna:5 main.next"));
+        assert_eq!(info.describe(4).as_deref(), Some("src/test.luna:6 main_loop in main"));
+        assert_eq!(info.describe(5), None);
+        assert_eq!(info.line_at(3).unwrap().pseudo.as_deref(), Some("call main_loop"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let info = DebugInfo::parse(INFO).unwrap();
+        assert_eq!(info.resolve("main_loop"), Some(4));
+        assert_eq!(info.resolve("test.luna:5"), Some(2));
+        assert_eq!(info.resolve("src/test.luna:6"), Some(4));
+        assert_eq!(info.resolve("test.luna:1"), Some(0));
+        assert_eq!(info.resolve("count"), None);
+        assert_eq!(info.resolve("test.luna:7"), None);
+
+        assert!(DebugInfo::parse("lunadbg 1\nline 0000 1 0 4 9").is_err());
+        assert!(DebugInfo::parse("lunadbg 1\nsymbol text zz main").is_err());
+        assert!(DebugInfo::parse("lunaobj 1").is_err());
+    }
+}
@@ -4,21 +4,24 @@ mod components;
 #[allow(overflowing_literals)]
 #[allow(arithmetic_overflow)]
 mod cpu;
+mod debuginfo;
 #[cfg(test)]
 #[allow(overflowing_literals)]
 #[allow(arithmetic_overflow)]
 mod tests;
 
 use crate::cpu::*;
+use crate::debuginfo::DebugInfo;
+use lunacore_isa::container::{Container, SectionKind, MAGIC};
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::path::Path;
 
 pub fn load_binary_file(file_path: &str) -> io::Result<Vec<u16>> {
     let path = Path::new(file_path);
-    let mut file = File::open(&path)?;
+    let mut file = File::open(path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
 
@@ -40,40 +43,103 @@ pub fn load_binary_file(file_path: &str) -> io::Result<Vec<u16>> {
     Ok(instructions)
 }
 
+pub fn load_data_file(file_path: &str) -> io::Result<Vec<u8>> {
+    let mut file = File::open(Path::new(file_path))?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let usage = format!("Usage: {} [--raw] <binary_file> [data_file]", args[0]);
 
-    if args.len() < 2 {
-        eprintln!("Missing Filename\nUsage: {} <binary_file>", args[0]);
+    // raw images are bare words that start at pc 0, with the data image in a separate file
+    let raw = args.iter().any(|arg| arg == "--raw");
+    let files = args[1..].iter().filter(|arg| *arg != "--raw").collect::<Vec<_>>();
+    let Some(input_filename) = files.first() else {
+        eprintln!("Missing Filename\n{}", usage);
         std::process::exit(1);
-    }
-
-    let input_filename = &args[1];
+    };
 
     let mut cpu = CPU::new();
 
-    let binary = load_binary_file(input_filename).expect("Invalid binary file");
-    let program_len = binary.len();
-    cpu.imem.load_binary(&binary[..]);
+    let program_len = if raw {
+        load_raw(&mut cpu, input_filename, files.get(1).copied())
+    } else {
+        let bytes = or_exit(load_data_file(input_filename), input_filename);
+        let container = Container::parse(&bytes).unwrap_or_else(|err| {
+            let hint = if bytes.starts_with(MAGIC) { "" } else { " (use --raw for raw images)" };
+            eprintln!("{}: {}{}", input_filename, err, hint);
+            std::process::exit(1);
+        });
+        load_container(&mut cpu, &container)
+    };
 
-    let data = [
-        "00000101", // 5
-        "00000001", // 1
-        "00000011", // 3
-        "00001000", // 8
-        "00000010", // 2
-        "00000110", // 6
-        "00000100", // 4
-        "00000111", // 7
-        "00001001", // 9
-        "00000000", // 0
-    ];
-    cpu.dmem.load_binary_str(data.join("").as_str());
+    // debug info sits next to the executable (prog.lunaexe -> prog.lunadbg)
+    let debug_filename = input_filename.strip_suffix("exe").map(|stem| stem.to_owned() + "dbg");
+    if let Some(debug_filename) = debug_filename.filter(|debug_filename| Path::new(debug_filename).exists()) {
+        match fs::read_to_string(&debug_filename).map_err(|err| err.to_string()).and_then(|text| DebugInfo::parse(&text)) {
+            Ok(debug_info) => {
+                println!("Debug info loaded from {}", debug_filename);
+                cpu.debug_info = Some(debug_info);
+            }
+            Err(err) => eprintln!("{}: {}, continuing without debug info", debug_filename, err),
+        }
+    }
 
     // Enter interactive mode
     interactive_mode(&mut cpu, program_len);
 }
 
+// a file that cannot be read ends the emulator like an invalid executable does
+fn or_exit<T>(result: io::Result<T>, filename: &str) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}: {}", filename, err);
+        std::process::exit(1);
+    })
+}
+
+// returns one past the last instruction of the program
+fn load_container(cpu: &mut CPU, container: &Container) -> usize {
+    let mut text_end = 0;
+    for section in &container.sections {
+        let address = section.address as usize;
+        match section.kind {
+            SectionKind::Text => {
+                let words = section.words();
+                cpu.imem.data[address..address + words.len()].copy_from_slice(&words);
+                text_end = text_end.max(address + words.len());
+            }
+            SectionKind::Data => cpu.dmem.data[address..address + section.bytes.len()].copy_from_slice(&section.bytes),
+        }
+    }
+    cpu.pc = container.entry;
+    cpu.regs.sp = container.sp;
+    text_end
+}
+
+fn load_raw(cpu: &mut CPU, input_filename: &str, data_filename: Option<&String>) -> usize {
+    let binary = or_exit(load_binary_file(input_filename), input_filename);
+    let program_len = binary.len();
+    cpu.imem.load_binary(&binary[..]);
+
+    // the data image is either given explicitly or sits next to the binary (prog.lunaexe -> prog.lunadata)
+    let data_filename = match data_filename {
+        Some(data_filename) => Some(data_filename.clone()),
+        None => input_filename
+            .strip_suffix("exe")
+            .map(|stem| stem.to_owned() + "data")
+            .filter(|data_filename| Path::new(data_filename).exists()),
+    };
+    if let Some(data_filename) = data_filename {
+        let data = or_exit(load_data_file(&data_filename), &data_filename);
+        cpu.dmem.load_binary(&data[..]);
+        println!("Data image loaded from {} ({} bytes)", data_filename, data.len());
+    }
+    program_len
+}
+
 fn interactive_mode(cpu: &mut CPU, program_len: usize) -> i32 {
     let mut breakpoints = Vec::new();
     let mut cycle_count = 0;
@@ -100,7 +166,7 @@ fn interactive_mode(cpu: &mut CPU, program_len: usize) -> i32 {
                 if (cpu.pc as usize) >= program_len {
                     println!("PC out of bounds. Program Halted.");
                 } else {
-                    println!("Hit breakpoint at PC: 0x{:04x}", cpu.pc);
+                    println!("Hit breakpoint at {}", cpu.location(cpu.pc));
                 }
             }
             "s" | "step" => {
@@ -122,12 +188,12 @@ fn interactive_mode(cpu: &mut CPU, program_len: usize) -> i32 {
                 io::stdout().flush().unwrap();
                 let mut range_input = String::new();
                 io::stdin().read_line(&mut range_input).unwrap();
-                let parts: Vec<_> = range_input.trim().split_whitespace().collect();
+                let parts: Vec<_> = range_input.split_whitespace().collect();
 
                 if parts.len() == 2 {
                     let parse_value = |s: &str| {
-                        if s.starts_with("0x") {
-                            u16::from_str_radix(&s[2..], 16) // Parse as hexadecimal
+                        if let Some(hex) = s.strip_prefix("0x") {
+                            u16::from_str_radix(hex, 16) // Parse as hexadecimal
                         } else {
                             s.parse::<u16>() // Parse as decimal
                         }
@@ -146,21 +212,25 @@ fn interactive_mode(cpu: &mut CPU, program_len: usize) -> i32 {
                 // Add a breakpoint
                 if let Some(pc_str) = cmd.strip_prefix("break ") {
                     let parse_value = |s: &str| {
-                        if s.starts_with("0x") {
-                            u16::from_str_radix(&s[2..], 16) // Parse as hexadecimal
+                        if let Some(hex) = s.strip_prefix("0x") {
+                            u16::from_str_radix(hex, 16) // Parse as hexadecimal
                         } else {
                             s.parse::<u16>() // Parse as decimal
                         }
                     };
 
-                    if let Ok(pc) = parse_value(pc_str) {
+                    // labels and file:line need the debug info
+                    let pc = parse_value(pc_str)
+                        .ok()
+                        .or_else(|| cpu.debug_info.as_ref().and_then(|info| info.resolve(pc_str)));
+                    if let Some(pc) = pc {
                         breakpoints.push(pc);
-                        println!("Breakpoint added at 0x{:04X}", pc);
+                        println!("Breakpoint added at {}", cpu.location(pc));
                     } else {
                         println!("Invalid breakpoint address.");
                     }
                 } else {
-                    println!("Usage: break <address>");
+                    println!("Usage: break <address|label|file:line>");
                 }
             }
 
@@ -170,7 +240,7 @@ fn interactive_mode(cpu: &mut CPU, program_len: usize) -> i32 {
                 println!("  step (s)        - Execute the next instruction");
                 println!("  state           - Print the CPU state");
                 println!("  memory          - Print memory contents (requires range)");
-                println!("  break <pc>      - Add a breakpoint at the given PC address");
+                println!("  break <pc>      - Add a breakpoint at the given PC address, label or file:line");
                 println!("  help            - Show this help message");
                 println!("  quit (q)        - Exit the emulator");
             }
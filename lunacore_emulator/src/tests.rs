@@ -1,4 +1,6 @@
 use crate::cpu::*;
+use crate::load_container;
+use lunacore_isa::container::Container;
 
 
 // test of general dp instructions
@@ -390,3 +392,22 @@ fn lunacore_test_multiplication() {
     assert_eq!(cpu.regs.sp, 0x0000);
 
 }
+
+// the entry point and initial sp come from the executable
+#[test]
+fn lunacore_test_load_container() {
+    let container = Container {
+        sp: 0xff00,
+        ..Container::new(1, &[0x0000, 0x4428], &[]) // push t0
+    };
+    let container = Container::parse(&container.to_bytes()).unwrap();
+
+    let mut cpu = CPU::new();
+    assert_eq!(load_container(&mut cpu, &container), 2);
+    assert_eq!((cpu.pc, cpu.regs.sp), (1, 0xff00));
+
+    cpu.fetch();
+    cpu.decode();
+    cpu.execute();
+    assert_eq!(cpu.regs.sp, 0xff00 - 2);
+}
@@ -0,0 +1,216 @@
+// the executable format (.lunaexe) written by the compiler and loaded by the emulator, all values
+// little-endian:
+//
+//   0   magic "LUNA"
+//   4   u16 version
+//   6   u16 entry point (word address)
+//   8   u16 initial sp
+//   10  u16 number of sections
+//   12  u32 CRC-32 of the whole file, computed with this field set to 0
+//   16  section headers, 8 bytes each: u16 kind (1 text, 2 data), u16 load address, u32 size in bytes
+//       followed by the contents of every section in the same order
+
+pub const MAGIC: &[u8; 4] = b"LUNA";
+pub const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 16;
+const SECTION_HEADER_SIZE: usize = 8;
+const CHECKSUM: std::ops::Range<usize> = 12..16;
+const MEMORY_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SectionKind {
+    // instruction words, loaded into the program ROM
+    Text = 1,
+    // bytes loaded into data memory
+    Data = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerSection {
+    pub kind: SectionKind,
+    // a word address for text, a byte address for data
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl ContainerSection {
+    // the contents of a text section as instruction words
+    pub fn words(&self) -> Vec<u16> {
+        self.bytes.chunks(2).map(|word| u16::from_le_bytes([word[0], word[1]])).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Container {
+    pub entry: u16,
+    pub sp: u16,
+    pub sections: Vec<ContainerSection>,
+}
+
+impl Container {
+    // a program with its text and data both loaded at address 0, and sp at 0 so the stack starts at
+    // the top of data memory
+    pub fn new(entry: u16, text: &[u16], data: &[u8]) -> Container {
+        let mut sections = vec![ContainerSection {
+            kind: SectionKind::Text,
+            address: 0,
+            bytes: text.iter().flat_map(|word| word.to_le_bytes()).collect(),
+        }];
+        if !data.is_empty() {
+            sections.push(ContainerSection {
+                kind: SectionKind::Data,
+                address: 0,
+                bytes: data.to_vec(),
+            });
+        }
+        Container { entry, sp: 0, sections }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for value in [VERSION, self.entry, self.sp, self.sections.len() as u16] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[0; 4]);
+        for section in &self.sections {
+            bytes.extend_from_slice(&(section.kind as u16).to_le_bytes());
+            bytes.extend_from_slice(&section.address.to_le_bytes());
+            bytes.extend_from_slice(&(section.bytes.len() as u32).to_le_bytes());
+        }
+        for section in &self.sections {
+            bytes.extend_from_slice(&section.bytes);
+        }
+
+        let checksum = crc32(&bytes);
+        bytes[CHECKSUM].copy_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Container, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+            return Err("Not a LunaCore executable".to_string());
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        if u16_at(4) != VERSION {
+            return Err(format!("Unsupported executable version {}", u16_at(4)));
+        }
+
+        let mut unchecked = bytes.to_vec();
+        unchecked[CHECKSUM].fill(0);
+        if crc32(&unchecked).to_le_bytes() != bytes[CHECKSUM] {
+            return Err("Checksum mismatch, the executable is corrupted".to_string());
+        }
+
+        let count = u16_at(10) as usize;
+        let mut offset = HEADER_SIZE + count * SECTION_HEADER_SIZE;
+        let mut sections = Vec::new();
+        for i in 0..count {
+            let header = HEADER_SIZE + i * SECTION_HEADER_SIZE;
+            let Some(header) = bytes.get(header..header + SECTION_HEADER_SIZE) else {
+                return Err("Truncated section table".to_string());
+            };
+            let kind = match u16::from_le_bytes([header[0], header[1]]) {
+                1 => SectionKind::Text,
+                2 => SectionKind::Data,
+                kind => return Err(format!("Unknown section kind {}", kind)),
+            };
+            let address = u16::from_le_bytes([header[2], header[3]]);
+            let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            match kind {
+                SectionKind::Text if !size.is_multiple_of(2) || address as usize + size / 2 > MEMORY_SIZE => {
+                    return Err(format!("Invalid text section at 0x{:04x}", address));
+                }
+                SectionKind::Data if address as usize + size > MEMORY_SIZE => {
+                    return Err(format!("Invalid data section at 0x{:04x}", address));
+                }
+                _ => {}
+            }
+            let Some(contents) = bytes.get(offset..offset + size) else {
+                return Err("Truncated section contents".to_string());
+            };
+            sections.push(ContainerSection {
+                kind,
+                address,
+                bytes: contents.to_vec(),
+            });
+            offset += size;
+        }
+
+        Ok(Container {
+            entry: u16_at(6),
+            sp: u16_at(8),
+            sections,
+        })
+    }
+}
+
+// CRC-32 (IEEE 802.3), as used by zip and png
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let container = Container::new(2, &[0x1a03, 0x83fc], &[1, 2, 3]);
+        let bytes = container.to_bytes();
+
+        assert_eq!(&bytes[..12], b"LUNA\x01\x00\x02\x00\x00\x00\x02\x00");
+        assert_eq!(&bytes[16..24], &[1, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(&bytes[32..], &[0x03, 0x1a, 0xfc, 0x83, 1, 2, 3]);
+        assert_eq!(Container::parse(&bytes), Ok(container));
+
+        let mut corrupted = bytes.clone();
+        corrupted[33] ^= 1;
+        assert!(Container::parse(&corrupted).is_err());
+        assert!(Container::parse(&bytes[..30]).is_err());
+        assert!(Container::parse(&[0x03, 0x1a]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_sections() {
+        let odd = Container {
+            entry: 0,
+            sp: 0,
+            sections: vec![ContainerSection {
+                kind: SectionKind::Text,
+                address: 0,
+                bytes: vec![0x03, 0x1a, 0xfc],
+            }],
+        };
+        assert_eq!(Container::parse(&odd.to_bytes()), Err("Invalid text section at 0x0000".to_string()));
+
+        let past_end = Container {
+            entry: 0,
+            sp: 0xff00,
+            sections: vec![ContainerSection {
+                kind: SectionKind::Data,
+                address: 0xffff,
+                bytes: vec![1, 2],
+            }],
+        };
+        assert_eq!(Container::parse(&past_end.to_bytes()), Err("Invalid data section at 0xffff".to_string()));
+
+        let text = ContainerSection {
+            kind: SectionKind::Text,
+            address: 0x10,
+            bytes: vec![0x03, 0x1a, 0xfc, 0x83],
+        };
+        assert_eq!(text.words(), vec![0x1a03, 0x83fc]);
+    }
+}
@@ -0,0 +1,460 @@
+// the LunaCore instruction set, shared by the assembler and the emulator so that both encode and
+// decode instructions the same way
+//
+//   DP      00 imm(2) cmd(3) td(3) tn(3) src2(3)
+//   MEM     01 imm(2) b(1) op(2) td(3) tn(3) src2(3)
+//   BRANCH  10 w(1) cond(4) offset(9)
+//
+// imm selects what src2 is: 00 a register, 01 a 3-bit immediate extended with zeros, 10 one
+// extended with ones, 11 a 16-bit immediate in the next word. a wide branch (w = 1) takes its
+// offset from the next word as well. op 11 is not used
+
+pub mod container;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    T0,
+    T1,
+    T2,
+    T3,
+    Bp,
+    Sp,
+    Pc,
+    In,
+}
+
+pub const REGISTER_NAMES: [&str; 8] = ["t0", "t1", "t2", "t3", "bp", "sp", "pc", "in"];
+
+impl Register {
+    pub const ALL: [Register; 8] = [
+        Register::T0,
+        Register::T1,
+        Register::T2,
+        Register::T3,
+        Register::Bp,
+        Register::Sp,
+        Register::Pc,
+        Register::In,
+    ];
+
+    // the low 3 bits select the register
+    pub fn from_bits(bits: u16) -> Register {
+        Register::ALL[(bits & 0b111) as usize]
+    }
+
+    pub fn bits(self) -> u16 {
+        self as u16
+    }
+
+    pub fn name(self) -> &'static str {
+        REGISTER_NAMES[self as usize]
+    }
+
+    // case-insensitive
+    pub fn from_name(name: &str) -> Option<Register> {
+        let index = REGISTER_NAMES.iter().position(|r| r.eq_ignore_ascii_case(name))?;
+        Some(Register::ALL[index])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Mov,
+    Shl,
+    Shr,
+}
+
+impl AluOp {
+    pub const ALL: [AluOp; 8] = [AluOp::Add, AluOp::Sub, AluOp::And, AluOp::Or, AluOp::Xor, AluOp::Mov, AluOp::Shl, AluOp::Shr];
+    pub const MNEMONICS: [&str; 8] = ["add", "sub", "and", "or", "xor", "mov", "shl", "shr"];
+
+    pub fn from_bits(bits: u16) -> AluOp {
+        AluOp::ALL[(bits & 0b111) as usize]
+    }
+
+    pub fn bits(self) -> u16 {
+        self as u16
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        AluOp::MNEMONICS[self as usize]
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<AluOp> {
+        let index = AluOp::MNEMONICS.iter().position(|m| *m == mnemonic)?;
+        Some(AluOp::ALL[index])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemOp {
+    Sav,
+    Lod,
+    // push and pop always address through sp, which is their tn
+    Push,
+    Pop,
+}
+
+impl MemOp {
+    pub const ALL: [MemOp; 4] = [MemOp::Sav, MemOp::Lod, MemOp::Push, MemOp::Pop];
+    pub const MNEMONICS: [&str; 4] = ["sav", "lod", "push", "pop"];
+
+    pub fn from_bits(bits: u16) -> MemOp {
+        MemOp::ALL[(bits & 0b11) as usize]
+    }
+
+    pub fn bits(self) -> u16 {
+        self as u16
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        MemOp::MNEMONICS[self as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Z,
+    Nz,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Ult,
+    Ule,
+    Ugt,
+    Uge,
+    Mi,
+    Pl,
+    Vs,
+    Vc,
+    // always
+    Mp,
+    // never
+    Nv,
+}
+
+impl Condition {
+    pub const ALL: [Condition; 16] = [
+        Condition::Z,
+        Condition::Nz,
+        Condition::Lt,
+        Condition::Le,
+        Condition::Gt,
+        Condition::Ge,
+        Condition::Ult,
+        Condition::Ule,
+        Condition::Ugt,
+        Condition::Uge,
+        Condition::Mi,
+        Condition::Pl,
+        Condition::Vs,
+        Condition::Vc,
+        Condition::Mp,
+        Condition::Nv,
+    ];
+    // the branch mnemonic of every condition, `jmp` branches always
+    pub const MNEMONICS: [&str; 16] = [
+        "jz", "jnz", "jlt", "jle", "jgt", "jge", "jult", "jule", "jugt", "juge", "jmi", "jpl", "jvs", "jvc", "jmp", "jnv",
+    ];
+    // other spellings the assembler accepts
+    const ALIASES: [(&str, Condition); 8] = [
+        ("jeq", Condition::Z),
+        ("jne", Condition::Nz),
+        ("jcc", Condition::Ult),
+        ("jcs", Condition::Uge),
+        ("jns", Condition::Mi),
+        ("jnc", Condition::Pl),
+        ("jal", Condition::Mp),
+        ("jmp", Condition::Mp),
+    ];
+
+    pub fn from_bits(bits: u16) -> Condition {
+        Condition::ALL[(bits & 0b1111) as usize]
+    }
+
+    pub fn bits(self) -> u16 {
+        self as u16
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        Condition::MNEMONICS[self as usize]
+    }
+
+    // case-sensitive, the assembler lowercases mnemonics first
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Condition> {
+        match Condition::MNEMONICS.iter().position(|m| *m == mnemonic) {
+            Some(index) => Some(Condition::ALL[index]),
+            None => Condition::ALIASES.iter().find(|(alias, _)| *alias == mnemonic).map(|(_, cond)| *cond),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Src2 {
+    Reg(Register),
+    // 0..=7
+    ZeroImm3(u8),
+    // -8..=-1
+    OneImm3(i8),
+    WideImm16(u16),
+}
+
+impl Src2 {
+    // the shortest encoding of an immediate, None when it does not fit in 16 bits. the wide field
+    // holds both signed and unsigned values, 0x8000..=0xffff are the same as -0x8000..=-1
+    pub fn shortest(value: i32) -> Option<Src2> {
+        match value {
+            0..=7 => Some(Src2::ZeroImm3(value as u8)),
+            -8..=-1 => Some(Src2::OneImm3(value as i8)),
+            _ => Src2::wide(value),
+        }
+    }
+
+    pub fn wide(value: i32) -> Option<Src2> {
+        (i16::MIN as i32..=u16::MAX as i32).contains(&value).then_some(Src2::WideImm16(value as u16))
+    }
+
+    // the extended immediate, None for a register
+    pub fn imm(self) -> Option<u16> {
+        match self {
+            Src2::Reg(_) => None,
+            Src2::ZeroImm3(i) => Some(i as u16),
+            Src2::OneImm3(i) => Some(i as i16 as u16),
+            Src2::WideImm16(i) => Some(i),
+        }
+    }
+
+    fn encode(self) -> (u16, u16, Option<u16>) {
+        match self {
+            Src2::Reg(r) => (0b00, r.bits(), None),
+            Src2::ZeroImm3(i) => (0b01, i as u16 & 0b111, None),
+            Src2::OneImm3(i) => (0b10, i as u16 & 0b111, None),
+            Src2::WideImm16(i) => (0b11, 0, Some(i)),
+        }
+    }
+
+    fn decode(words: &[u16]) -> Option<Src2> {
+        let bits = words[0] & 0b111;
+        match field(words[0], 13, 12) {
+            0b00 => Some(Src2::Reg(Register::from_bits(bits))),
+            0b01 => Some(Src2::ZeroImm3(bits as u8)),
+            0b10 => Some(Src2::OneImm3(bits as i8 - 8)),
+            _ => words.get(1).map(|imm| Src2::WideImm16(*imm)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offset {
+    // -256..=255
+    SignImm9(i16),
+    WideImm16(u16),
+}
+
+impl Offset {
+    // None when the offset does not fit in the 9-bit field
+    pub fn short(offset: i32) -> Option<Offset> {
+        (-256..=255).contains(&offset).then_some(Offset::SignImm9(offset as i16))
+    }
+
+    // program addresses wrap around at 16 bits, so every offset fits
+    pub fn wide(offset: i32) -> Offset {
+        Offset::WideImm16(offset as u16)
+    }
+
+    // where a branch at `pc` lands, short offsets are relative to the pc register, which skips a wide next instruction
+    pub fn target(self, pc: u16, next_wide: bool) -> u16 {
+        let pc = match self {
+            Offset::SignImm9(_) => pc.wrapping_add(2 + next_wide as u16),
+            Offset::WideImm16(_) => pc.wrapping_add(3),
+        };
+        pc.wrapping_add(self.value())
+    }
+
+    // what the branch adds to the pc register
+    pub fn value(self) -> u16 {
+        match self {
+            Offset::SignImm9(offset) => offset as u16,
+            Offset::WideImm16(offset) => offset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Dp { op: AluOp, td: Register, tn: Register, src2: Src2 },
+    Mem { op: MemOp, byte: bool, td: Register, tn: Register, src2: Src2 },
+    Branch { cond: Condition, offset: Offset },
+}
+
+impl Instruction {
+    // one word, or two for a wide immediate or offset
+    pub fn encode(&self) -> Vec<u16> {
+        let (word, wide) = match *self {
+            Instruction::Dp { op, td, tn, src2 } => {
+                let (imm, bits, wide) = src2.encode();
+                ((imm << 12) | (op.bits() << 9) | (td.bits() << 6) | (tn.bits() << 3) | bits, wide)
+            }
+            Instruction::Mem { op, byte, td, tn, src2 } => {
+                let (imm, bits, wide) = src2.encode();
+                let word = (0b01 << 14) | (imm << 12) | ((byte as u16) << 11) | (op.bits() << 9) | (td.bits() << 6) | (tn.bits() << 3);
+                (word | bits, wide)
+            }
+            Instruction::Branch { cond, offset } => {
+                let (w, bits, wide) = match offset {
+                    Offset::SignImm9(offset) => (0, offset as u16 & 0x1ff, None),
+                    Offset::WideImm16(offset) => (1, 0, Some(offset)),
+                };
+                ((0b10 << 14) | (w << 13) | (cond.bits() << 9) | bits, wide)
+            }
+        };
+        std::iter::once(word).chain(wide).collect()
+    }
+
+    // the instruction at the start of `words`, None for op 11 and for a wide instruction whose
+    // second word is missing. bits the encoding ignores are dropped, so `encode` may not give them back
+    pub fn decode(words: &[u16]) -> Option<Instruction> {
+        let word = *words.first()?;
+        let (td, tn) = (Register::from_bits(field(word, 8, 6)), Register::from_bits(field(word, 5, 3)));
+        match field(word, 15, 14) {
+            0b00 => Some(Instruction::Dp {
+                op: AluOp::from_bits(field(word, 11, 9)),
+                td,
+                tn,
+                src2: Src2::decode(words)?,
+            }),
+            0b01 => Some(Instruction::Mem {
+                op: MemOp::from_bits(field(word, 10, 9)),
+                byte: field(word, 11, 11) == 1,
+                td,
+                tn,
+                src2: Src2::decode(words)?,
+            }),
+            0b10 => {
+                let offset = match field(word, 13, 13) {
+                    // sign extend the 9-bit offset
+                    0 => Offset::SignImm9(((field(word, 8, 0) << 7) as i16) >> 7),
+                    _ => Offset::WideImm16(*words.get(1)?),
+                };
+                Some(Instruction::Branch { cond: Condition::from_bits(field(word, 12, 9)), offset })
+            }
+            _ => None,
+        }
+    }
+
+    // the last operand of a dp or mem instruction
+    pub fn src2(&self) -> Option<Src2> {
+        match *self {
+            Instruction::Dp { src2, .. } | Instruction::Mem { src2, .. } => Some(src2),
+            Instruction::Branch { .. } => None,
+        }
+    }
+
+    // the same instruction with another last operand, branches have none
+    pub fn with_src2(self, src2: Src2) -> Instruction {
+        match self {
+            Instruction::Dp { op, td, tn, .. } => Instruction::Dp { op, td, tn, src2 },
+            Instruction::Mem { op, byte, td, tn, .. } => Instruction::Mem { op, byte, td, tn, src2 },
+            branch => branch,
+        }
+    }
+
+    pub fn is_wide(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Dp { src2: Src2::WideImm16(_), .. }
+                | Instruction::Mem { src2: Src2::WideImm16(_), .. }
+                | Instruction::Branch { offset: Offset::WideImm16(_), .. }
+        )
+    }
+}
+
+// whether the instruction starting with this word takes a second one
+pub fn is_wide(word: u16) -> bool {
+    match field(word, 15, 14) {
+        0b00 | 0b01 => field(word, 13, 12) == 0b11,
+        0b10 => field(word, 13, 13) == 1,
+        _ => false,
+    }
+}
+
+// bits end..=start of a word
+fn field(word: u16, end: u16, start: u16) -> u16 {
+    (word >> start) & ((1 << (end - start + 1)) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mov = Instruction::Dp { op: AluOp::Mov, td: Register::T0, tn: Register::T0, src2: Src2::ZeroImm3(2) };
+        assert_eq!(mov.encode(), vec![0b0001101000000010]);
+        let add = Instruction::Dp { op: AluOp::Add, td: Register::T0, tn: Register::T0, src2: Src2::OneImm3(-1) };
+        assert_eq!(add.encode(), vec![0b0010000000000111]);
+        let push = Instruction::Mem { op: MemOp::Push, byte: false, td: Register::T0, tn: Register::Sp, src2: Src2::Reg(Register::T0) };
+        assert_eq!(push.encode(), vec![0b0100010000101000]);
+        let popb = Instruction::Mem { op: MemOp::Pop, byte: true, td: Register::T3, tn: Register::Sp, src2: Src2::Reg(Register::T0) };
+        assert_eq!(popb.encode(), vec![0b0100111011101000]);
+        let mov = Instruction::Dp { op: AluOp::Mov, td: Register::T1, tn: Register::T0, src2: Src2::WideImm16(0xffe0) };
+        assert_eq!(mov.encode(), vec![0b0011101001000000, 0xffe0]);
+
+        // only the 9 bits of the offset may reach the word
+        let jmp = Instruction::Branch { cond: Condition::Mp, offset: Offset::SignImm9(-1) };
+        assert_eq!(jmp.encode(), vec![0b1001110111111111]);
+        let jz = Instruction::Branch { cond: Condition::Z, offset: Offset::WideImm16(0x1234) };
+        assert_eq!(jz.encode(), vec![0b1010000000000000, 0x1234]);
+    }
+
+    #[test]
+    fn test_decode() {
+        // every one-word instruction decodes to what encodes back to it
+        for word in 0..0xc000u16 {
+            let words = [word, 0x1234];
+            let instruction = Instruction::decode(&words).unwrap();
+            assert_eq!(instruction.is_wide(), is_wide(word));
+            let encoded = instruction.encode();
+            // wide instructions drop the unused bits next to imm/w
+            let mask = if is_wide(word) { if word >> 14 == 0b10 { 0xfe00 } else { 0xfff8 } } else { 0xffff };
+            assert_eq!((encoded[0], encoded.get(1).copied()), (word & mask, is_wide(word).then_some(0x1234)));
+        }
+        assert_eq!(Instruction::decode(&[0xc000]), None);
+        assert_eq!(Instruction::decode(&[0x3a00]), None);
+        assert_eq!(Instruction::decode(&[0x81ff]), Some(Instruction::Branch { cond: Condition::Z, offset: Offset::SignImm9(-1) }));
+        assert_eq!(Instruction::decode(&[0x8003]), Some(Instruction::Branch { cond: Condition::Z, offset: Offset::SignImm9(3) }));
+        assert_eq!(Instruction::decode(&[0x8101]), Some(Instruction::Branch { cond: Condition::Z, offset: Offset::SignImm9(-255) }));
+    }
+
+    #[test]
+    fn test_tables() {
+        assert_eq!(Register::from_name("SP"), Some(Register::Sp));
+        assert_eq!(Register::Pc.name(), "pc");
+        assert_eq!(AluOp::from_mnemonic("shr"), Some(AluOp::Shr));
+        assert_eq!(Condition::from_mnemonic("jeq"), Some(Condition::Z));
+        assert_eq!(Condition::from_mnemonic("jmp"), Some(Condition::Mp));
+        assert_eq!(Condition::Mp.mnemonic(), "jmp");
+        assert_eq!(Condition::from_mnemonic("jxx"), None);
+        assert_eq!(Src2::OneImm3(-8).imm(), Some(0xfff8));
+    }
+
+    #[test]
+    fn test_operands() {
+        assert_eq!(Src2::shortest(-3), Some(Src2::OneImm3(-3)));
+        assert_eq!(Src2::shortest(8), Some(Src2::WideImm16(8)));
+        assert_eq!(Src2::shortest(-0x8000), Some(Src2::WideImm16(0x8000)));
+        assert_eq!(Src2::shortest(0x10000), None);
+        assert_eq!(Offset::short(-256), Some(Offset::SignImm9(-256)));
+        assert_eq!(Offset::short(256), None);
+        // pc reads 2 ahead, 3 when the branch or the instruction after it is wide
+        assert_eq!(Offset::SignImm9(-3).target(10, true), 10);
+        assert_eq!(Offset::wide(-3).target(10, false), 10);
+        assert_eq!(Offset::SignImm9(0).target(0xffff, false), 1);
+    }
+}